@@ -68,13 +68,13 @@ pub struct GetUser {
     pub user: User,
 }
 
-#[derive(cynic::QueryFragment, Debug)]
+#[derive(cynic::QueryFragment, Debug, Clone)]
 pub struct User {
     pub id: String,
     pub groups: Vec<Group>,
 }
 
-#[derive(cynic::QueryFragment, Debug)]
+#[derive(cynic::QueryFragment, Debug, Clone)]
 pub struct Group {
     pub id: i32,
     pub display_name: String,
@@ -110,6 +110,87 @@ pub struct DeleteGroup {
     pub delete_group: Success,
 }
 
+#[derive(cynic::QueryFragment, Debug)]
+#[cynic(graphql_type = "Query")]
+pub struct GetApiVersion {
+    pub api_version: String,
+}
+
+#[derive(cynic::QueryFragment, Debug)]
+pub struct AttributeValue {
+    pub name: String,
+    pub value: Vec<String>,
+    pub schema: AttributeSchema,
+}
+
+#[derive(cynic::QueryFragment, Debug)]
+pub struct AttributeSchema {
+    pub is_hardcoded: bool,
+}
+
+#[derive(cynic::QueryFragment, Debug)]
+#[cynic(graphql_type = "User")]
+pub struct UserDetails {
+    pub id: String,
+    pub groups: Vec<Group>,
+    pub attributes: Vec<AttributeValue>,
+}
+
+#[derive(cynic::QueryFragment, Debug)]
+#[cynic(graphql_type = "Query")]
+pub struct ListUsers {
+    pub users: Vec<UserDetails>,
+}
+
+#[derive(cynic::QueryFragment, Debug)]
+#[cynic(graphql_type = "Group")]
+pub struct GroupDetails {
+    pub id: i32,
+    pub display_name: String,
+    pub attributes: Vec<AttributeValue>,
+}
+
+#[derive(cynic::QueryFragment, Debug)]
+#[cynic(graphql_type = "Query")]
+pub struct ListGroups {
+    pub groups: Vec<GroupDetails>,
+}
+
+#[derive(cynic::InputObject, Debug)]
+pub struct AttributeValueInput {
+    pub name: String,
+    pub value: Vec<String>,
+}
+
+#[derive(cynic::QueryVariables, Debug)]
+pub struct UpdateUserAttributesVariables<'a> {
+    pub username: &'a str,
+    pub attributes: Vec<AttributeValueInput>,
+}
+
+#[derive(cynic::QueryFragment, Debug)]
+#[cynic(graphql_type = "Mutation", variables = "UpdateUserAttributesVariables")]
+pub struct UpdateUserAttributes {
+    #[arguments(user: { id: $username, insertAttributes: $attributes })]
+    pub update_user: Success,
+}
+
+#[derive(cynic::QueryVariables, Debug)]
+pub struct UpdateGroupAttributesVariables {
+    pub id: i32,
+    pub attributes: Vec<AttributeValueInput>,
+}
+
+#[derive(cynic::QueryFragment, Debug)]
+#[cynic(
+    graphql_type = "Mutation",
+    variables = "UpdateGroupAttributesVariables"
+)]
+pub struct UpdateGroupAttributes {
+    #[arguments(group: { id: $id, insertAttributes: $attributes })]
+    pub update_group: Success,
+}
+
 #[cfg(test)]
 mod tests {
     use cynic::{MutationBuilder, QueryBuilder};
@@ -177,4 +258,51 @@ mod tests {
 
         insta::assert_snapshot!(operation.query);
     }
+
+    #[test]
+    fn get_api_version_gql_output() {
+        let operation = GetApiVersion::build(());
+
+        insta::assert_snapshot!(operation.query);
+    }
+
+    #[test]
+    fn list_users_gql_output() {
+        let operation = ListUsers::build(());
+
+        insta::assert_snapshot!(operation.query);
+    }
+
+    #[test]
+    fn list_groups_gql_output() {
+        let operation = ListGroups::build(());
+
+        insta::assert_snapshot!(operation.query);
+    }
+
+    #[test]
+    fn update_user_attributes_gql_output() {
+        let operation = UpdateUserAttributes::build(UpdateUserAttributesVariables {
+            username: "user",
+            attributes: vec![AttributeValueInput {
+                name: "department".into(),
+                value: vec!["media".into()],
+            }],
+        });
+
+        insta::assert_snapshot!(operation.query);
+    }
+
+    #[test]
+    fn update_group_attributes_gql_output() {
+        let operation = UpdateGroupAttributes::build(UpdateGroupAttributesVariables {
+            id: 3,
+            attributes: vec![AttributeValueInput {
+                name: "department".into(),
+                value: vec!["media".into()],
+            }],
+        });
+
+        insta::assert_snapshot!(operation.query);
+    }
 }
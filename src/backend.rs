@@ -0,0 +1,1186 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use futures::future::BoxFuture;
+use k8s_openapi::api::core::v1::ObjectReference;
+use queries::{AttributeSchema, AttributeValue, Group, GroupDetails, User, UserDetails};
+use tokio::sync::Mutex as AsyncMutex;
+use tracing::{trace, warn};
+
+use crate::context::DryRunReporter;
+use crate::instances::LldapInstance;
+use crate::lldap::{Error, Result, custom_attributes};
+
+/// Everything the controller needs from the directory it manages users and groups in
+///
+/// Implemented by [`LldapClient`](crate::lldap::LldapClient) for the GraphQL API of LLDAP and by
+/// [`MemoryBackend`] for tests and dry runs.
+pub trait DirectoryBackend: Send + Sync {
+    /// `None` when the user does not exist
+    fn get_user<'a>(&'a self, username: &'a str) -> BoxFuture<'a, Result<Option<User>>>;
+
+    fn create_user<'a>(&'a self, username: &'a str) -> BoxFuture<'a, Result<User>>;
+
+    /// Returns false when the user did not exist
+    fn delete_user<'a>(&'a self, username: &'a str) -> BoxFuture<'a, Result<bool>>;
+
+    /// All users including their groups and attributes
+    fn list_users(&self) -> BoxFuture<'_, Result<Vec<UserDetails>>>;
+
+    fn get_groups(&self) -> BoxFuture<'_, Result<Vec<Group>>>;
+
+    /// All groups including their attributes
+    fn list_groups(&self) -> BoxFuture<'_, Result<Vec<GroupDetails>>>;
+
+    fn create_group<'a>(&'a self, name: &'a str) -> BoxFuture<'a, Result<Group>>;
+
+    fn delete_group(&self, id: i32) -> BoxFuture<'_, Result<()>>;
+
+    fn add_user_to_group<'a>(&'a self, username: &'a str, group: i32) -> BoxFuture<'a, Result<()>>;
+
+    fn remove_user_from_group<'a>(
+        &'a self,
+        username: &'a str,
+        group: i32,
+    ) -> BoxFuture<'a, Result<()>>;
+
+    /// Set the attributes of the user, attributes that are not given are left untouched
+    fn update_user_attributes<'a>(
+        &'a self,
+        username: &'a str,
+        attributes: &'a BTreeMap<String, Vec<String>>,
+    ) -> BoxFuture<'a, Result<()>>;
+
+    /// Set the attributes of the group, attributes that are not given are left untouched
+    fn update_group_attributes<'a>(
+        &'a self,
+        id: i32,
+        attributes: &'a BTreeMap<String, Vec<String>>,
+    ) -> BoxFuture<'a, Result<()>>;
+
+    fn update_password<'a>(
+        &'a self,
+        username: &'a str,
+        password: &'a str,
+    ) -> BoxFuture<'a, Result<()>>;
+
+    /// Make the user a member of exactly the needed groups, groups that do not exist are ignored
+    fn update_user_groups<'a>(
+        &'a self,
+        user: &'a User,
+        needed_groups: &'a [String],
+    ) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            let all_groups = self.get_groups().await?;
+
+            let needed_groups: Vec<_> = needed_groups
+                .iter()
+                .filter_map(|needed_group| {
+                    all_groups
+                        .iter()
+                        .find(|group| &group.display_name == needed_group)
+                        .map(|group| group.id)
+                })
+                .collect();
+
+            let current_groups: Vec<_> = user.groups.iter().map(|group| group.id).collect();
+
+            let remove = current_groups
+                .iter()
+                .filter(|group| !needed_groups.contains(group));
+            for &group in remove {
+                trace!(username = user.id, group, "Removing user from group");
+
+                self.remove_user_from_group(&user.id, group).await?;
+            }
+
+            let add = needed_groups
+                .iter()
+                .filter(|group| !current_groups.contains(group));
+            for &group in add {
+                trace!(username = user.id, group, "Adding user to group");
+
+                self.add_user_to_group(&user.id, group).await?;
+            }
+
+            Ok(())
+        })
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct MemoryUser {
+    pub groups: BTreeSet<i32>,
+    pub attributes: BTreeMap<String, Vec<String>>,
+    /// The password that was last set, if it was set through the backend
+    pub password: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct MemoryGroup {
+    pub name: String,
+    pub attributes: BTreeMap<String, Vec<String>>,
+}
+
+/// Users and groups of a directory that only exists in memory
+#[derive(Debug, Default)]
+pub struct MemoryState {
+    pub users: BTreeMap<String, MemoryUser>,
+    pub groups: BTreeMap<i32, MemoryGroup>,
+    /// LLDAP does not reuse the ids of deleted groups
+    last_group_id: i32,
+}
+
+impl MemoryState {
+    /// Start out with the users and groups of another directory
+    pub fn from_details(users: &[UserDetails], groups: &[GroupDetails]) -> Self {
+        let mut state = Self::default();
+
+        for group in groups {
+            state.groups.insert(
+                group.id,
+                MemoryGroup {
+                    name: group.display_name.clone(),
+                    attributes: custom_attributes(&group.attributes),
+                },
+            );
+            state.last_group_id = state.last_group_id.max(group.id);
+        }
+
+        for user in users {
+            state.users.insert(
+                user.id.clone(),
+                MemoryUser {
+                    groups: user.groups.iter().map(|group| group.id).collect(),
+                    attributes: custom_attributes(&user.attributes),
+                    password: None,
+                },
+            );
+        }
+
+        state
+    }
+
+    /// Returns the id of the new group
+    pub fn add_group(&mut self, name: &str) -> i32 {
+        self.last_group_id += 1;
+        self.groups.insert(
+            self.last_group_id,
+            MemoryGroup {
+                name: name.into(),
+                attributes: BTreeMap::new(),
+            },
+        );
+
+        self.last_group_id
+    }
+
+    pub fn group_id(&self, name: &str) -> Option<i32> {
+        self.groups
+            .iter()
+            .find(|(_, group)| group.name == name)
+            .map(|(id, _)| *id)
+    }
+
+    /// Names of the groups the user is a member of
+    pub fn user_groups(&self, username: &str) -> BTreeSet<String> {
+        self.users[username]
+            .groups
+            .iter()
+            .map(|id| self.groups[id].name.clone())
+            .collect()
+    }
+
+    pub fn group(&self, id: i32) -> Group {
+        Group {
+            id,
+            display_name: self.groups[&id].name.clone(),
+        }
+    }
+
+    pub fn user(&self, username: &str) -> Option<User> {
+        let user = self.users.get(username)?;
+
+        Some(User {
+            id: username.into(),
+            groups: user.groups.iter().map(|id| self.group(*id)).collect(),
+        })
+    }
+
+    pub fn user_mut(&mut self, username: &str) -> Result<&mut MemoryUser> {
+        self.users
+            .get_mut(username)
+            .ok_or_else(|| Error::NotFound(format!("user '{username}'")))
+    }
+
+    pub fn group_mut(&mut self, id: i32) -> Result<&mut MemoryGroup> {
+        self.groups
+            .get_mut(&id)
+            .ok_or_else(|| Error::NotFound(format!("group '{id}'")))
+    }
+
+    pub fn delete_group(&mut self, id: i32) -> Result<()> {
+        self.group_mut(id)?;
+        self.groups.remove(&id);
+        for user in self.users.values_mut() {
+            user.groups.remove(&id);
+        }
+
+        Ok(())
+    }
+
+    pub fn add_user_to_group(&mut self, username: &str, group: i32) -> Result<()> {
+        self.group_mut(group)?;
+        self.user_mut(username)?.groups.insert(group);
+
+        Ok(())
+    }
+
+    pub fn remove_user_from_group(&mut self, username: &str, group: i32) -> Result<()> {
+        self.user_mut(username)?.groups.remove(&group);
+
+        Ok(())
+    }
+}
+
+fn attribute_values(attributes: &BTreeMap<String, Vec<String>>) -> Vec<AttributeValue> {
+    attributes
+        .iter()
+        .map(|(name, value)| AttributeValue {
+            name: name.clone(),
+            value: value.clone(),
+            schema: AttributeSchema {
+                is_hardcoded: false,
+            },
+        })
+        .collect()
+}
+
+/// Directory that only exists in memory
+#[derive(Debug, Default)]
+pub struct MemoryBackend {
+    state: Mutex<MemoryState>,
+}
+
+impl MemoryBackend {
+    /// Start out with the users and groups of another directory
+    pub fn from_details(users: &[UserDetails], groups: &[GroupDetails]) -> Self {
+        Self {
+            state: Mutex::new(MemoryState::from_details(users, groups)),
+        }
+    }
+
+    fn state(&self) -> MutexGuard<'_, MemoryState> {
+        self.state.lock().expect("Lock should not be poisoned")
+    }
+
+    /// The password that was last set for the user
+    pub fn password(&self, username: &str) -> Option<String> {
+        self.state().users.get(username)?.password.clone()
+    }
+}
+
+impl DirectoryBackend for MemoryBackend {
+    fn get_user<'a>(&'a self, username: &'a str) -> BoxFuture<'a, Result<Option<User>>> {
+        Box::pin(async move { Ok(self.state().user(username)) })
+    }
+
+    fn create_user<'a>(&'a self, username: &'a str) -> BoxFuture<'a, Result<User>> {
+        Box::pin(async move {
+            let mut state = self.state();
+            state.users.entry(username.into()).or_default();
+
+            Ok(state.user(username).expect("User was just created"))
+        })
+    }
+
+    fn delete_user<'a>(&'a self, username: &'a str) -> BoxFuture<'a, Result<bool>> {
+        Box::pin(async move { Ok(self.state().users.remove(username).is_some()) })
+    }
+
+    fn list_users(&self) -> BoxFuture<'_, Result<Vec<UserDetails>>> {
+        Box::pin(async move {
+            let state = self.state();
+
+            Ok(state
+                .users
+                .iter()
+                .map(|(username, user)| UserDetails {
+                    id: username.clone(),
+                    groups: user.groups.iter().map(|id| state.group(*id)).collect(),
+                    attributes: attribute_values(&user.attributes),
+                })
+                .collect())
+        })
+    }
+
+    fn get_groups(&self) -> BoxFuture<'_, Result<Vec<Group>>> {
+        Box::pin(async move {
+            let state = self.state();
+
+            Ok(state.groups.keys().map(|id| state.group(*id)).collect())
+        })
+    }
+
+    fn list_groups(&self) -> BoxFuture<'_, Result<Vec<GroupDetails>>> {
+        Box::pin(async move {
+            Ok(self
+                .state()
+                .groups
+                .iter()
+                .map(|(id, group)| GroupDetails {
+                    id: *id,
+                    display_name: group.name.clone(),
+                    attributes: attribute_values(&group.attributes),
+                })
+                .collect())
+        })
+    }
+
+    fn create_group<'a>(&'a self, name: &'a str) -> BoxFuture<'a, Result<Group>> {
+        Box::pin(async move {
+            let mut state = self.state();
+            let id = state.add_group(name);
+
+            Ok(state.group(id))
+        })
+    }
+
+    fn delete_group(&self, id: i32) -> BoxFuture<'_, Result<()>> {
+        Box::pin(async move { self.state().delete_group(id) })
+    }
+
+    fn add_user_to_group<'a>(&'a self, username: &'a str, group: i32) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move { self.state().add_user_to_group(username, group) })
+    }
+
+    fn remove_user_from_group<'a>(
+        &'a self,
+        username: &'a str,
+        group: i32,
+    ) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move { self.state().remove_user_from_group(username, group) })
+    }
+
+    fn update_user_attributes<'a>(
+        &'a self,
+        username: &'a str,
+        attributes: &'a BTreeMap<String, Vec<String>>,
+    ) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            let mut state = self.state();
+            let user = state.user_mut(username)?;
+            user.attributes.extend(attributes.clone());
+
+            Ok(())
+        })
+    }
+
+    fn update_group_attributes<'a>(
+        &'a self,
+        id: i32,
+        attributes: &'a BTreeMap<String, Vec<String>>,
+    ) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            let mut state = self.state();
+            let group = state.group_mut(id)?;
+            group.attributes.extend(attributes.clone());
+
+            Ok(())
+        })
+    }
+
+    fn update_password<'a>(
+        &'a self,
+        username: &'a str,
+        password: &'a str,
+    ) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            self.state().user_mut(username)?.password = Some(password.into());
+
+            Ok(())
+        })
+    }
+}
+
+/// A change to the users and groups of a directory, with groups referred to by name
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Change {
+    CreateGroup {
+        group: String,
+    },
+    SetGroupAttribute {
+        group: String,
+        name: String,
+        value: Vec<String>,
+    },
+    DeleteGroup {
+        group: String,
+    },
+    CreateUser {
+        username: String,
+    },
+    AddUserToGroup {
+        username: String,
+        group: String,
+    },
+    RemoveUserFromGroup {
+        username: String,
+        group: String,
+    },
+    SetUserAttribute {
+        username: String,
+        name: String,
+        value: Vec<String>,
+    },
+    SetPassword {
+        username: String,
+    },
+    DeleteUser {
+        username: String,
+    },
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Change::CreateGroup { group } => write!(f, "+ group '{group}'"),
+            Change::SetGroupAttribute { group, name, value } => write!(
+                f,
+                "~ group '{group}': set attribute '{name}' to [{}]",
+                value.join(", ")
+            ),
+            Change::DeleteGroup { group } => write!(f, "- group '{group}'"),
+            Change::CreateUser { username } => write!(f, "+ user '{username}'"),
+            Change::AddUserToGroup { username, group } => {
+                write!(f, "~ user '{username}': add to group '{group}'")
+            }
+            Change::RemoveUserFromGroup { username, group } => {
+                write!(f, "~ user '{username}': remove from group '{group}'")
+            }
+            Change::SetUserAttribute {
+                username,
+                name,
+                value,
+            } => write!(
+                f,
+                "~ user '{username}': set attribute '{name}' to [{}]",
+                value.join(", ")
+            ),
+            Change::SetPassword { username } => write!(f, "~ user '{username}': set password"),
+            Change::DeleteUser { username } => write!(f, "- user '{username}'"),
+        }
+    }
+}
+
+/// Receives the changes a [`RecordingBackend`] makes
+pub trait ChangeSink: Send + Sync {
+    /// Called before the change is made to the backend
+    fn record<'a>(
+        &'a self,
+        backend: &'a dyn DirectoryBackend,
+        change: Change,
+    ) -> BoxFuture<'a, Result<()>>;
+}
+
+/// Passes every change to a sink before making it in another backend, or instead of making it
+pub struct RecordingBackend<S> {
+    inner: Arc<dyn DirectoryBackend>,
+    sink: S,
+    /// Only record the changes, reads still go to the inner backend
+    dry_run: bool,
+    /// Groups that were only recorded, by the made up id they are known by
+    created_groups: Mutex<BTreeMap<i32, String>>,
+}
+
+impl<S: ChangeSink> RecordingBackend<S> {
+    pub fn new(inner: Arc<dyn DirectoryBackend>, sink: S) -> Self {
+        Self {
+            inner,
+            sink,
+            dry_run: false,
+            created_groups: Default::default(),
+        }
+    }
+
+    /// Record the changes without making them
+    pub fn dry_run(inner: Arc<dyn DirectoryBackend>, sink: S) -> Self {
+        Self {
+            dry_run: true,
+            ..Self::new(inner, sink)
+        }
+    }
+
+    pub fn sink(&self) -> &S {
+        &self.sink
+    }
+
+    async fn record(&self, change: Change) -> Result<()> {
+        self.sink.record(self.inner.as_ref(), change).await
+    }
+
+    async fn group_name(&self, id: i32) -> Result<String> {
+        let created = self
+            .created_groups
+            .lock()
+            .expect("Lock should not be poisoned")
+            .get(&id)
+            .cloned();
+        if let Some(name) = created {
+            return Ok(name);
+        }
+
+        Ok(self
+            .inner
+            .get_groups()
+            .await?
+            .into_iter()
+            .find(|group| group.id == id)
+            .map(|group| group.display_name)
+            .unwrap_or_else(|| id.to_string()))
+    }
+}
+
+impl<S: ChangeSink> DirectoryBackend for RecordingBackend<S> {
+    fn get_user<'a>(&'a self, username: &'a str) -> BoxFuture<'a, Result<Option<User>>> {
+        self.inner.get_user(username)
+    }
+
+    fn create_user<'a>(&'a self, username: &'a str) -> BoxFuture<'a, Result<User>> {
+        Box::pin(async move {
+            self.record(Change::CreateUser {
+                username: username.into(),
+            })
+            .await?;
+            if self.dry_run {
+                return Ok(User {
+                    id: username.into(),
+                    groups: Vec::new(),
+                });
+            }
+
+            self.inner.create_user(username).await
+        })
+    }
+
+    fn delete_user<'a>(&'a self, username: &'a str) -> BoxFuture<'a, Result<bool>> {
+        Box::pin(async move {
+            if self.inner.get_user(username).await?.is_none() {
+                return Ok(false);
+            }
+
+            self.record(Change::DeleteUser {
+                username: username.into(),
+            })
+            .await?;
+            if self.dry_run {
+                return Ok(true);
+            }
+
+            self.inner.delete_user(username).await
+        })
+    }
+
+    fn list_users(&self) -> BoxFuture<'_, Result<Vec<UserDetails>>> {
+        self.inner.list_users()
+    }
+
+    fn get_groups(&self) -> BoxFuture<'_, Result<Vec<Group>>> {
+        self.inner.get_groups()
+    }
+
+    fn list_groups(&self) -> BoxFuture<'_, Result<Vec<GroupDetails>>> {
+        self.inner.list_groups()
+    }
+
+    fn create_group<'a>(&'a self, name: &'a str) -> BoxFuture<'a, Result<Group>> {
+        Box::pin(async move {
+            self.record(Change::CreateGroup { group: name.into() })
+                .await?;
+            if !self.dry_run {
+                return self.inner.create_group(name).await;
+            }
+
+            let mut created_groups = self
+                .created_groups
+                .lock()
+                .expect("Lock should not be poisoned");
+            // Real ids are positive, so the made up ones never collide with them
+            let id = -(created_groups.len() as i32) - 1;
+            created_groups.insert(id, name.into());
+
+            Ok(Group {
+                id,
+                display_name: name.into(),
+            })
+        })
+    }
+
+    fn delete_group(&self, id: i32) -> BoxFuture<'_, Result<()>> {
+        Box::pin(async move {
+            let group = self.group_name(id).await?;
+            self.record(Change::DeleteGroup { group }).await?;
+            if self.dry_run {
+                return Ok(());
+            }
+
+            self.inner.delete_group(id).await
+        })
+    }
+
+    fn add_user_to_group<'a>(&'a self, username: &'a str, group: i32) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            self.record(Change::AddUserToGroup {
+                username: username.into(),
+                group: self.group_name(group).await?,
+            })
+            .await?;
+            if self.dry_run {
+                return Ok(());
+            }
+
+            self.inner.add_user_to_group(username, group).await
+        })
+    }
+
+    fn remove_user_from_group<'a>(
+        &'a self,
+        username: &'a str,
+        group: i32,
+    ) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            self.record(Change::RemoveUserFromGroup {
+                username: username.into(),
+                group: self.group_name(group).await?,
+            })
+            .await?;
+            if self.dry_run {
+                return Ok(());
+            }
+
+            self.inner.remove_user_from_group(username, group).await
+        })
+    }
+
+    fn update_user_attributes<'a>(
+        &'a self,
+        username: &'a str,
+        attributes: &'a BTreeMap<String, Vec<String>>,
+    ) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            for (name, value) in attributes {
+                self.record(Change::SetUserAttribute {
+                    username: username.into(),
+                    name: name.clone(),
+                    value: value.clone(),
+                })
+                .await?;
+            }
+            if self.dry_run {
+                return Ok(());
+            }
+
+            self.inner
+                .update_user_attributes(username, attributes)
+                .await
+        })
+    }
+
+    fn update_group_attributes<'a>(
+        &'a self,
+        id: i32,
+        attributes: &'a BTreeMap<String, Vec<String>>,
+    ) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            let group = self.group_name(id).await?;
+            for (name, value) in attributes {
+                self.record(Change::SetGroupAttribute {
+                    group: group.clone(),
+                    name: name.clone(),
+                    value: value.clone(),
+                })
+                .await?;
+            }
+            if self.dry_run {
+                return Ok(());
+            }
+
+            self.inner.update_group_attributes(id, attributes).await
+        })
+    }
+
+    fn update_password<'a>(
+        &'a self,
+        username: &'a str,
+        password: &'a str,
+    ) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            self.record(Change::SetPassword {
+                username: username.into(),
+            })
+            .await?;
+            if self.dry_run {
+                return Ok(());
+            }
+
+            self.inner.update_password(username, password).await
+        })
+    }
+}
+
+/// Reports the changes as events on the object that is being reconciled, for a
+/// [`RecordingBackend`] in dry run mode
+pub struct DryRunEvents {
+    reporter: DryRunReporter,
+    reference: ObjectReference,
+    /// Users that would have been created, the password of other users can not be compared so it
+    /// is not reported
+    created_users: Mutex<BTreeSet<String>>,
+}
+
+impl DryRunEvents {
+    pub fn new(reporter: DryRunReporter, reference: ObjectReference) -> Self {
+        Self {
+            reporter,
+            reference,
+            created_users: Default::default(),
+        }
+    }
+
+    fn describe(&self, change: &Change) -> Option<String> {
+        let description = match change {
+            Change::CreateGroup { group } => format!("create group '{group}'"),
+            Change::SetGroupAttribute { group, name, value } => format!(
+                "set attribute '{name}' of group '{group}' to [{}]",
+                value.join(", ")
+            ),
+            Change::DeleteGroup { group } => format!("delete group '{group}'"),
+            Change::CreateUser { username } => {
+                self.created_users
+                    .lock()
+                    .expect("Lock should not be poisoned")
+                    .insert(username.clone());
+
+                format!("create user '{username}'")
+            }
+            Change::AddUserToGroup { username, group } => {
+                format!("add user '{username}' to group '{group}'")
+            }
+            Change::RemoveUserFromGroup { username, group } => {
+                format!("remove user '{username}' from group '{group}'")
+            }
+            Change::SetUserAttribute {
+                username,
+                name,
+                value,
+            } => format!(
+                "set attribute '{name}' of user '{username}' to [{}]",
+                value.join(", ")
+            ),
+            Change::SetPassword { username } => {
+                let created = self
+                    .created_users
+                    .lock()
+                    .expect("Lock should not be poisoned")
+                    .contains(username);
+                if !created {
+                    return None;
+                }
+
+                format!("set password of user '{username}'")
+            }
+            Change::DeleteUser { username } => format!("delete user '{username}'"),
+        };
+
+        Some(description)
+    }
+}
+
+impl ChangeSink for DryRunEvents {
+    fn record<'a>(
+        &'a self,
+        _backend: &'a dyn DirectoryBackend,
+        change: Change,
+    ) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            let Some(change) = self.describe(&change) else {
+                return Ok(());
+            };
+
+            // Every change to LLDAP is its own subject, the object can need several of them
+            let reported = self
+                .reporter
+                .report(&self.reference, &change, &change)
+                .await;
+
+            // A missing event should not fail the reconcile
+            if let Err(err) = reported {
+                warn!(
+                    name = self.reference.name,
+                    "Failed to publish dry run event: {err}"
+                );
+            }
+
+            Ok(())
+        })
+    }
+}
+
+struct Cached<T> {
+    fetched: Instant,
+    value: T,
+}
+
+impl<T> Cached<T> {
+    fn new(value: T) -> Self {
+        Self {
+            fetched: Instant::now(),
+            value,
+        }
+    }
+}
+
+/// Users and groups of one directory, `None` when they need to be listed again
+#[derive(Default)]
+struct CachedDirectory {
+    users: Option<Cached<BTreeMap<String, User>>>,
+    groups: Option<Cached<Vec<Group>>>,
+}
+
+/// Short-lived copy of the users and groups of every LLDAP instance, shared between reconciles so
+/// resyncing many objects lists the users and groups once instead of once per object
+pub struct DirectoryCache {
+    ttl: Duration,
+    directories: Mutex<HashMap<Option<String>, Arc<AsyncMutex<CachedDirectory>>>>,
+}
+
+impl DirectoryCache {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            directories: Default::default(),
+        }
+    }
+
+    fn directories(
+        &self,
+    ) -> MutexGuard<'_, HashMap<Option<String>, Arc<AsyncMutex<CachedDirectory>>>> {
+        self.directories
+            .lock()
+            .expect("Lock should not be poisoned")
+    }
+
+    /// Serve the users and groups of the instance from the cache, the backend is returned as is
+    /// when the cache is disabled
+    pub fn wrap(
+        &self,
+        instance: &LldapInstance,
+        inner: Arc<dyn DirectoryBackend>,
+    ) -> Arc<dyn DirectoryBackend> {
+        if self.ttl.is_zero() {
+            return inner;
+        }
+
+        let directory = self
+            .directories()
+            .entry(instance.name.clone())
+            .or_default()
+            .clone();
+
+        Arc::new(CachedBackend {
+            inner,
+            ttl: self.ttl,
+            directory,
+        })
+    }
+
+    /// Forget the users and groups of the instance, the next read lists them again
+    pub fn invalidate(&self, instance: &LldapInstance) {
+        self.directories().remove(&instance.name);
+    }
+}
+
+/// Reads users and groups through the [`DirectoryCache`] and keeps it up to date with the changes
+/// it makes
+struct CachedBackend {
+    inner: Arc<dyn DirectoryBackend>,
+    ttl: Duration,
+    /// Locked while listing, so concurrent reconciles wait for the same list instead of all
+    /// listing the users and groups themselves
+    directory: Arc<AsyncMutex<CachedDirectory>>,
+}
+
+fn users_by_name(users: &[UserDetails]) -> BTreeMap<String, User> {
+    users
+        .iter()
+        .map(|user| {
+            let cached = User {
+                id: user.id.clone(),
+                groups: user.groups.clone(),
+            };
+
+            (user.id.clone(), cached)
+        })
+        .collect()
+}
+
+impl CachedBackend {
+    fn fresh<'a, T>(&self, cached: &'a Option<Cached<T>>) -> Option<&'a T> {
+        cached
+            .as_ref()
+            .filter(|cached| cached.fetched.elapsed() < self.ttl)
+            .map(|cached| &cached.value)
+    }
+
+    /// Apply the change to the cache after a mutation. Everything is dropped when the mutation
+    /// failed, the cache being out of date might be the reason it failed.
+    ///
+    /// Another reconcile can list the users and groups between the mutation and the update, so
+    /// the updates have to be idempotent.
+    async fn after<T>(
+        &self,
+        result: Result<T>,
+        update: impl FnOnce(&mut CachedDirectory, &T),
+    ) -> Result<T> {
+        let mut directory = self.directory.lock().await;
+        match &result {
+            Ok(value) => update(&mut directory, value),
+            Err(_) => *directory = CachedDirectory::default(),
+        }
+
+        result
+    }
+}
+
+impl DirectoryBackend for CachedBackend {
+    fn get_user<'a>(&'a self, username: &'a str) -> BoxFuture<'a, Result<Option<User>>> {
+        Box::pin(async move {
+            let mut directory = self.directory.lock().await;
+            if let Some(users) = self.fresh(&directory.users) {
+                return Ok(users.get(username).cloned());
+            }
+
+            trace!("Listing users");
+            let users = users_by_name(&self.inner.list_users().await?);
+            let user = users.get(username).cloned();
+            directory.users = Some(Cached::new(users));
+
+            Ok(user)
+        })
+    }
+
+    fn create_user<'a>(&'a self, username: &'a str) -> BoxFuture<'a, Result<User>> {
+        Box::pin(async move {
+            let result = self.inner.create_user(username).await;
+            self.after(result, |directory, user| {
+                if let Some(users) = &mut directory.users {
+                    users.value.insert(user.id.clone(), user.clone());
+                }
+            })
+            .await
+        })
+    }
+
+    fn delete_user<'a>(&'a self, username: &'a str) -> BoxFuture<'a, Result<bool>> {
+        Box::pin(async move {
+            let result = self.inner.delete_user(username).await;
+            self.after(result, |directory, _| {
+                if let Some(users) = &mut directory.users {
+                    users.value.remove(username);
+                }
+            })
+            .await
+        })
+    }
+
+    fn list_users(&self) -> BoxFuture<'_, Result<Vec<UserDetails>>> {
+        Box::pin(async move {
+            let mut directory = self.directory.lock().await;
+            let users = self.inner.list_users().await?;
+            directory.users = Some(Cached::new(users_by_name(&users)));
+
+            Ok(users)
+        })
+    }
+
+    fn get_groups(&self) -> BoxFuture<'_, Result<Vec<Group>>> {
+        Box::pin(async move {
+            let mut directory = self.directory.lock().await;
+            if let Some(groups) = self.fresh(&directory.groups) {
+                return Ok(groups.clone());
+            }
+
+            trace!("Listing groups");
+            let groups = self.inner.get_groups().await?;
+            directory.groups = Some(Cached::new(groups.clone()));
+
+            Ok(groups)
+        })
+    }
+
+    fn list_groups(&self) -> BoxFuture<'_, Result<Vec<GroupDetails>>> {
+        Box::pin(async move {
+            let mut directory = self.directory.lock().await;
+            let groups = self.inner.list_groups().await?;
+            let cached = groups
+                .iter()
+                .map(|group| Group {
+                    id: group.id,
+                    display_name: group.display_name.clone(),
+                })
+                .collect();
+            directory.groups = Some(Cached::new(cached));
+
+            Ok(groups)
+        })
+    }
+
+    fn create_group<'a>(&'a self, name: &'a str) -> BoxFuture<'a, Result<Group>> {
+        Box::pin(async move {
+            let result = self.inner.create_group(name).await;
+            self.after(result, |directory, group| {
+                if let Some(groups) = &mut directory.groups {
+                    if !groups.value.iter().any(|cached| cached.id == group.id) {
+                        groups.value.push(group.clone());
+                    }
+                }
+            })
+            .await
+        })
+    }
+
+    fn delete_group(&self, id: i32) -> BoxFuture<'_, Result<()>> {
+        Box::pin(async move {
+            let result = self.inner.delete_group(id).await;
+            self.after(result, |directory, _| {
+                if let Some(groups) = &mut directory.groups {
+                    groups.value.retain(|group| group.id != id);
+                }
+                if let Some(users) = &mut directory.users {
+                    for user in users.value.values_mut() {
+                        user.groups.retain(|group| group.id != id);
+                    }
+                }
+            })
+            .await
+        })
+    }
+
+    fn add_user_to_group<'a>(&'a self, username: &'a str, group: i32) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            let result = self.inner.add_user_to_group(username, group).await;
+            self.after(result, |directory, _| {
+                let added = directory
+                    .groups
+                    .as_ref()
+                    .and_then(|groups| groups.value.iter().find(|cached| cached.id == group))
+                    .cloned();
+                let user = directory
+                    .users
+                    .as_mut()
+                    .and_then(|users| users.value.get_mut(username));
+
+                match (user, added) {
+                    (Some(user), Some(added)) => {
+                        if !user.groups.iter().any(|cached| cached.id == group) {
+                            user.groups.push(added);
+                        }
+                    }
+                    // Without the name of the group the membership can not be cached
+                    (Some(_), None) => directory.users = None,
+                    (None, _) => {}
+                }
+            })
+            .await
+        })
+    }
+
+    fn remove_user_from_group<'a>(
+        &'a self,
+        username: &'a str,
+        group: i32,
+    ) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            let result = self.inner.remove_user_from_group(username, group).await;
+            self.after(result, |directory, _| {
+                if let Some(user) = directory
+                    .users
+                    .as_mut()
+                    .and_then(|users| users.value.get_mut(username))
+                {
+                    user.groups.retain(|cached| cached.id != group);
+                }
+            })
+            .await
+        })
+    }
+
+    // Attributes and passwords are not cached
+
+    fn update_user_attributes<'a>(
+        &'a self,
+        username: &'a str,
+        attributes: &'a BTreeMap<String, Vec<String>>,
+    ) -> BoxFuture<'a, Result<()>> {
+        self.inner.update_user_attributes(username, attributes)
+    }
+
+    fn update_group_attributes<'a>(
+        &'a self,
+        id: i32,
+        attributes: &'a BTreeMap<String, Vec<String>>,
+    ) -> BoxFuture<'a, Result<()>> {
+        self.inner.update_group_attributes(id, attributes)
+    }
+
+    fn update_password<'a>(
+        &'a self,
+        username: &'a str,
+        password: &'a str,
+    ) -> BoxFuture<'a, Result<()>> {
+        self.inner.update_password(username, password)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn memory_backend() {
+        let backend = MemoryBackend::default();
+        backend.create_group("old").await.unwrap();
+        backend.create_group("media").await.unwrap();
+
+        let user = backend.create_user("jellyfin.media").await.unwrap();
+        backend
+            .update_user_groups(&user, &["old".into()])
+            .await
+            .unwrap();
+
+        let user = backend.get_user("jellyfin.media").await.unwrap().unwrap();
+        backend
+            .update_user_groups(&user, &["media".into(), "missing".into()])
+            .await
+            .unwrap();
+        backend
+            .update_password("jellyfin.media", "secret")
+            .await
+            .unwrap();
+
+        let user = backend.get_user("jellyfin.media").await.unwrap().unwrap();
+        let groups: Vec<_> = user
+            .groups
+            .iter()
+            .map(|group| &group.display_name)
+            .collect();
+        assert_eq!(groups, ["media"]);
+        assert_eq!(backend.password("jellyfin.media"), Some("secret".into()));
+
+        assert!(backend.delete_user("jellyfin.media").await.unwrap());
+        assert!(!backend.delete_user("jellyfin.media").await.unwrap());
+        assert!(backend.get_user("jellyfin.media").await.unwrap().is_none());
+    }
+}
@@ -0,0 +1,101 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use kube::Resource;
+use kube::runtime::reflector::ObjectRef;
+use rand::Rng;
+
+/// Keeps track of consecutive reconcile failures per object and computes how long to wait before
+/// retrying, doubling the delay on every failure up to a maximum.
+pub struct Backoff {
+    base: Duration,
+    max: Duration,
+    attempts: Mutex<HashMap<String, u32>>,
+}
+
+impl Backoff {
+    pub fn new(base: Duration, max: Duration) -> Self {
+        Self {
+            base,
+            max,
+            attempts: Default::default(),
+        }
+    }
+
+    /// Register a failure for the object and return the jittered delay before the next attempt
+    pub fn next_delay<T>(&self, obj: &T) -> Duration
+    where
+        T: Resource,
+        <T as Resource>::DynamicType: Default,
+    {
+        let mut attempts = self.attempts.lock().expect("Lock should not be poisoned");
+        let attempt = attempts.entry(key(obj)).or_default();
+
+        let delay = delay_for_attempt(self.base, self.max, *attempt);
+        *attempt = attempt.saturating_add(1);
+
+        jitter(delay)
+    }
+
+    /// Forget all previous failures of the object
+    pub fn reset<T>(&self, obj: &T)
+    where
+        T: Resource,
+        <T as Resource>::DynamicType: Default,
+    {
+        self.attempts
+            .lock()
+            .expect("Lock should not be poisoned")
+            .remove(&key(obj));
+    }
+}
+
+fn key<T>(obj: &T) -> String
+where
+    T: Resource,
+    <T as Resource>::DynamicType: Default,
+{
+    ObjectRef::from_obj(obj).to_string()
+}
+
+fn delay_for_attempt(base: Duration, max: Duration, attempt: u32) -> Duration {
+    2u32.checked_pow(attempt)
+        .and_then(|factor| base.checked_mul(factor))
+        .map_or(max, |delay| delay.min(max))
+}
+
+/// Spread retries out over the second half of the delay, so objects that failed at the same time
+/// do not all retry at the same time
+fn jitter(delay: Duration) -> Duration {
+    let half = delay / 2;
+    half + rand::thread_rng().gen_range(Duration::ZERO..=half)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn delay_doubles_until_max() {
+        let base = Duration::from_secs(5);
+        let max = Duration::from_secs(300);
+
+        assert_eq!(delay_for_attempt(base, max, 0), Duration::from_secs(5));
+        assert_eq!(delay_for_attempt(base, max, 1), Duration::from_secs(10));
+        assert_eq!(delay_for_attempt(base, max, 3), Duration::from_secs(40));
+        assert_eq!(delay_for_attempt(base, max, 7), max);
+        assert_eq!(delay_for_attempt(base, max, u32::MAX), max);
+    }
+
+    #[test]
+    fn jitter_stays_within_bounds() {
+        let delay = Duration::from_secs(40);
+
+        for _ in 0..100 {
+            let jittered = jitter(delay);
+            assert!(jittered >= delay / 2);
+            assert!(jittered <= delay);
+        }
+    }
+}
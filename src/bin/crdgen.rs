@@ -1,9 +1,94 @@
-use kube::CustomResourceExt;
-
-fn main() {
-    print!(
-        "{}---\n{}",
-        serde_yaml::to_string(&lldap_controller::resources::ServiceUser::crd()).unwrap(),
-        serde_yaml::to_string(&lldap_controller::resources::Group::crd()).unwrap()
-    )
+use std::path::PathBuf;
+
+use clap::{Parser, ValueEnum};
+use lldap_controller::config::Config;
+use lldap_controller::manifests::{self, ManifestOptions};
+use lldap_controller::rbac::Rbac;
+use serde::Serialize;
+
+#[derive(ValueEnum, Clone, Copy, PartialEq, Eq)]
+enum Output {
+    Crds,
+    /// Service account, roles and bindings
+    Rbac,
+    /// Admission and conversion webhooks, including a cert-manager certificate
+    Webhooks,
+    Deployment,
+    All,
+}
+
+/// Generate the manifests needed to deploy the controller
+#[derive(Parser)]
+struct Args {
+    /// Manifests to generate
+    #[arg(value_enum, default_values_t = [Output::Crds])]
+    output: Vec<Output>,
+    /// Controller configuration the RBAC rules and deployment are derived from, the RBAC is
+    /// namespaced when `watch.namespaces` is set
+    #[arg(long)]
+    config: Option<PathBuf>,
+    /// Name of the deployment, service account and roles
+    #[arg(long, default_value = "lldap-controller")]
+    name: String,
+    /// Namespace the controller is deployed in
+    #[arg(long, default_value = "lldap")]
+    namespace: String,
+    #[arg(
+        long,
+        default_value = "git.huizinga.dev/dreaded_x/lldap-controller:latest"
+    )]
+    image: String,
+}
+
+fn push<T: Serialize>(documents: &mut Vec<String>, object: T) {
+    documents.push(serde_yaml::to_string(&object).expect("Manifest should serialize"));
+}
+
+fn main() -> anyhow::Result<()> {
+    let args = Args::parse();
+
+    let config = Config::load(args.config.as_deref())?;
+    let options = ManifestOptions {
+        name: args.name,
+        namespace: args.namespace,
+        image: args.image,
+    };
+
+    let wants = |output| args.output.contains(&output) || args.output.contains(&Output::All);
+
+    let mut documents = Vec::new();
+    if wants(Output::Crds) {
+        for crd in manifests::crds(&config, &options)? {
+            push(&mut documents, crd);
+        }
+    }
+    if wants(Output::Rbac) {
+        push(&mut documents, manifests::service_account(&options));
+
+        let rbac = Rbac::new(&config, &options.name, &options.namespace);
+        if let Some(cluster_role) = rbac.cluster_role {
+            push(&mut documents, cluster_role);
+        }
+        if let Some(cluster_role_binding) = rbac.cluster_role_binding {
+            push(&mut documents, cluster_role_binding);
+        }
+        for role in rbac.roles {
+            push(&mut documents, role);
+        }
+        for role_binding in rbac.role_bindings {
+            push(&mut documents, role_binding);
+        }
+    }
+    if wants(Output::Webhooks) {
+        for webhook in manifests::webhooks(&config, &options) {
+            push(&mut documents, webhook);
+        }
+    }
+    if wants(Output::Deployment) {
+        push(&mut documents, manifests::deployment(&config, &options));
+    }
+
+    print!("{}", documents.join("---\n"));
+
+    Ok(())
 }
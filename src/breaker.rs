@@ -0,0 +1,118 @@
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tracing::{info, warn};
+
+use crate::context::Context;
+
+#[derive(Default)]
+struct State {
+    failures: u32,
+    open: bool,
+}
+
+/// Stops reconciles from hammering LLDAP while it is unavailable.
+///
+/// The breaker trips after a number of consecutive failures, after which reconciles are skipped
+/// until a periodic probe succeeds again.
+pub struct CircuitBreaker {
+    threshold: u32,
+    probe_interval: Duration,
+    state: Mutex<State>,
+}
+
+impl CircuitBreaker {
+    pub fn new(threshold: u32, probe_interval: Duration) -> Self {
+        Self {
+            threshold,
+            probe_interval,
+            state: Default::default(),
+        }
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.state.lock().expect("Lock should not be poisoned").open
+    }
+
+    pub fn probe_interval(&self) -> Duration {
+        self.probe_interval
+    }
+
+    pub fn record_success(&self) {
+        let mut state = self.state.lock().expect("Lock should not be poisoned");
+        state.failures = 0;
+        if state.open {
+            info!("LLDAP is reachable again, closing circuit breaker");
+            state.open = false;
+        }
+    }
+
+    pub fn record_failure(&self) {
+        let mut state = self.state.lock().expect("Lock should not be poisoned");
+        state.failures = state.failures.saturating_add(1);
+        if !state.open && state.failures >= self.threshold {
+            warn!(
+                failures = state.failures,
+                "LLDAP seems to be unreachable, opening circuit breaker"
+            );
+            state.open = true;
+        }
+    }
+}
+
+/// Periodically check if LLDAP instances with an open breaker are reachable again
+pub async fn probe(ctx: Arc<Context>) {
+    loop {
+        tokio::time::sleep(Duration::from_secs(
+            ctx.config.circuit_breaker.probe_interval_secs,
+        ))
+        .await;
+
+        if let Err(err) = ctx.instances.prune(&ctx.client).await {
+            warn!("Failed to remove LLDAP instances of deleted LldapServers: {err}");
+        }
+
+        for instance in ctx.instances.all() {
+            if !instance.breaker.is_open() {
+                continue;
+            }
+
+            let server = instance.display_name();
+            let result = match instance.config.client().await {
+                Ok(client) => client.get_api_version().await,
+                Err(err) => Err(err),
+            };
+
+            match result {
+                Ok(version) => {
+                    info!(server, version, "LLDAP probe succeeded");
+                    instance.breaker.record_success();
+                }
+                Err(err) => warn!(server, "LLDAP probe failed: {err}"),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn trips_after_threshold_and_recovers() {
+        let breaker = CircuitBreaker::new(3, Duration::from_secs(30));
+
+        breaker.record_failure();
+        breaker.record_failure();
+        assert!(!breaker.is_open());
+
+        breaker.record_failure();
+        assert!(breaker.is_open());
+
+        breaker.record_success();
+        assert!(!breaker.is_open());
+
+        breaker.record_failure();
+        assert!(!breaker.is_open());
+    }
+}
@@ -0,0 +1,628 @@
+use std::collections::BTreeMap;
+use std::env::VarError;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+use anyhow::Context;
+use passwords::PasswordGenerator;
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase", default, deny_unknown_fields)]
+pub struct Config {
+    /// Used as the finalizer, field manager and event reporter
+    pub controller_name: String,
+    /// Seconds between reconciles of objects that reconciled successfully, used for every kind
+    /// that has no interval in `resync`
+    pub requeue_interval_secs: u64,
+    pub resync: ResyncConfig,
+    /// Seconds the users and groups listed from LLDAP are reused by other reconciles, so a resync
+    /// of many objects only lists them once. 0 disables the cache
+    pub cache_ttl_secs: u64,
+    /// Only log and publish events for the changes that would be made in LLDAP and Kubernetes,
+    /// without making them
+    pub dry_run: bool,
+    pub pause: PauseConfig,
+    pub error_backoff: ErrorBackoffConfig,
+    pub circuit_breaker: CircuitBreakerConfig,
+    pub password: PasswordConfig,
+    pub groups: GroupsConfig,
+    pub watch: WatchConfig,
+    pub policy: PolicyConfig,
+    pub webhook: WebhookConfig,
+    pub lldap: LldapSettings,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            controller_name: "lldap.huizinga.dev".into(),
+            requeue_interval_secs: 3600,
+            resync: Default::default(),
+            cache_ttl_secs: 10,
+            dry_run: false,
+            pause: Default::default(),
+            error_backoff: Default::default(),
+            circuit_breaker: Default::default(),
+            password: Default::default(),
+            groups: Default::default(),
+            watch: Default::default(),
+            policy: Default::default(),
+            webhook: Default::default(),
+            lldap: Default::default(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase", default, deny_unknown_fields)]
+pub struct ErrorBackoffConfig {
+    /// Seconds to wait after the first failure, doubled on every consecutive failure
+    pub base_secs: u64,
+    /// Upper limit for the delay between retries
+    pub max_secs: u64,
+}
+
+impl Default for ErrorBackoffConfig {
+    fn default() -> Self {
+        Self {
+            base_secs: 5,
+            max_secs: 300,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase", default, deny_unknown_fields)]
+pub struct CircuitBreakerConfig {
+    /// Consecutive LLDAP failures before reconciles are paused
+    pub threshold: u32,
+    /// Seconds between checks if LLDAP is reachable again
+    pub probe_interval_secs: u64,
+}
+
+impl Default for CircuitBreakerConfig {
+    fn default() -> Self {
+        Self {
+            threshold: 5,
+            probe_interval_secs: 30,
+        }
+    }
+}
+
+/// Settings for the passwords generated for service users
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase", default, deny_unknown_fields)]
+pub struct PasswordConfig {
+    pub length: usize,
+    pub numbers: bool,
+    pub lowercase_letters: bool,
+    pub uppercase_letters: bool,
+    pub symbols: bool,
+    pub exclude_similar_characters: bool,
+    /// Require at least one character of every enabled kind
+    pub strict: bool,
+}
+
+impl Default for PasswordConfig {
+    fn default() -> Self {
+        Self {
+            length: 32,
+            numbers: true,
+            lowercase_letters: true,
+            uppercase_letters: true,
+            symbols: false,
+            exclude_similar_characters: false,
+            strict: true,
+        }
+    }
+}
+
+impl PasswordConfig {
+    pub fn generator(&self) -> PasswordGenerator {
+        PasswordGenerator::new()
+            .length(self.length)
+            .numbers(self.numbers)
+            .lowercase_letters(self.lowercase_letters)
+            .uppercase_letters(self.uppercase_letters)
+            .symbols(self.symbols)
+            .exclude_similar_characters(self.exclude_similar_characters)
+            .strict(self.strict)
+    }
+}
+
+/// Names of the builtin LLDAP groups
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase", default, deny_unknown_fields)]
+pub struct GroupsConfig {
+    /// Group for service users that can manage passwords
+    pub password_manager: String,
+    /// Group for all other service users
+    pub strict_readonly: String,
+}
+
+impl Default for GroupsConfig {
+    fn default() -> Self {
+        Self {
+            password_manager: "lldap_password_manager".into(),
+            strict_readonly: "lldap_strict_readonly".into(),
+        }
+    }
+}
+
+/// Limits which objects the controller watches, so multiple scoped instances can run in the same
+/// cluster with namespaced permissions
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase", default, deny_unknown_fields)]
+pub struct WatchConfig {
+    /// Namespaces to watch ServiceUsers in, all namespaces when empty
+    pub namespaces: Vec<String>,
+    /// Only reconcile ServiceUsers and Groups matching this label selector
+    pub label_selector: Option<String>,
+    /// Groups are cluster scoped, disable this when running with only namespaced permissions
+    pub groups: bool,
+    /// Seconds between polls of LLDAP for changes made outside the controller, objects whose user
+    /// or group changed are reconciled right away. `null` disables polling
+    pub lldap_poll_interval_secs: Option<u64>,
+}
+
+impl Default for WatchConfig {
+    fn default() -> Self {
+        Self {
+            namespaces: Vec::new(),
+            label_selector: None,
+            groups: true,
+            lldap_poll_interval_secs: Some(60),
+        }
+    }
+}
+
+/// Restrictions on what ServiceUsers and Groups are allowed to do
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase", default, deny_unknown_fields)]
+pub struct PolicyConfig {
+    /// Privileged groups that service users can not be added to and that can not be managed
+    /// through a Group
+    pub denied_groups: Vec<String>,
+    /// Namespaces allowed to request a group, groups that are not listed can be requested from
+    /// every namespace
+    pub allowed_namespaces: BTreeMap<String, Vec<String>>,
+    /// Namespaces of the group owners, GroupBindings in these namespaces are trusted. When set,
+    /// service users can only join groups that a GroupBinding grants their namespace access to
+    pub group_binding_namespaces: Vec<String>,
+}
+
+impl Default for PolicyConfig {
+    fn default() -> Self {
+        Self {
+            denied_groups: vec!["lldap_admin".into()],
+            allowed_namespaces: BTreeMap::new(),
+            group_binding_namespaces: Vec::new(),
+        }
+    }
+}
+
+/// Interval between reconciles of objects that reconciled successfully, objects can override it
+/// with `spec.resyncIntervalSecs`
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase", default, deny_unknown_fields)]
+pub struct ResyncConfig {
+    /// Seconds between reconciles of ServiceUsers, defaults to `requeueIntervalSecs`
+    pub service_user_secs: Option<u64>,
+    /// Seconds between reconciles of Groups, defaults to `requeueIntervalSecs`
+    pub group_secs: Option<u64>,
+    /// Up to this percentage of the interval a reconcile is randomly moved forward, so objects
+    /// that were reconciled together (for example at startup) do not all resync together
+    pub jitter_percent: u8,
+}
+
+impl Default for ResyncConfig {
+    fn default() -> Self {
+        Self {
+            service_user_secs: None,
+            group_secs: None,
+            jitter_percent: 10,
+        }
+    }
+}
+
+/// Stop reconciling objects, so changes made by hand in LLDAP are not reverted
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase", default, deny_unknown_fields)]
+pub struct PauseConfig {
+    /// Pause every object, like setting the paused annotation on all of them
+    pub all: bool,
+    /// Still delete the user or group of a paused object when the object is deleted, otherwise
+    /// the deletion waits until the object is no longer paused
+    pub cleanup: bool,
+}
+
+impl Default for PauseConfig {
+    fn default() -> Self {
+        Self {
+            all: false,
+            cleanup: true,
+        }
+    }
+}
+
+/// Validating admission webhook, rejects invalid specs at apply time
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase", default, deny_unknown_fields)]
+pub struct WebhookConfig {
+    pub enabled: bool,
+    pub listen: SocketAddr,
+    /// The api server only talks to webhooks over https
+    pub cert_file: PathBuf,
+    pub key_file: PathBuf,
+}
+
+impl Default for WebhookConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            listen: ([0, 0, 0, 0], 8443).into(),
+            cert_file: "/etc/lldap-controller/tls/tls.crt".into(),
+            key_file: "/etc/lldap-controller/tls/tls.key".into(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase", default, deny_unknown_fields)]
+pub struct LldapSettings {
+    pub url: Option<String>,
+    pub username: Option<String>,
+    pub username_file: Option<PathBuf>,
+    pub password: Option<String>,
+    pub password_file: Option<PathBuf>,
+    pub timeout_secs: u64,
+    pub connect_timeout_secs: Option<u64>,
+    /// PEM bundle with additional certificates to trust
+    pub ca_file: Option<PathBuf>,
+    /// PEM certificate and key used for mutual TLS
+    pub client_cert_file: Option<PathBuf>,
+    pub client_key_file: Option<PathBuf>,
+    pub insecure_skip_verify: bool,
+    pub proxy: Option<String>,
+}
+
+impl Default for LldapSettings {
+    fn default() -> Self {
+        Self {
+            url: None,
+            username: None,
+            username_file: None,
+            password: None,
+            password_file: None,
+            timeout_secs: 1,
+            connect_timeout_secs: None,
+            ca_file: None,
+            client_cert_file: None,
+            client_key_file: None,
+            insecure_skip_verify: false,
+            proxy: None,
+        }
+    }
+}
+
+fn optional_env(name: &str) -> anyhow::Result<Option<String>> {
+    match std::env::var(name) {
+        Ok(value) => Ok(Some(value)),
+        Err(VarError::NotPresent) => Ok(None),
+        Err(err) => Err(err).with_context(|| format!("Variable '{name}' is invalid")),
+    }
+}
+
+fn parse_env<T>(name: &str, key: &str) -> anyhow::Result<Option<T>>
+where
+    T: FromStr,
+    <T as FromStr>::Err: std::error::Error + Send + Sync + 'static,
+{
+    optional_env(name)?
+        .map(|value| value.parse())
+        .transpose()
+        .with_context(|| format!("Variable '{name}' is not a valid value for '{key}'"))
+}
+
+fn invalid(key: &str, reason: &str) -> anyhow::Error {
+    anyhow::anyhow!("Invalid value for '{key}': {reason}")
+}
+
+impl Config {
+    /// Load the configuration from the (optional) file, apply the environment variable overrides
+    /// and validate the result
+    pub fn load(path: Option<&Path>) -> anyhow::Result<Self> {
+        let mut config = match path {
+            Some(path) => Self::from_file(path)?,
+            None => Self::default(),
+        };
+
+        config.apply_env()?;
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    fn from_file(path: &Path) -> anyhow::Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read config file '{}'", path.display()))?;
+
+        Self::from_yaml(&contents)
+            .with_context(|| format!("Failed to load config file '{}'", path.display()))
+    }
+
+    fn from_yaml(contents: &str) -> anyhow::Result<Self> {
+        serde_path_to_error::deserialize(serde_yaml::Deserializer::from_str(contents)).map_err(
+            |err| {
+                let path = err.path().to_string();
+                let err = err.into_inner();
+                if path == "." {
+                    anyhow::anyhow!("{err}")
+                } else {
+                    invalid(&path, &err.to_string())
+                }
+            },
+        )
+    }
+
+    fn apply_env(&mut self) -> anyhow::Result<()> {
+        if let Some(name) = optional_env("LLDAP_CONTROLLER_NAME")? {
+            self.controller_name = name;
+        }
+        if let Some(namespaces) = optional_env("LLDAP_CONTROLLER_NAMESPACES")? {
+            self.watch.namespaces = namespaces
+                .split(',')
+                .map(|namespace| namespace.trim().to_owned())
+                .filter(|namespace| !namespace.is_empty())
+                .collect();
+        }
+        if let Some(selector) = optional_env("LLDAP_CONTROLLER_LABEL_SELECTOR")? {
+            self.watch.label_selector = Some(selector);
+        }
+        if let Some(enabled) = parse_env("LLDAP_CONTROLLER_WEBHOOK_ENABLED", "webhook.enabled")? {
+            self.webhook.enabled = enabled;
+        }
+        if let Some(dry_run) = parse_env("LLDAP_CONTROLLER_DRY_RUN", "dryRun")? {
+            self.dry_run = dry_run;
+        }
+        if let Some(paused) = parse_env("LLDAP_CONTROLLER_PAUSED", "pause.all")? {
+            self.pause.all = paused;
+        }
+
+        let lldap = &mut self.lldap;
+        if let Some(url) = optional_env("LLDAP_URL")? {
+            lldap.url = Some(url);
+        }
+        if let Some(username) = optional_env("LLDAP_USERNAME")? {
+            lldap.username = Some(username);
+            lldap.username_file = None;
+        }
+        if let Some(username_file) = optional_env("LLDAP_USERNAME_FILE")? {
+            lldap.username_file = Some(username_file.into());
+            lldap.username = None;
+        }
+        if let Some(password) = optional_env("LLDAP_PASSWORD")? {
+            lldap.password = Some(password);
+            lldap.password_file = None;
+        }
+        if let Some(password_file) = optional_env("LLDAP_PASSWORD_FILE")? {
+            lldap.password_file = Some(password_file.into());
+            lldap.password = None;
+        }
+        if let Some(timeout) = parse_env("LLDAP_TIMEOUT", "lldap.timeoutSecs")? {
+            lldap.timeout_secs = timeout;
+        }
+        if let Some(timeout) = parse_env("LLDAP_CONNECT_TIMEOUT", "lldap.connectTimeoutSecs")? {
+            lldap.connect_timeout_secs = Some(timeout);
+        }
+        if let Some(ca_file) = optional_env("LLDAP_CA_FILE")? {
+            lldap.ca_file = Some(ca_file.into());
+        }
+        if let Some(cert_file) = optional_env("LLDAP_CLIENT_CERT_FILE")? {
+            lldap.client_cert_file = Some(cert_file.into());
+        }
+        if let Some(key_file) = optional_env("LLDAP_CLIENT_KEY_FILE")? {
+            lldap.client_key_file = Some(key_file.into());
+        }
+        if let Some(insecure) = parse_env("LLDAP_INSECURE_SKIP_VERIFY", "lldap.insecureSkipVerify")?
+        {
+            lldap.insecure_skip_verify = insecure;
+        }
+        if let Some(proxy) = optional_env("LLDAP_PROXY")? {
+            lldap.proxy = Some(proxy);
+        }
+
+        Ok(())
+    }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        // The controller name is also used as label value on the secrets we create
+        if self.controller_name.is_empty()
+            || self.controller_name.len() > 63
+            || !self
+                .controller_name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+        {
+            return Err(invalid(
+                "controllerName",
+                "should be a valid label value (at most 63 alphanumeric characters, '-', '_' or '.')",
+            ));
+        }
+        if self.requeue_interval_secs == 0 {
+            return Err(invalid("requeueIntervalSecs", "should be at least 1"));
+        }
+        if self.resync.service_user_secs == Some(0) {
+            return Err(invalid("resync.serviceUserSecs", "should be at least 1"));
+        }
+        if self.resync.group_secs == Some(0) {
+            return Err(invalid("resync.groupSecs", "should be at least 1"));
+        }
+        if self.resync.jitter_percent > 90 {
+            return Err(invalid("resync.jitterPercent", "should be at most 90"));
+        }
+        if self.error_backoff.base_secs == 0 {
+            return Err(invalid("errorBackoff.baseSecs", "should be at least 1"));
+        }
+        if self.error_backoff.max_secs < self.error_backoff.base_secs {
+            return Err(invalid(
+                "errorBackoff.maxSecs",
+                "should not be smaller than 'errorBackoff.baseSecs'",
+            ));
+        }
+        if self.circuit_breaker.threshold == 0 {
+            return Err(invalid("circuitBreaker.threshold", "should be at least 1"));
+        }
+        if self.circuit_breaker.probe_interval_secs == 0 {
+            return Err(invalid(
+                "circuitBreaker.probeIntervalSecs",
+                "should be at least 1",
+            ));
+        }
+        if let Err(err) = self.password.generator().generate_one() {
+            return Err(invalid("password", err));
+        }
+        if self.groups.password_manager.is_empty() {
+            return Err(invalid("groups.passwordManager", "can not be empty"));
+        }
+        if self.groups.strict_readonly.is_empty() {
+            return Err(invalid("groups.strictReadonly", "can not be empty"));
+        }
+        if self.watch.namespaces.iter().any(String::is_empty) {
+            return Err(invalid(
+                "watch.namespaces",
+                "can not contain empty namespaces",
+            ));
+        }
+        if self
+            .watch
+            .label_selector
+            .as_ref()
+            .is_some_and(|selector| selector.trim().is_empty())
+        {
+            return Err(invalid("watch.labelSelector", "can not be empty"));
+        }
+        if self.watch.lldap_poll_interval_secs == Some(0) {
+            return Err(invalid(
+                "watch.lldapPollIntervalSecs",
+                "should be at least 1, use null to disable polling",
+            ));
+        }
+        if self.policy.denied_groups.iter().any(String::is_empty) {
+            return Err(invalid(
+                "policy.deniedGroups",
+                "can not contain empty group names",
+            ));
+        }
+        if self
+            .policy
+            .group_binding_namespaces
+            .iter()
+            .any(String::is_empty)
+        {
+            return Err(invalid(
+                "policy.groupBindingNamespaces",
+                "can not contain empty namespaces",
+            ));
+        }
+        if let Some(group) = self
+            .policy
+            .allowed_namespaces
+            .iter()
+            .find_map(|(group, namespaces)| namespaces.is_empty().then_some(group))
+        {
+            return Err(invalid(
+                &format!("policy.allowedNamespaces.{group}"),
+                "should list at least one namespace, use 'policy.deniedGroups' to deny the group everywhere",
+            ));
+        }
+
+        // Without a url there is no default server, every object needs a serverRef
+        let lldap = &self.lldap;
+        let Some(url) = &lldap.url else {
+            return Ok(());
+        };
+        reqwest::Url::parse(url).map_err(|err| invalid("lldap.url", &err.to_string()))?;
+        if lldap.username.is_some() == lldap.username_file.is_some() {
+            return Err(invalid(
+                "lldap.username",
+                "exactly one of 'lldap.username' and 'lldap.usernameFile' needs to be set",
+            ));
+        }
+        if lldap.password.is_some() == lldap.password_file.is_some() {
+            return Err(invalid(
+                "lldap.password",
+                "exactly one of 'lldap.password' and 'lldap.passwordFile' needs to be set",
+            ));
+        }
+        if lldap.timeout_secs == 0 {
+            return Err(invalid("lldap.timeoutSecs", "should be at least 1"));
+        }
+        if lldap.client_cert_file.is_some() != lldap.client_key_file.is_some() {
+            return Err(invalid(
+                "lldap.clientCertFile",
+                "'lldap.clientCertFile' and 'lldap.clientKeyFile' need to be set together",
+            ));
+        }
+
+        Ok(())
+    }
+
+    pub fn service_user_resync_interval(&self) -> Duration {
+        Duration::from_secs(
+            self.resync
+                .service_user_secs
+                .unwrap_or(self.requeue_interval_secs),
+        )
+    }
+
+    pub fn group_resync_interval(&self) -> Duration {
+        Duration::from_secs(self.resync.group_secs.unwrap_or(self.requeue_interval_secs))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_config_roundtrips() {
+        let yaml = serde_yaml::to_string(&Config::default()).unwrap();
+        let config = Config::from_yaml(&yaml).unwrap();
+
+        assert_eq!(config.controller_name, "lldap.huizinga.dev");
+        assert_eq!(config.requeue_interval_secs, 3600);
+    }
+
+    #[test]
+    fn resync_interval_per_kind() {
+        let config =
+            Config::from_yaml("requeueIntervalSecs: 600\nresync:\n  groupSecs: 86400\n").unwrap();
+
+        assert_eq!(
+            config.service_user_resync_interval(),
+            Duration::from_secs(600)
+        );
+        assert_eq!(config.group_resync_interval(), Duration::from_secs(86400));
+    }
+
+    #[test]
+    fn errors_name_the_key() {
+        let err = Config::from_yaml("errorBackoff:\n  maxSecs: soon\n").unwrap_err();
+        assert!(err.to_string().contains("'errorBackoff.maxSecs'"), "{err}");
+
+        let err = Config::from_yaml("lldap:\n  urll: http://lldap\n").unwrap_err();
+        assert!(err.to_string().contains("urll"), "{err}");
+
+        let mut config = Config::default();
+        config.lldap.url = Some("http://lldap:17170".into());
+        config.lldap.username = Some("admin".into());
+        config.lldap.password = Some("password".into());
+        config.error_backoff.max_secs = 1;
+        let err = config.validate().unwrap_err();
+        assert!(err.to_string().contains("'errorBackoff.maxSecs'"), "{err}");
+    }
+}
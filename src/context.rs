@@ -1,28 +1,118 @@
-use k8s_openapi::api::core::v1::Secret;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use k8s_openapi::api::core::v1::{ObjectReference, Secret};
 use kube::runtime::events::{Event, EventType, Recorder, Reporter};
 use kube::{Resource, ResourceExt};
+use tracing::info;
+
+use crate::backend::{DirectoryBackend, DirectoryCache, DryRunEvents, RecordingBackend};
+use crate::backoff::Backoff;
+use crate::config::Config;
+use crate::instances::{LldapInstance, LldapInstances};
+use crate::lldap;
+use crate::poller::OwnChanges;
+use crate::resources::GrantStores;
 
-use crate::lldap::LldapConfig;
+/// Label used to mark the objects created by the controller
+pub const MANAGED_BY_LABEL: &str = "app.kubernetes.io/managed-by";
 
 #[derive(Clone)]
 pub struct Context {
     pub client: kube::Client,
-    pub lldap_config: LldapConfig,
+    pub config: Arc<Config>,
+    pub instances: Arc<LldapInstances>,
     pub controller_name: String,
     pub recorder: Recorder,
+    pub backoff: Arc<Backoff>,
+    pub cache: Arc<DirectoryCache>,
+    /// Users and groups changed by the reconcilers, for the poller to skip
+    pub own_changes: Arc<OwnChanges>,
+    /// Used for every LLDAP instance instead of connecting to them over GraphQL
+    pub backend: Option<Arc<dyn DirectoryBackend>>,
+    /// Serves the granted groups from watches instead of the API server
+    pub grants: Option<GrantStores>,
+    pub dry_run_reporter: DryRunReporter,
 }
 
 impl Context {
-    pub fn new(controller_name: &str, client: kube::Client, lldap_config: LldapConfig) -> Self {
-        let reporter: Reporter = controller_name.into();
-        let recorder = Recorder::new(client.clone(), reporter);
+    pub fn new(client: kube::Client, config: Config) -> anyhow::Result<Self> {
+        let reporter: Reporter = config.controller_name.as_str().into();
+        let recorder = Recorder::new(client.clone(), reporter.clone());
+        let dry_run_reporter = DryRunReporter::new(client.clone(), reporter);
 
-        Self {
+        Ok(Self {
             client,
-            lldap_config,
-            controller_name: controller_name.into(),
+            instances: Arc::new(LldapInstances::new(&config)?),
+            controller_name: config.controller_name.clone(),
             recorder,
+            backoff: Arc::new(Backoff::new(
+                Duration::from_secs(config.error_backoff.base_secs),
+                Duration::from_secs(config.error_backoff.max_secs),
+            )),
+            cache: Arc::new(DirectoryCache::new(Duration::from_secs(
+                config.cache_ttl_secs,
+            ))),
+            own_changes: Default::default(),
+            config: Arc::new(config),
+            backend: None,
+            grants: None,
+            dry_run_reporter,
+        })
+    }
+
+    pub fn with_backend(self, backend: Arc<dyn DirectoryBackend>) -> Self {
+        Self {
+            backend: Some(backend),
+            ..self
+        }
+    }
+
+    pub fn with_grants(self, grants: GrantStores) -> Self {
+        Self {
+            grants: Some(grants),
+            ..self
+        }
+    }
+
+    /// Backend to manage the users and groups of the instance with, in dry run mode the changes
+    /// are reported as events on the object instead
+    pub async fn backend<T>(
+        &self,
+        instance: &LldapInstance,
+        obj: &T,
+    ) -> lldap::Result<Arc<dyn DirectoryBackend>>
+    where
+        T: Resource<DynamicType = ()>,
+    {
+        let backend = self.read_backend(instance).await?;
+
+        if !self.config.dry_run {
+            return Ok(self.own_changes.wrap(instance, backend));
         }
+
+        let events = DryRunEvents::new(self.dry_run_reporter.clone(), obj.object_ref(&()));
+
+        Ok(Arc::new(RecordingBackend::dry_run(backend, events)))
+    }
+
+    /// Backend to only read the users and groups of the instance with, it is never wrapped for dry
+    /// run mode
+    pub async fn read_backend(
+        &self,
+        instance: &LldapInstance,
+    ) -> lldap::Result<Arc<dyn DirectoryBackend>> {
+        let backend: Arc<dyn DirectoryBackend> = match &self.backend {
+            Some(backend) => backend.clone(),
+            None => Arc::new(instance.config.client().await?),
+        };
+
+        Ok(self.cache.wrap(instance, backend))
+    }
+
+    pub fn managed_by_selector(&self) -> String {
+        format!("{MANAGED_BY_LABEL}={}", self.controller_name)
     }
 }
 
@@ -34,6 +124,10 @@ pub trait ControllerEvents {
     where
         T: Resource<DynamicType = ()> + Sync;
 
+    async fn password_rotated<T>(&self, obj: &T, secret: &Secret) -> Result<(), Self::Error>
+    where
+        T: Resource<DynamicType = ()> + Sync;
+
     async fn user_created<T>(&self, obj: &T, username: &str) -> Result<(), Self::Error>
     where
         T: Resource<DynamicType = ()> + Sync;
@@ -53,6 +147,90 @@ pub trait ControllerEvents {
     async fn user_not_found<T>(&self, obj: &T, username: &str) -> Result<(), Self::Error>
     where
         T: Resource<DynamicType = ()> + Sync;
+
+    async fn policy_violation<T>(&self, obj: &T, message: &str) -> Result<(), Self::Error>
+    where
+        T: Resource<DynamicType = ()> + Sync;
+
+    /// The object was deleted without removing its user or group from LLDAP
+    async fn cleanup_skipped<T>(&self, obj: &T, reason: &str) -> Result<(), Self::Error>
+    where
+        T: Resource<DynamicType = ()> + Sync;
+
+    /// A change that was not made because of dry run mode
+    async fn would_change(
+        &self,
+        reference: &ObjectReference,
+        change: &str,
+    ) -> Result<(), Self::Error>;
+}
+
+/// Publishes the changes that are not made in dry run mode as events, the same change to an object
+/// is found again by every reconcile but only reported once
+#[derive(Clone)]
+pub struct DryRunReporter {
+    client: kube::Client,
+    reporter: Reporter,
+    /// Last change reported, by object and the subject of the change
+    reported: Arc<Mutex<HashMap<(String, String), String>>>,
+}
+
+impl DryRunReporter {
+    pub fn new(client: kube::Client, reporter: Reporter) -> Self {
+        Self {
+            client,
+            reporter,
+            reported: Default::default(),
+        }
+    }
+
+    fn object_key(reference: &ObjectReference) -> String {
+        format!(
+            "{}/{}/{}",
+            reference.kind.as_deref().unwrap_or_default(),
+            reference.namespace.as_deref().unwrap_or_default(),
+            reference.name.as_deref().unwrap_or_default()
+        )
+    }
+
+    /// Report the change to the subject of the object, unless it is the change that was reported
+    /// for the subject last. Returns if the change was reported.
+    pub async fn report(
+        &self,
+        reference: &ObjectReference,
+        subject: &str,
+        change: &str,
+    ) -> Result<bool, kube::Error> {
+        let key = (Self::object_key(reference), subject.to_owned());
+        let previous = self
+            .reported
+            .lock()
+            .expect("Lock should not be poisoned")
+            .insert(key, change.into());
+        if previous.as_deref() == Some(change) {
+            return Ok(false);
+        }
+
+        info!(name = reference.name, "Dry run, would {change}");
+
+        // All dry run events share their reason and action, a shared recorder would merge them
+        // into a single series that only shows the first change
+        Recorder::new(self.client.clone(), self.reporter.clone())
+            .would_change(reference, change)
+            .await?;
+
+        Ok(true)
+    }
+
+    /// Forget the changes reported for the object, once it is deleted
+    pub fn forget(&self, reference: &ObjectReference) {
+        let object = Self::object_key(reference);
+
+        self.reported
+            .lock()
+            .expect("Lock should not be poisoned")
+            .retain(|(reported, _), _| *reported != object);
+    }
 }
 
 impl ControllerEvents for Recorder {
@@ -75,6 +253,26 @@ impl ControllerEvents for Recorder {
         .await
     }
 
+    async fn password_rotated<T>(&self, obj: &T, secret: &Secret) -> Result<(), Self::Error>
+    where
+        T: Resource<DynamicType = ()> + Sync,
+    {
+        self.publish(
+            &Event {
+                type_: EventType::Normal,
+                reason: "PasswordRotated".into(),
+                note: Some(format!(
+                    "Generated new password in secret '{}'",
+                    secret.name_any()
+                )),
+                action: "PasswordRotated".into(),
+                secondary: Some(secret.object_ref(&())),
+            },
+            &obj.object_ref(&()),
+        )
+        .await
+    }
+
     async fn user_created<T>(&self, obj: &T, username: &str) -> Result<(), Self::Error>
     where
         T: Resource<DynamicType = ()> + Sync,
@@ -159,4 +357,58 @@ impl ControllerEvents for Recorder {
         )
         .await
     }
+
+    async fn policy_violation<T>(&self, obj: &T, message: &str) -> Result<(), Self::Error>
+    where
+        T: Resource<DynamicType = ()> + Sync,
+    {
+        self.publish(
+            &Event {
+                type_: EventType::Warning,
+                reason: "PolicyViolation".into(),
+                note: Some(message.into()),
+                action: "PolicyViolation".into(),
+                secondary: None,
+            },
+            &obj.object_ref(&()),
+        )
+        .await
+    }
+
+    async fn cleanup_skipped<T>(&self, obj: &T, reason: &str) -> Result<(), Self::Error>
+    where
+        T: Resource<DynamicType = ()> + Sync,
+    {
+        self.publish(
+            &Event {
+                type_: EventType::Warning,
+                reason: "CleanupSkipped".into(),
+                note: Some(format!(
+                    "Removed finalizer without cleaning up LLDAP: {reason}"
+                )),
+                action: "CleanupSkipped".into(),
+                secondary: None,
+            },
+            &obj.object_ref(&()),
+        )
+        .await
+    }
+
+    async fn would_change(
+        &self,
+        reference: &ObjectReference,
+        change: &str,
+    ) -> Result<(), Self::Error> {
+        self.publish(
+            &Event {
+                type_: EventType::Normal,
+                reason: "DryRun".into(),
+                note: Some(format!("Would {change}")),
+                action: "DryRun".into(),
+                secondary: None,
+            },
+            reference,
+        )
+        .await
+    }
 }
@@ -0,0 +1,355 @@
+use std::fmt;
+
+use k8s_openapi::api::authorization::v1::{
+    ResourceAttributes, SelfSubjectAccessReview, SelfSubjectAccessReviewSpec,
+};
+use kube::api::PostParams;
+use kube::{Api, ResourceExt};
+
+use crate::backend::DirectoryBackend;
+use crate::config::Config;
+use crate::instances::{LldapInstance, LldapInstances};
+use crate::rbac::{self, Access};
+use crate::resources::LldapServer;
+
+/// Group LLDAP gives full access to, the controller needs it to manage users and groups
+const ADMIN_GROUP: &str = "lldap_admin";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    Pass(String),
+    Fail {
+        problem: String,
+        hint: String,
+    },
+    /// Not checked because an earlier check failed
+    Skip(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Check {
+    pub name: String,
+    pub outcome: Outcome,
+}
+
+/// Result of all checks
+#[derive(Debug, Default)]
+pub struct Report {
+    pub checks: Vec<Check>,
+}
+
+impl Report {
+    fn pass(&mut self, name: impl Into<String>, detail: impl Into<String>) {
+        self.checks.push(Check {
+            name: name.into(),
+            outcome: Outcome::Pass(detail.into()),
+        });
+    }
+
+    fn fail(&mut self, name: impl Into<String>, problem: impl Into<String>, hint: &str) {
+        self.checks.push(Check {
+            name: name.into(),
+            outcome: Outcome::Fail {
+                problem: problem.into(),
+                hint: hint.into(),
+            },
+        });
+    }
+
+    fn skip(&mut self, name: impl Into<String>, reason: impl Into<String>) {
+        self.checks.push(Check {
+            name: name.into(),
+            outcome: Outcome::Skip(reason.into()),
+        });
+    }
+
+    pub fn passed(&self) -> bool {
+        !self
+            .checks
+            .iter()
+            .any(|check| matches!(check.outcome, Outcome::Fail { .. }))
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for check in &self.checks {
+            match &check.outcome {
+                Outcome::Pass(detail) => writeln!(f, "PASS  {}: {detail}", check.name)?,
+                Outcome::Fail { problem, hint } => {
+                    writeln!(f, "FAIL  {}: {problem}", check.name)?;
+                    writeln!(f, "      hint: {hint}")?;
+                }
+                Outcome::Skip(reason) => writeln!(f, "SKIP  {}: {reason}", check.name)?,
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn access_name(access: &Access) -> String {
+    let mut resource = access.resource.clone();
+    if let Some(subresource) = &access.subresource {
+        resource = format!("{resource}/{subresource}");
+    }
+    if !access.group.is_empty() {
+        resource = format!("{resource}.{}", access.group);
+    }
+
+    match &access.namespace {
+        Some(namespace) => format!("RBAC {resource} in '{namespace}'"),
+        None => format!("RBAC {resource}"),
+    }
+}
+
+/// Verbs the service account is not allowed to use
+async fn denied_verbs(client: &kube::Client, access: &Access) -> kube::Result<Vec<String>> {
+    let reviews = Api::<SelfSubjectAccessReview>::all(client.clone());
+
+    let mut denied = Vec::new();
+    for verb in &access.verbs {
+        let review = SelfSubjectAccessReview {
+            spec: SelfSubjectAccessReviewSpec {
+                resource_attributes: Some(ResourceAttributes {
+                    group: Some(access.group.clone()),
+                    resource: Some(access.resource.clone()),
+                    subresource: access.subresource.clone(),
+                    verb: Some(verb.clone()),
+                    namespace: access.namespace.clone(),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        let review = reviews.create(&PostParams::default(), &review).await?;
+        if !review.status.is_some_and(|status| status.allowed) {
+            denied.push(verb.clone());
+        }
+    }
+
+    Ok(denied)
+}
+
+async fn check_rbac(report: &mut Report, client: &kube::Client, config: &Config) {
+    let namespace = client.default_namespace().to_owned();
+
+    for access in rbac::required_access(config, &namespace) {
+        let name = access_name(&access);
+
+        match denied_verbs(client, &access).await {
+            Ok(denied) if denied.is_empty() => report.pass(name, access.verbs.join(", ")),
+            Ok(denied) => report.fail(
+                name,
+                format!("not allowed to {}", denied.join(", ")),
+                "apply the roles generated by `crdgen rbac` with the same configuration",
+            ),
+            Err(err) => report.fail(
+                name,
+                format!("access review failed: {err}"),
+                "the service account needs to be able to create SelfSubjectAccessReviews",
+            ),
+        }
+    }
+}
+
+async fn check_lldap(report: &mut Report, config: &Config, instance: &LldapInstance) {
+    let server = instance.display_name();
+    let url = instance.config.url();
+    let name = |check: &str| format!("LLDAP '{server}' {check}");
+
+    let client = match instance.config.build_client().await {
+        Ok(client) => {
+            report.pass(name("reachable"), url);
+            report.pass(name("login"), instance.config.username());
+            client
+        }
+        Err(err) if err.is_unavailable() => {
+            report.fail(
+                name("reachable"),
+                format!("{url}: {err}"),
+                "check the url, TLS settings, proxy and network policies between the controller and LLDAP",
+            );
+            report.skip(name("login"), "LLDAP is not reachable");
+            return;
+        }
+        Err(err) => {
+            report.pass(name("reachable"), url);
+            report.fail(
+                name("login"),
+                err.to_string(),
+                "check the username and password the controller logs in with",
+            );
+            return;
+        }
+    };
+
+    match client.get_api_version().await {
+        Ok(version) => report.pass(name("apiVersion"), version),
+        Err(err) => report.fail(
+            name("apiVersion"),
+            err.to_string(),
+            "make sure the url points at LLDAP and not at a proxy or another service",
+        ),
+    }
+
+    let username = instance.config.username();
+    match client.get_user(&username).await {
+        Ok(Some(user))
+            if user
+                .groups
+                .iter()
+                .any(|group| group.display_name == ADMIN_GROUP) =>
+        {
+            report.pass(name("admin"), format!("'{username}' is in '{ADMIN_GROUP}'"))
+        }
+        Ok(Some(_)) => report.fail(
+            name("admin"),
+            format!("'{username}' is not in '{ADMIN_GROUP}'"),
+            "add the user to lldap_admin, it is needed to create users and groups",
+        ),
+        Ok(None) => report.fail(
+            name("admin"),
+            format!("user '{username}' does not exist"),
+            "the user the controller logs in with should be able to read its own groups",
+        ),
+        Err(err) => report.fail(
+            name("admin"),
+            format!("failed to get user '{username}': {err}"),
+            "the user the controller logs in with should be able to read its own groups",
+        ),
+    }
+
+    match client.get_groups().await {
+        Ok(groups) => {
+            for group in [
+                &config.groups.password_manager,
+                &config.groups.strict_readonly,
+            ] {
+                if groups
+                    .iter()
+                    .any(|existing| existing.display_name == *group)
+                {
+                    report.pass(name("group"), format!("'{group}' exists"));
+                } else {
+                    report.fail(
+                        name("group"),
+                        format!("'{group}' does not exist"),
+                        "every service user is added to this group, create it in LLDAP or change 'groups' in the configuration",
+                    );
+                }
+            }
+        }
+        Err(err) => report.fail(
+            name("group"),
+            format!("failed to get groups: {err}"),
+            "the user the controller logs in with should be in lldap_admin",
+        ),
+    }
+}
+
+/// Check everything the controller needs to work, with a hint on how to fix every failure
+pub async fn run(config: &Config) -> Report {
+    let mut report = Report::default();
+
+    let client = match kube::Client::try_default().await {
+        Ok(client) => client,
+        Err(err) => {
+            report.fail(
+                "Kubernetes client",
+                err.to_string(),
+                "run inside the cluster or point KUBECONFIG at a valid kubeconfig",
+            );
+            return report;
+        }
+    };
+
+    match client.apiserver_version().await {
+        Ok(version) => report.pass("Kubernetes API", version.git_version),
+        Err(err) => {
+            report.fail(
+                "Kubernetes API",
+                err.to_string(),
+                "check that the api server is reachable and the credentials are valid",
+            );
+            return report;
+        }
+    }
+
+    check_rbac(&mut report, &client, config).await;
+
+    let instances = match LldapInstances::new(config) {
+        Ok(instances) => instances,
+        Err(err) => {
+            report.fail(
+                "LLDAP configuration",
+                format!("{err:#}"),
+                "fix the 'lldap' section of the configuration",
+            );
+            return report;
+        }
+    };
+
+    let mut checked = false;
+    if let Some(instance) = instances.default_instance() {
+        check_lldap(&mut report, config, &instance).await;
+        checked = true;
+    }
+
+    match Api::<LldapServer>::all(client.clone())
+        .list(&Default::default())
+        .await
+    {
+        Ok(servers) => {
+            for server in servers {
+                let name = server.name_any();
+                match instances.get(&client, Some(&name)).await {
+                    Ok(instance) => check_lldap(&mut report, config, &instance).await,
+                    Err(err) => report.fail(
+                        format!("LLDAP '{name}' configuration"),
+                        err.to_string(),
+                        "check the LldapServer and the secrets it references",
+                    ),
+                }
+                checked = true;
+            }
+        }
+        Err(err) => report.fail(
+            "LldapServers",
+            err.to_string(),
+            "make sure the CRDs generated by `crdgen` are installed",
+        ),
+    }
+
+    if !checked {
+        report.fail(
+            "LLDAP",
+            "no LLDAP server is configured",
+            "set 'lldap.url' in the configuration or create an LldapServer",
+        );
+    }
+
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn report_output() {
+        let mut report = Report::default();
+        report.pass("Kubernetes API", "v1.31.0");
+        report.fail(
+            "RBAC serviceusers.lldap.huizinga.dev",
+            "not allowed to patch",
+            "apply the roles generated by `crdgen rbac` with the same configuration",
+        );
+        report.skip("LLDAP 'default' login", "LLDAP is not reachable");
+
+        assert!(!report.passed());
+        insta::assert_snapshot!(report.to_string());
+    }
+}
@@ -0,0 +1,198 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use kube::{Resource, ResourceExt};
+use serde::Deserialize;
+use serde_json::Value;
+use tracing::debug;
+
+use crate::config::Config;
+use crate::resources::{Group, GroupBinding, ServiceUser, service_user};
+
+/// Custom resources read from YAML files instead of the cluster
+#[derive(Debug, Default)]
+pub struct Documents {
+    pub service_users: Vec<ServiceUser>,
+    pub groups: Vec<Group>,
+    pub group_bindings: Vec<GroupBinding>,
+}
+
+/// Every YAML file in the directory, or the path itself if it is a file
+fn yaml_files(path: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    if !path.is_dir() {
+        return Ok(vec![path.to_owned()]);
+    }
+
+    let mut files = Vec::new();
+    for entry in std::fs::read_dir(path)
+        .with_context(|| format!("Failed to read directory '{}'", path.display()))?
+    {
+        let path = entry?.path();
+        let is_yaml = path
+            .extension()
+            .is_some_and(|extension| extension == "yaml" || extension == "yml");
+
+        if is_yaml && path.is_file() {
+            files.push(path);
+        }
+    }
+    files.sort();
+
+    Ok(files)
+}
+
+impl Documents {
+    /// Read all documents in the files and directories, objects without a namespace are placed in
+    /// `default_namespace`
+    pub fn read(paths: &[PathBuf], default_namespace: &str) -> anyhow::Result<Self> {
+        let mut documents = Self::default();
+
+        for path in paths {
+            for file in yaml_files(path)? {
+                let contents = std::fs::read_to_string(&file)
+                    .with_context(|| format!("Failed to read file '{}'", file.display()))?;
+
+                documents
+                    .parse(&contents, default_namespace)
+                    .with_context(|| format!("Failed to parse file '{}'", file.display()))?;
+            }
+        }
+
+        Ok(documents)
+    }
+
+    /// Add the resources in a (multi document) YAML string, other kinds are ignored
+    pub fn parse(&mut self, contents: &str, default_namespace: &str) -> anyhow::Result<()> {
+        for document in serde_yaml::Deserializer::from_str(contents) {
+            let object = Value::deserialize(document)?;
+            if object.is_null() {
+                continue;
+            }
+
+            let kind = object
+                .get("kind")
+                .and_then(Value::as_str)
+                .unwrap_or_default();
+            let api_version = object
+                .get("apiVersion")
+                .and_then(Value::as_str)
+                .unwrap_or_default();
+            if !api_version.starts_with(&format!("{}/", ServiceUser::group(&()))) {
+                debug!(api_version, kind, "Ignoring document");
+                continue;
+            }
+
+            match kind {
+                "ServiceUser" => {
+                    let object = service_user::convert(object, &ServiceUser::api_version(&()))
+                        .map_err(anyhow::Error::msg)?;
+                    let mut service_user: ServiceUser = serde_json::from_value(object)?;
+                    if service_user.metadata.namespace.is_none() {
+                        service_user.metadata.namespace = Some(default_namespace.into());
+                    }
+
+                    self.service_users.push(service_user);
+                }
+                "Group" => self.groups.push(serde_json::from_value(object)?),
+                "GroupBinding" => {
+                    let mut group_binding: GroupBinding = serde_json::from_value(object)?;
+                    if group_binding.metadata.namespace.is_none() {
+                        group_binding.metadata.namespace = Some(default_namespace.into());
+                    }
+
+                    self.group_bindings.push(group_binding);
+                }
+                _ => debug!(api_version, kind, "Ignoring document"),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Groups that the GroupBindings in the owner namespaces grant the namespace access to, like
+    /// in the cluster but namespace selectors only match when they have no requirements as the
+    /// labels of the namespace are not known
+    pub fn granted_groups(&self, config: &Config, namespace: &str) -> Option<BTreeSet<String>> {
+        let owner_namespaces = &config.policy.group_binding_namespaces;
+        if owner_namespaces.is_empty() {
+            return None;
+        }
+
+        let labels = BTreeMap::new();
+        let granted = self
+            .group_bindings
+            .iter()
+            .filter(|binding| {
+                binding
+                    .namespace()
+                    .is_some_and(|namespace| owner_namespaces.contains(&namespace))
+            })
+            .filter(|binding| binding.grants(namespace, &labels))
+            .map(|binding| binding.spec.group.clone())
+            .collect();
+
+        Some(granted)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_documents() {
+        let mut documents = Documents::default();
+        documents
+            .parse(
+                r#"
+apiVersion: lldap.huizinga.dev/v1
+kind: ServiceUser
+metadata:
+  name: grafana
+spec:
+  additionalGroups: [media]
+---
+apiVersion: lldap.huizinga.dev/v2
+kind: ServiceUser
+metadata:
+  name: jellyfin
+  namespace: media
+spec: {}
+---
+apiVersion: lldap.huizinga.dev/v1
+kind: Group
+metadata:
+  name: media
+spec: {}
+---
+apiVersion: v1
+kind: ConfigMap
+metadata:
+  name: unrelated
+"#,
+                "monitoring",
+            )
+            .unwrap();
+
+        let service_users: Vec<_> = documents
+            .service_users
+            .iter()
+            .map(|service_user| {
+                (
+                    service_user.name_any(),
+                    service_user.namespace().unwrap(),
+                    service_user.spec.groups.len(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            service_users,
+            [
+                ("grafana".into(), "monitoring".into(), 1),
+                ("jellyfin".into(), "media".into(), 0)
+            ]
+        );
+        assert_eq!(documents.groups.len(), 1);
+    }
+}
@@ -0,0 +1,299 @@
+use queries::{GroupDetails, UserDetails};
+use tracing::warn;
+
+use crate::config::Config;
+use crate::lldap::custom_attributes;
+use crate::resources::service_user::{GroupRef, ServiceUserSpec, split_username};
+use crate::resources::{Group, GroupSpec, ServiceUser};
+use crate::validation;
+
+/// Which users to export, users are exported when they match any of the includes and none of the
+/// excludes. Without includes only members of the password manager or strict readonly group are
+/// exported, every ServiceUser is a member of one of them, while people usually are not
+#[derive(Debug, Default)]
+pub struct Filter {
+    /// Only export members of these groups
+    pub include_groups: Vec<String>,
+    /// Skip members of these groups
+    pub exclude_groups: Vec<String>,
+    /// Only export users with an id matching one of these patterns
+    pub include_users: Vec<String>,
+    /// Skip users with an id matching one of these patterns
+    pub exclude_users: Vec<String>,
+}
+
+impl Filter {
+    fn matches(&self, user: &UserDetails, config: &Config) -> bool {
+        let member_of = |groups: &[String]| {
+            user.groups
+                .iter()
+                .any(|group| groups.contains(&group.display_name))
+        };
+        let id_matches = |patterns: &[String]| {
+            patterns
+                .iter()
+                .any(|pattern| matches_pattern(pattern, &user.id))
+        };
+
+        let included = if self.include_groups.is_empty() && self.include_users.is_empty() {
+            member_of(&[
+                config.groups.password_manager.clone(),
+                config.groups.strict_readonly.clone(),
+            ])
+        } else {
+            (self.include_groups.is_empty() || member_of(&self.include_groups))
+                && (self.include_users.is_empty() || id_matches(&self.include_users))
+        };
+
+        included && !member_of(&self.exclude_groups) && !id_matches(&self.exclude_users)
+    }
+}
+
+/// Match a value against a pattern where `*` matches any number of characters
+fn matches_pattern(pattern: &str, value: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = value.strip_prefix(first) else {
+        return false;
+    };
+
+    let mut parts: Vec<_> = parts.collect();
+    let Some(last) = parts.pop() else {
+        // No wildcard in the pattern
+        return rest.is_empty();
+    };
+
+    for part in parts {
+        match rest.find(part) {
+            Some(index) => rest = &rest[index + part.len()..],
+            None => return false,
+        }
+    }
+
+    rest.ends_with(last)
+}
+
+/// Custom resources describing the current state of LLDAP
+#[derive(Debug, Default)]
+pub struct Export {
+    pub groups: Vec<Group>,
+    pub service_users: Vec<ServiceUser>,
+}
+
+/// Convert the users and groups in LLDAP into custom resources.
+///
+/// Builtin groups and groups denied by the policy are left out, as are users that can not be
+/// represented as a ServiceUser.
+pub fn export(
+    config: &Config,
+    users: Vec<UserDetails>,
+    groups: Vec<GroupDetails>,
+    filter: &Filter,
+    server_ref: Option<&str>,
+) -> Export {
+    let builtin = |name: &str| {
+        name == config.groups.password_manager || name == config.groups.strict_readonly
+    };
+    let managed =
+        |name: &str| !builtin(name) && validation::group_allowed(name, None, config).is_ok();
+
+    let mut export = Export::default();
+
+    for group in groups {
+        if !managed(&group.display_name) {
+            continue;
+        }
+
+        let object = Group::new(
+            &group.display_name,
+            GroupSpec {
+                attributes: custom_attributes(&group.attributes),
+                server_ref: server_ref.map(Into::into),
+                resync_interval_secs: None,
+            },
+        );
+
+        match object.validate(config) {
+            Ok(()) => export.groups.push(object),
+            Err(err) => warn!(group = group.display_name, "Skipping group: {err}"),
+        }
+    }
+
+    for user in users {
+        if !filter.matches(&user, config) {
+            continue;
+        }
+
+        let Some((name, namespace)) = split_username(&user.id) else {
+            warn!(
+                username = user.id,
+                "Skipping user: username is not in the '<name>.<namespace>' format of ServiceUsers"
+            );
+            continue;
+        };
+
+        let password_manager = user
+            .groups
+            .iter()
+            .any(|group| group.display_name == config.groups.password_manager);
+
+        let mut groups = Vec::new();
+        for group in &user.groups {
+            if builtin(&group.display_name) {
+                continue;
+            }
+
+            if !managed(&group.display_name) {
+                warn!(
+                    username = user.id,
+                    group = group.display_name,
+                    "Leaving out membership of a group the controller can not manage"
+                );
+                continue;
+            }
+
+            groups.push(GroupRef {
+                name: group.display_name.clone(),
+            });
+        }
+
+        let mut object = ServiceUser::new(
+            name,
+            ServiceUserSpec {
+                password_manager,
+                groups,
+                secret_template: Default::default(),
+                attributes: custom_attributes(&user.attributes),
+                server_ref: server_ref.map(Into::into),
+                resync_interval_secs: None,
+            },
+        );
+        object.metadata.namespace = Some(namespace.into());
+
+        match object.validate(config) {
+            Ok(()) => export.service_users.push(object),
+            Err(err) => warn!(username = user.id, "Skipping user: {err}"),
+        }
+    }
+
+    export
+}
+
+#[cfg(test)]
+mod tests {
+    use kube::ResourceExt;
+    use queries::{AttributeSchema, AttributeValue};
+
+    use super::*;
+
+    fn attribute(name: &str, value: &str, is_hardcoded: bool) -> AttributeValue {
+        AttributeValue {
+            name: name.into(),
+            value: vec![value.into()],
+            schema: AttributeSchema { is_hardcoded },
+        }
+    }
+
+    fn group(id: i32, name: &str) -> queries::Group {
+        queries::Group {
+            id,
+            display_name: name.into(),
+        }
+    }
+
+    #[test]
+    fn patterns() {
+        assert!(matches_pattern("grafana.monitoring", "grafana.monitoring"));
+        assert!(matches_pattern("*.monitoring", "grafana.monitoring"));
+        assert!(matches_pattern("grafana.*", "grafana.monitoring"));
+        assert!(matches_pattern("*a*a*", "grafana.monitoring"));
+        assert!(!matches_pattern("grafana", "grafana.monitoring"));
+        assert!(!matches_pattern("*.media", "grafana.monitoring"));
+    }
+
+    #[test]
+    fn export_output() {
+        let users = vec![
+            UserDetails {
+                id: "grafana.monitoring".into(),
+                groups: vec![
+                    group(1, "lldap_admin"),
+                    group(2, "lldap_password_manager"),
+                    group(4, "media"),
+                ],
+                attributes: vec![
+                    attribute("mail", "grafana@example.com", true),
+                    attribute("department", "monitoring", false),
+                ],
+            },
+            UserDetails {
+                id: "jellyfin.media".into(),
+                groups: vec![group(3, "lldap_strict_readonly"), group(4, "media")],
+                attributes: Vec::new(),
+            },
+            UserDetails {
+                id: "admin".into(),
+                groups: vec![group(1, "lldap_admin")],
+                attributes: Vec::new(),
+            },
+        ];
+        let groups = vec![
+            GroupDetails {
+                id: 1,
+                display_name: "lldap_admin".into(),
+                attributes: Vec::new(),
+            },
+            GroupDetails {
+                id: 2,
+                display_name: "lldap_password_manager".into(),
+                attributes: Vec::new(),
+            },
+            GroupDetails {
+                id: 4,
+                display_name: "media".into(),
+                attributes: vec![attribute("gid", "2000", false)],
+            },
+        ];
+        let filter = Filter {
+            exclude_users: vec!["jellyfin.*".into()],
+            ..Default::default()
+        };
+
+        let export = export(&Config::default(), users, groups, &filter, Some("main"));
+
+        insta::assert_yaml_snapshot!((export.groups, export.service_users));
+    }
+
+    #[test]
+    fn only_service_users_by_default() {
+        let users = || {
+            vec![
+                UserDetails {
+                    id: "grafana.monitoring".into(),
+                    groups: vec![group(3, "lldap_strict_readonly")],
+                    attributes: Vec::new(),
+                },
+                UserDetails {
+                    id: "john.doe".into(),
+                    groups: vec![group(4, "media")],
+                    attributes: Vec::new(),
+                },
+            ]
+        };
+        let exported = |filter: &Filter| {
+            export(&Config::default(), users(), Vec::new(), filter, None)
+                .service_users
+                .iter()
+                .map(|service_user| service_user.name_any())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(exported(&Filter::default()), ["grafana"]);
+
+        let filter = Filter {
+            include_users: vec!["*.doe".into()],
+            ..Default::default()
+        };
+        assert_eq!(exported(&filter), ["john"]);
+    }
+}
@@ -0,0 +1,319 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use k8s_openapi::api::core::v1::Secret;
+use kube::{Api, ResourceExt};
+use tracing::debug;
+
+use crate::breaker::CircuitBreaker;
+use crate::config::{Config, LldapSettings};
+use crate::lldap::{ConnectionSettings, LldapConfig};
+use crate::resources::LldapServer;
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("No serverRef set and no default LLDAP server configured")]
+    NoDefaultServer,
+    #[error("LldapServer '{0}' does not exist")]
+    ServerNotFound(String),
+    #[error("Kube api error: {0}")]
+    Kube(#[from] kube::Error),
+    #[error("Secret '{namespace}/{name}' does not contain key '{key}'")]
+    MissingSecretKey {
+        namespace: String,
+        name: String,
+        key: String,
+    },
+    #[error("Invalid LldapServer '{0}': {1:#}")]
+    InvalidServer(String, anyhow::Error),
+}
+
+impl Error {
+    /// The LLDAP instance is gone for good, as opposed to temporarily not resolvable. A missing
+    /// secret is not, the server still exists and the secret can come back.
+    pub fn is_gone(&self) -> bool {
+        matches!(self, Self::NoDefaultServer | Self::ServerNotFound(_))
+    }
+}
+
+type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// An LLDAP instance together with the state we keep about it
+pub struct LldapInstance {
+    /// Name of the LldapServer, or `None` for the default server from the configuration
+    pub name: Option<String>,
+    pub config: LldapConfig,
+    pub breaker: CircuitBreaker,
+}
+
+impl LldapInstance {
+    pub fn display_name(&self) -> &str {
+        self.name.as_deref().unwrap_or("default")
+    }
+}
+
+struct CachedInstance {
+    /// Resource versions of the LldapServer and the secrets it references
+    version: String,
+    instance: Arc<LldapInstance>,
+}
+
+/// Resolves `serverRef`s to LLDAP instances, caching them until the LldapServer or any of its
+/// secrets change
+pub struct LldapInstances {
+    default: Option<Arc<LldapInstance>>,
+    servers: Mutex<HashMap<String, CachedInstance>>,
+    defaults: LldapSettings,
+    breaker_threshold: u32,
+    probe_interval: Duration,
+}
+
+impl LldapInstances {
+    pub fn new(config: &Config) -> anyhow::Result<Self> {
+        let breaker_threshold = config.circuit_breaker.threshold;
+        let probe_interval = Duration::from_secs(config.circuit_breaker.probe_interval_secs);
+
+        let default = match config.lldap.url {
+            Some(_) => Some(Arc::new(LldapInstance {
+                name: None,
+                config: LldapConfig::new(&config.lldap)?,
+                breaker: CircuitBreaker::new(breaker_threshold, probe_interval),
+            })),
+            None => None,
+        };
+
+        Ok(Self {
+            default,
+            servers: Default::default(),
+            defaults: config.lldap.clone(),
+            breaker_threshold,
+            probe_interval,
+        })
+    }
+
+    pub fn default_instance(&self) -> Option<Arc<LldapInstance>> {
+        self.default.clone()
+    }
+
+    /// All instances that are currently known
+    pub fn all(&self) -> Vec<Arc<LldapInstance>> {
+        let servers = self.servers.lock().expect("Lock should not be poisoned");
+
+        self.default
+            .iter()
+            .cloned()
+            .chain(servers.values().map(|cached| cached.instance.clone()))
+            .collect()
+    }
+
+    /// Forget the instance of an LldapServer that no longer exists
+    fn evict(&self, name: &str) {
+        let evicted = self
+            .servers
+            .lock()
+            .expect("Lock should not be poisoned")
+            .remove(name);
+
+        if evicted.is_some() {
+            debug!(name, "Removed LLDAP instance of deleted LldapServer");
+        }
+    }
+
+    /// Forget the instances of all LldapServers that no longer exist, so they are not polled or
+    /// probed anymore
+    pub async fn prune(&self, client: &kube::Client) -> Result<()> {
+        let existing: HashSet<_> = Api::<LldapServer>::all(client.clone())
+            .list_metadata(&Default::default())
+            .await?
+            .into_iter()
+            .map(|server| server.name_any())
+            .collect();
+
+        let known: Vec<_> = self
+            .servers
+            .lock()
+            .expect("Lock should not be poisoned")
+            .keys()
+            .filter(|name| !existing.contains(*name))
+            .cloned()
+            .collect();
+        for name in known {
+            self.evict(&name);
+        }
+
+        Ok(())
+    }
+
+    pub async fn get(
+        &self,
+        client: &kube::Client,
+        server_ref: Option<&str>,
+    ) -> Result<Arc<LldapInstance>> {
+        let Some(name) = server_ref else {
+            return self.default.clone().ok_or(Error::NoDefaultServer);
+        };
+
+        let Some(server) = Api::<LldapServer>::all(client.clone())
+            .get_opt(name)
+            .await?
+        else {
+            self.evict(name);
+
+            return Err(Error::ServerNotFound(name.to_owned()));
+        };
+        let spec = &server.spec;
+
+        let mut version = server.resource_version().unwrap_or_default();
+
+        let credentials = &spec.credentials;
+        let credentials_secret = get_secret(client, &credentials.name).await?;
+        version += &format!(
+            "/{}",
+            credentials_secret.resource_version().unwrap_or_default()
+        );
+
+        let ca_bundle = match &spec.tls.ca_secret_ref {
+            Some(secret_ref) => {
+                let secret = get_secret(client, &secret_ref.name).await?;
+                version += &format!("/{}", secret.resource_version().unwrap_or_default());
+
+                Some(secret_value(&secret, &secret_ref.key)?)
+            }
+            None => None,
+        };
+
+        let identity = match &spec.tls.client_cert_secret_ref {
+            Some(secret_ref) => {
+                let secret = get_secret(client, &secret_ref.name).await?;
+                version += &format!("/{}", secret.resource_version().unwrap_or_default());
+
+                let mut pem = secret_value(&secret, "tls.crt")?;
+                pem.push(b'\n');
+                pem.extend(secret_value(&secret, "tls.key")?);
+
+                Some(pem)
+            }
+            None => None,
+        };
+
+        if let Some(cached) = self
+            .servers
+            .lock()
+            .expect("Lock should not be poisoned")
+            .get(name)
+            .filter(|cached| cached.version == version)
+        {
+            return Ok(cached.instance.clone());
+        }
+
+        debug!(name, "Creating LLDAP instance from LldapServer");
+
+        let invalid = |err| Error::InvalidServer(name.to_owned(), err);
+        let username = String::from_utf8(secret_value(
+            &credentials_secret,
+            &credentials.username_key,
+        )?)
+        .map_err(|err| invalid(err.into()))?;
+        let password = String::from_utf8(secret_value(
+            &credentials_secret,
+            &credentials.password_key,
+        )?)
+        .map_err(|err| invalid(err.into()))?;
+
+        let config = LldapConfig::from_connection(ConnectionSettings {
+            url: spec.url.clone(),
+            username,
+            password,
+            ca_bundle: ca_bundle.as_deref(),
+            identity: identity.as_deref(),
+            insecure_skip_verify: spec.tls.insecure_skip_verify,
+            timeout: Duration::from_secs(spec.timeout_secs.unwrap_or(self.defaults.timeout_secs)),
+            connect_timeout: spec
+                .connect_timeout_secs
+                .or(self.defaults.connect_timeout_secs)
+                .map(Duration::from_secs),
+            proxy: self.defaults.proxy.as_deref(),
+        })
+        .map_err(invalid)?;
+
+        let instance = Arc::new(LldapInstance {
+            name: Some(name.to_owned()),
+            config,
+            breaker: CircuitBreaker::new(self.breaker_threshold, self.probe_interval),
+        });
+
+        self.servers
+            .lock()
+            .expect("Lock should not be poisoned")
+            .insert(
+                name.to_owned(),
+                CachedInstance {
+                    version,
+                    instance: instance.clone(),
+                },
+            );
+
+        Ok(instance)
+    }
+}
+
+/// Secrets of LldapServers are read from the namespace of the controller
+async fn get_secret(client: &kube::Client, name: &str) -> Result<Secret> {
+    Ok(Api::<Secret>::default_namespaced(client.clone())
+        .get(name)
+        .await?)
+}
+
+fn secret_value(secret: &Secret, key: &str) -> Result<Vec<u8>> {
+    secret
+        .data
+        .as_ref()
+        .and_then(|data| data.get(key))
+        .map(|value| value.0.clone())
+        .ok_or_else(|| Error::MissingSecretKey {
+            namespace: secret.namespace().unwrap_or_default(),
+            name: secret.name_any(),
+            key: key.to_owned(),
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use kube::api::DeleteParams;
+
+    use super::*;
+    use crate::testing::kube::TestEnv;
+
+    #[tokio::test]
+    async fn evict_deleted_servers() {
+        let env = TestEnv::start(Config::default()).await;
+        let servers = env.create_server("secondary").await;
+        let instances = &env.ctx.instances;
+
+        let instance = instances
+            .get(&env.ctx.client, Some("secondary"))
+            .await
+            .unwrap();
+        let again = instances
+            .get(&env.ctx.client, Some("secondary"))
+            .await
+            .unwrap();
+        assert!(Arc::ptr_eq(&instance, &again));
+        assert_eq!(instances.all().len(), 2);
+
+        servers
+            .delete("secondary", &DeleteParams::default())
+            .await
+            .unwrap();
+        instances.prune(&env.ctx.client).await.unwrap();
+        assert_eq!(instances.all().len(), 1);
+
+        let err = instances
+            .get(&env.ctx.client, Some("secondary"))
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(err, Error::ServerNotFound(name) if name == "secondary"));
+    }
+}
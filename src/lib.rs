@@ -1,3 +1,20 @@
+pub mod backend;
+pub mod backoff;
+pub mod breaker;
+pub mod config;
 pub mod context;
+pub mod doctor;
+pub mod documents;
+pub mod export;
+pub mod instances;
 pub mod lldap;
+pub mod manifests;
+pub mod plan;
+pub mod poller;
+pub mod rbac;
 pub mod resources;
+pub mod standalone;
+#[cfg(test)]
+mod testing;
+pub mod validation;
+pub mod webhook;
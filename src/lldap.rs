@@ -1,20 +1,33 @@
-use std::time::Duration;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock, RwLockReadGuard, Weak};
+use std::time::{Duration, Instant};
 
 use anyhow::Context;
 use cynic::http::{CynicReqwestError, ReqwestExt};
-use cynic::{GraphQlError, GraphQlResponse, MutationBuilder, QueryBuilder};
+use cynic::{GraphQlError, GraphQlResponse, MutationBuilder, Operation, QueryBuilder};
+use futures::future::BoxFuture;
 use lldap_auth::login::{ClientSimpleLoginRequest, ServerLoginResponse};
 use lldap_auth::opaque::AuthenticationError;
 use lldap_auth::registration::ServerRegistrationStartResponse;
 use lldap_auth::{opaque, registration};
 use queries::{
-    AddUserToGroup, AddUserToGroupVariables, CreateGroup, CreateGroupVariables, CreateUser,
-    CreateUserVariables, DeleteGroup, DeleteGroupVariables, DeleteUser, DeleteUserVariables,
-    GetGroups, GetUser, GetUserVariables, Group, RemoveUserFromGroup, RemoveUserFromGroupVariables,
-    User,
+    AddUserToGroup, AddUserToGroupVariables, AttributeValue, AttributeValueInput, CreateGroup,
+    CreateGroupVariables, CreateUser, CreateUserVariables, DeleteGroup, DeleteGroupVariables,
+    DeleteUser, DeleteUserVariables, GetApiVersion, GetGroups, GetUser, GetUserVariables, Group,
+    GroupDetails, ListGroups, ListUsers, RemoveUserFromGroup, RemoveUserFromGroupVariables,
+    UpdateGroupAttributes, UpdateGroupAttributesVariables, UpdateUserAttributes,
+    UpdateUserAttributesVariables, User, UserDetails,
 };
 use reqwest::header::{AUTHORIZATION, HeaderMap, HeaderValue};
-use tracing::{debug, trace};
+use reqwest::{Certificate, ClientBuilder, Identity, Proxy, StatusCode};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use tokio::sync::Mutex as AsyncMutex;
+use tracing::{debug, error, info, warn};
+
+use crate::backend::DirectoryBackend;
+use crate::config::LldapSettings;
 
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
@@ -26,10 +39,36 @@ pub enum Error {
     Authentication(#[from] AuthenticationError),
     #[error("GraphQL error: {0}")]
     GraphQl(#[from] GraphQlError),
+    #[error("Entity not found: {0}")]
+    NotFound(String),
+    #[error("Login rejected: {0}")]
+    LoginRejected(reqwest::StatusCode),
+}
+
+impl Error {
+    /// Errors caused by LLDAP being unreachable or failing, as opposed to LLDAP rejecting the
+    /// request. Rejected requests do not get better by backing off, so they should not trip the
+    /// breaker
+    pub fn is_unavailable(&self) -> bool {
+        match self {
+            Self::Cynic(CynicReqwestError::ReqwestError(err)) | Self::Reqwest(err) => {
+                err.is_connect()
+                    || err.is_timeout()
+                    || err.status().is_some_and(|status| status.is_server_error())
+            }
+            Self::Cynic(CynicReqwestError::ErrorResponse(status, _)) => status.is_server_error(),
+            _ => false,
+        }
+    }
 }
 
 pub type Result<T, E = Error> = std::result::Result<T, E>;
 
+const CREDENTIALS_POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Tokens issued by LLDAP are valid for a day by default, log in again well before that
+const CLIENT_MAX_AGE: Duration = Duration::from_secs(60 * 60);
+
 fn check_graphql_errors<T>(response: GraphQlResponse<T>) -> Result<T> {
     if let Some(errors) = &response.errors {
         if !errors.is_empty() {
@@ -42,41 +81,349 @@ fn check_graphql_errors<T>(response: GraphQlResponse<T>) -> Result<T> {
         .expect("Data should be valid if there are no error"))
 }
 
-#[derive(Clone)]
-pub struct LldapConfig {
+/// The attributes created by the admin, the builtin attributes are part of the user or group
+/// itself
+pub fn custom_attributes(attributes: &[AttributeValue]) -> BTreeMap<String, Vec<String>> {
+    attributes
+        .iter()
+        .filter(|attribute| !attribute.schema.is_hardcoded)
+        .map(|attribute| (attribute.name.clone(), attribute.value.clone()))
+        .collect()
+}
+
+fn attribute_inputs(attributes: &BTreeMap<String, Vec<String>>) -> Vec<AttributeValueInput> {
+    attributes
+        .iter()
+        .map(|(name, value)| AttributeValueInput {
+            name: name.clone(),
+            value: value.clone(),
+        })
+        .collect()
+}
+
+#[derive(Clone, PartialEq, Eq)]
+struct Credentials {
     username: String,
     password: String,
+}
+
+/// Client that is logged in with the credentials, reused until they change or the token gets old
+struct CachedClient {
+    credentials: Credentials,
+    client: LldapClient,
+}
+
+#[derive(Clone)]
+pub struct LldapConfig {
+    credentials: Arc<RwLock<Credentials>>,
+    client: Arc<AsyncMutex<Option<CachedClient>>>,
+    username_file: Option<PathBuf>,
+    password_file: Option<PathBuf>,
     url: String,
+    timeout: Duration,
+    connect_timeout: Option<Duration>,
+    ca_certificates: Vec<Certificate>,
+    identity: Option<Identity>,
+    insecure_skip_verify: bool,
+    proxy: Option<Proxy>,
+}
+
+fn read_file(key: &str, path: &Path) -> anyhow::Result<Vec<u8>> {
+    std::fs::read(path)
+        .with_context(|| format!("Failed to read file '{}' from '{key}'", path.display()))
+}
+
+fn read_credential(path: &Path) -> anyhow::Result<String> {
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read file '{}'", path.display()))?;
+
+    // Files created by hand usually end with a newline that is not part of the secret
+    Ok(contents.trim_end_matches(['\n', '\r']).to_owned())
+}
+
+/// Read a credential either directly from the config or from the file it points to
+fn credential(key: &str, value: &Option<String>, file: &Option<PathBuf>) -> anyhow::Result<String> {
+    match (value, file) {
+        (Some(value), _) => Ok(value.clone()),
+        (None, Some(path)) => {
+            read_credential(path).with_context(|| format!("Invalid value for '{key}File'"))
+        }
+        (None, None) => anyhow::bail!("Invalid value for '{key}': needs to be set"),
+    }
+}
+
+/// Everything needed to connect to an LLDAP instance, independent of where it is configured
+pub struct ConnectionSettings<'a> {
+    pub url: String,
+    pub username: String,
+    pub password: String,
+    /// PEM bundle with additional certificates to trust
+    pub ca_bundle: Option<&'a [u8]>,
+    /// PEM certificate followed by the PEM key used for mutual TLS
+    pub identity: Option<&'a [u8]>,
+    pub insecure_skip_verify: bool,
+    pub timeout: Duration,
+    pub connect_timeout: Option<Duration>,
+    pub proxy: Option<&'a str>,
 }
 
 impl LldapConfig {
-    pub fn try_from_env() -> anyhow::Result<Self> {
+    pub fn new(settings: &LldapSettings) -> anyhow::Result<Self> {
+        let url = settings
+            .url
+            .clone()
+            .context("Invalid value for 'lldap.url': needs to be set")?;
+
+        let ca_bundle = settings
+            .ca_file
+            .as_deref()
+            .map(|path| read_file("lldap.caFile", path))
+            .transpose()?;
+
+        let identity = match (&settings.client_cert_file, &settings.client_key_file) {
+            (Some(cert), Some(key)) => {
+                let mut pem = read_file("lldap.clientCertFile", cert)?;
+                pem.push(b'\n');
+                pem.extend(read_file("lldap.clientKeyFile", key)?);
+
+                Some(pem)
+            }
+            (None, None) => None,
+            _ => anyhow::bail!(
+                "Invalid value for 'lldap.clientCertFile': 'lldap.clientCertFile' and 'lldap.clientKeyFile' need to be set together"
+            ),
+        };
+
+        let username = credential(
+            "lldap.username",
+            &settings.username,
+            &settings.username_file,
+        )?;
+        let password = credential(
+            "lldap.password",
+            &settings.password,
+            &settings.password_file,
+        )?;
+
+        let config = Self::from_connection(ConnectionSettings {
+            url,
+            username,
+            password,
+            ca_bundle: ca_bundle.as_deref(),
+            identity: identity.as_deref(),
+            insecure_skip_verify: settings.insecure_skip_verify,
+            timeout: Duration::from_secs(settings.timeout_secs),
+            connect_timeout: settings.connect_timeout_secs.map(Duration::from_secs),
+            proxy: settings.proxy.as_deref(),
+        })
+        .context("Invalid value for 'lldap'")?;
+
         Ok(Self {
-            username: std::env::var("LLDAP_USERNAME")
-                .context("Variable 'LLDAP_USERNAME' is not set or invalid")?,
-            password: std::env::var("LLDAP_PASSWORD")
-                .context("Variable 'LLDAP_PASSWORD' is not set or invalid")?,
-            url: std::env::var("LLDAP_URL")
-                .context("Variable 'LLDAP_URL' is not set or invalid")?,
+            username_file: settings.username_file.clone(),
+            password_file: settings.password_file.clone(),
+            ..config
         })
     }
 
+    pub fn from_connection(settings: ConnectionSettings) -> anyhow::Result<Self> {
+        reqwest::Url::parse(&settings.url).context("Url is not valid")?;
+
+        let ca_certificates = match settings.ca_bundle {
+            Some(bundle) => {
+                let certificates = Certificate::from_pem_bundle(bundle)
+                    .context("CA bundle does not contain valid PEM certificates")?;
+                if certificates.is_empty() {
+                    anyhow::bail!("CA bundle does not contain any certificates");
+                }
+
+                certificates
+            }
+            None => Vec::new(),
+        };
+
+        let identity = settings
+            .identity
+            .map(Identity::from_pem)
+            .transpose()
+            .context("Client certificate and key are not valid")?;
+
+        if settings.insecure_skip_verify {
+            warn!(
+                url = settings.url,
+                "TLS certificate verification for LLDAP is disabled"
+            );
+        }
+
+        let proxy = settings
+            .proxy
+            .map(Proxy::all)
+            .transpose()
+            .context("Proxy is not valid")?;
+
+        let config = Self {
+            credentials: Arc::new(RwLock::new(Credentials {
+                username: settings.username,
+                password: settings.password,
+            })),
+            client: Default::default(),
+            username_file: None,
+            password_file: None,
+            url: settings.url,
+            timeout: settings.timeout,
+            connect_timeout: settings.connect_timeout,
+            ca_certificates,
+            identity,
+            insecure_skip_verify: settings.insecure_skip_verify,
+            proxy,
+        };
+
+        // Make sure the TLS settings are usable before we start reconciling
+        config
+            .client_builder()
+            .build()
+            .context("Failed to create LLDAP client")?;
+
+        Ok(config)
+    }
+
+    fn client_builder(&self) -> ClientBuilder {
+        let mut builder = ClientBuilder::new()
+            .timeout(self.timeout)
+            .danger_accept_invalid_certs(self.insecure_skip_verify);
+
+        if let Some(connect_timeout) = self.connect_timeout {
+            builder = builder.connect_timeout(connect_timeout);
+        }
+
+        for certificate in &self.ca_certificates {
+            builder = builder.add_root_certificate(certificate.clone());
+        }
+
+        if let Some(identity) = &self.identity {
+            builder = builder.identity(identity.clone());
+        }
+
+        if let Some(proxy) = &self.proxy {
+            builder = builder.proxy(proxy.clone());
+        }
+
+        builder
+    }
+
+    /// Re-read the credentials that come from files, returns true if they changed
+    fn reload_credentials(&self) -> anyhow::Result<bool> {
+        let mut credentials = self.credentials().clone();
+
+        if let Some(path) = &self.username_file {
+            credentials.username = read_credential(path)?;
+        }
+        if let Some(path) = &self.password_file {
+            credentials.password = read_credential(path)?;
+        }
+
+        let mut current = self
+            .credentials
+            .write()
+            .expect("Lock should not be poisoned");
+        if *current == credentials {
+            return Ok(false);
+        }
+        *current = credentials;
+
+        Ok(true)
+    }
+
+    /// Keep the credentials in sync with the files they are read from, so rotating the admin
+    /// password does not require a restart
+    pub async fn watch_credentials(self) {
+        if self.username_file.is_none() && self.password_file.is_none() {
+            return;
+        }
+
+        let mut interval = tokio::time::interval(CREDENTIALS_POLL_INTERVAL);
+        loop {
+            interval.tick().await;
+
+            match self.reload_credentials() {
+                Ok(true) => {
+                    info!("LLDAP credentials changed, logging in again");
+
+                    match self.client().await {
+                        Ok(_) => info!("Logged in to LLDAP with the new credentials"),
+                        Err(err) => {
+                            error!("Failed to log in with the new LLDAP credentials: {err}")
+                        }
+                    }
+                }
+                Ok(false) => {}
+                Err(err) => warn!("Failed to reload LLDAP credentials: {err:#}"),
+            }
+        }
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// Name of the user the controller logs in with
+    pub fn username(&self) -> String {
+        self.credentials().username.clone()
+    }
+
+    fn credentials(&self) -> RwLockReadGuard<'_, Credentials> {
+        self.credentials
+            .read()
+            .expect("Lock should not be poisoned")
+    }
+
+    /// Client that is logged in to LLDAP, reusing the previous login while the credentials stay
+    /// the same and LLDAP accepts its token
+    pub async fn client(&self) -> Result<LldapClient> {
+        let mut cached = self.client.lock().await;
+        let credentials = self.credentials().clone();
+
+        if let Some(cached) = cached.as_ref().filter(|cached| {
+            cached.credentials == credentials
+                && cached.client.logged_in_at.elapsed() < CLIENT_MAX_AGE
+        }) {
+            return Ok(cached.client.clone());
+        }
+
+        let client = LldapClient {
+            cache: Some(Arc::downgrade(&self.client)),
+            ..self.build_client().await?
+        };
+        *cached = Some(CachedClient {
+            credentials,
+            client: client.clone(),
+        });
+
+        Ok(client)
+    }
+
+    /// Log in to LLDAP, every call logs in again
     pub async fn build_client(&self) -> Result<LldapClient> {
         debug!("Creating LLDAP client");
-        let timeout = Duration::from_secs(1);
 
-        let client = reqwest::ClientBuilder::new().timeout(timeout).build()?;
+        let client = self.client_builder().build()?;
+        let credentials = self.credentials().clone();
 
-        let response: ServerLoginResponse = client
+        let response = client
             .post(format!("{}/auth/simple/login", self.url))
             .json(&ClientSimpleLoginRequest {
-                username: self.username.clone().into(),
-                password: self.password.clone(),
+                username: credentials.username.into(),
+                password: credentials.password,
             })
             .send()
-            .await?
-            .json()
             .await?;
+        // LLDAP answers wrong credentials with a plain text body
+        if matches!(
+            response.status(),
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN
+        ) {
+            return Err(Error::LoginRejected(response.status()));
+        }
+        let response: ServerLoginResponse = response.error_for_status()?.json().await?;
 
         let mut auth: HeaderValue = format!("Bearer {}", response.token)
             .try_into()
@@ -85,204 +432,572 @@ impl LldapConfig {
         let mut headers = HeaderMap::new();
         headers.insert(AUTHORIZATION, auth);
 
-        let client = reqwest::ClientBuilder::new()
-            .timeout(timeout)
-            .default_headers(headers)
-            .build()?;
+        let client = self.client_builder().default_headers(headers).build()?;
 
         Ok(LldapClient {
             client,
             url: self.url.clone(),
+            logged_in_at: Instant::now(),
+            cache: None,
         })
     }
 }
 
+#[derive(Clone)]
 pub struct LldapClient {
     client: reqwest::Client,
     url: String,
+    logged_in_at: Instant,
+    /// Cache the client was stored in by [`LldapConfig::client`]
+    cache: Option<Weak<AsyncMutex<Option<CachedClient>>>>,
 }
 
 impl LldapClient {
-    pub async fn get_user(&self, username: &str) -> Result<User> {
-        let operation = GetUser::build(GetUserVariables { username });
+    /// Run the operation, forgetting the cached client when LLDAP no longer accepts its token so
+    /// the next reconcile logs in again instead of failing until the client is an hour old
+    async fn graphql<Q, V>(&self, operation: Operation<Q, V>) -> Result<Q>
+    where
+        Q: DeserializeOwned + Send + 'static,
+        V: Serialize + Send,
+    {
         let response = self
             .client
             .post(format!("{}/api/graphql", self.url))
             .run_graphql(operation)
-            .await?;
+            .await;
 
-        Ok(check_graphql_errors(response)?.user)
+        if let Err(CynicReqwestError::ErrorResponse(StatusCode::UNAUTHORIZED, _)) = &response {
+            warn!("LLDAP rejected the token, logging in again");
+            self.forget().await;
+        }
+
+        check_graphql_errors(response?)
     }
 
-    pub async fn create_user(&self, username: &str) -> Result<User> {
-        let operation = CreateUser::build(CreateUserVariables { username });
+    async fn forget(&self) {
+        let Some(cache) = self.cache.as_ref().and_then(Weak::upgrade) else {
+            return;
+        };
 
-        let response = self
-            .client
-            .post(format!("{}/api/graphql", self.url))
-            .run_graphql(operation)
-            .await?;
+        let mut cached = cache.lock().await;
+        if cached
+            .as_ref()
+            .is_some_and(|cached| cached.client.logged_in_at == self.logged_in_at)
+        {
+            *cached = None;
+        }
+    }
 
-        Ok(check_graphql_errors(response)?.create_user)
+    pub async fn get_api_version(&self) -> Result<String> {
+        let operation = GetApiVersion::build(());
+        Ok(self.graphql(operation).await?.api_version)
     }
+}
 
-    pub async fn delete_user(&self, username: &str) -> Result<()> {
-        let operation = DeleteUser::build(DeleteUserVariables { username });
+impl DirectoryBackend for LldapClient {
+    fn get_user<'a>(&'a self, username: &'a str) -> BoxFuture<'a, Result<Option<User>>> {
+        Box::pin(async move {
+            let operation = GetUser::build(GetUserVariables { username });
+            match self.graphql(operation).await {
+                Ok(data) => Ok(Some(data.user)),
+                Err(Error::GraphQl(err))
+                    if err.message == format!("Entity not found: `{username}`") =>
+                {
+                    Ok(None)
+                }
+                Err(err) => Err(err),
+            }
+        })
+    }
 
-        let response = self
-            .client
-            .post(format!("{}/api/graphql", self.url))
-            .run_graphql(operation)
-            .await?;
+    fn create_user<'a>(&'a self, username: &'a str) -> BoxFuture<'a, Result<User>> {
+        Box::pin(async move {
+            let operation = CreateUser::build(CreateUserVariables { username });
+            Ok(self.graphql(operation).await?.create_user)
+        })
+    }
 
-        check_graphql_errors(response)?;
+    fn delete_user<'a>(&'a self, username: &'a str) -> BoxFuture<'a, Result<bool>> {
+        Box::pin(async move {
+            let operation = DeleteUser::build(DeleteUserVariables { username });
+            match self.graphql(operation).await {
+                Ok(_) => Ok(true),
+                Err(Error::GraphQl(err))
+                    if err.message == format!("Entity not found: `No such user: '{username}'`") =>
+                {
+                    Ok(false)
+                }
+                Err(err) => Err(err),
+            }
+        })
+    }
 
-        Ok(())
+    fn list_users(&self) -> BoxFuture<'_, Result<Vec<UserDetails>>> {
+        Box::pin(async move {
+            let operation = ListUsers::build(());
+            Ok(self.graphql(operation).await?.users)
+        })
     }
 
-    pub async fn get_groups(&self) -> Result<Vec<Group>> {
-        let operation = GetGroups::build(());
+    fn get_groups(&self) -> BoxFuture<'_, Result<Vec<Group>>> {
+        Box::pin(async move {
+            let operation = GetGroups::build(());
+            Ok(self.graphql(operation).await?.groups)
+        })
+    }
 
-        let response = self
-            .client
-            .post(format!("{}/api/graphql", self.url))
-            .run_graphql(operation)
-            .await?;
+    fn list_groups(&self) -> BoxFuture<'_, Result<Vec<GroupDetails>>> {
+        Box::pin(async move {
+            let operation = ListGroups::build(());
+            Ok(self.graphql(operation).await?.groups)
+        })
+    }
 
-        Ok(check_graphql_errors(response)?.groups)
+    fn create_group<'a>(&'a self, name: &'a str) -> BoxFuture<'a, Result<Group>> {
+        Box::pin(async move {
+            let operation = CreateGroup::build(CreateGroupVariables { name });
+            Ok(self.graphql(operation).await?.create_group)
+        })
     }
 
-    pub async fn create_group(&self, name: &str) -> Result<Group> {
-        let operation = CreateGroup::build(CreateGroupVariables { name });
+    fn delete_group(&self, id: i32) -> BoxFuture<'_, Result<()>> {
+        Box::pin(async move {
+            let operation = DeleteGroup::build(DeleteGroupVariables { id });
+            self.graphql(operation).await?;
 
-        let response = self
-            .client
-            .post(format!("{}/api/graphql", self.url))
-            .run_graphql(operation)
-            .await?;
+            Ok(())
+        })
+    }
+
+    fn add_user_to_group<'a>(&'a self, username: &'a str, group: i32) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            let operation = AddUserToGroup::build(AddUserToGroupVariables { username, group });
+            self.graphql(operation).await?;
 
-        Ok(check_graphql_errors(response)?.create_group)
+            Ok(())
+        })
     }
 
-    pub async fn delete_group(&self, id: i32) -> Result<()> {
-        let operation = DeleteGroup::build(DeleteGroupVariables { id });
+    fn remove_user_from_group<'a>(
+        &'a self,
+        username: &'a str,
+        group: i32,
+    ) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            let operation =
+                RemoveUserFromGroup::build(RemoveUserFromGroupVariables { username, group });
+            self.graphql(operation).await?;
+
+            Ok(())
+        })
+    }
 
-        let response = self
-            .client
-            .post(format!("{}/api/graphql", self.url))
-            .run_graphql(operation)
-            .await?;
+    fn update_user_attributes<'a>(
+        &'a self,
+        username: &'a str,
+        attributes: &'a BTreeMap<String, Vec<String>>,
+    ) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            let operation = UpdateUserAttributes::build(UpdateUserAttributesVariables {
+                username,
+                attributes: attribute_inputs(attributes),
+            });
+            self.graphql(operation).await?;
+
+            Ok(())
+        })
+    }
 
-        check_graphql_errors(response)?;
+    fn update_group_attributes<'a>(
+        &'a self,
+        id: i32,
+        attributes: &'a BTreeMap<String, Vec<String>>,
+    ) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            let operation = UpdateGroupAttributes::build(UpdateGroupAttributesVariables {
+                id,
+                attributes: attribute_inputs(attributes),
+            });
+            self.graphql(operation).await?;
+
+            Ok(())
+        })
+    }
 
-        Ok(())
+    fn update_password<'a>(
+        &'a self,
+        username: &'a str,
+        password: &'a str,
+    ) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            let mut rng = rand::rngs::OsRng;
+            let registration_start_request =
+                opaque::client::registration::start_registration(password.as_bytes(), &mut rng)?;
+
+            let start_request = registration::ClientRegistrationStartRequest {
+                username: username.into(),
+                registration_start_request: registration_start_request.message,
+            };
+
+            let response: ServerRegistrationStartResponse = self
+                .client
+                .post(format!("{}/auth/opaque/register/start", self.url))
+                .json(&start_request)
+                .send()
+                .await?
+                .json()
+                .await?;
+
+            let registration_finish = opaque::client::registration::finish_registration(
+                registration_start_request.state,
+                response.registration_response,
+                &mut rng,
+            )?;
+
+            let request = registration::ClientRegistrationFinishRequest {
+                server_data: response.server_data,
+                registration_upload: registration_finish.message,
+            };
+
+            let _response = self
+                .client
+                .post(format!("{}/auth/opaque/register/finish", self.url))
+                .json(&request)
+                .send()
+                .await?;
+
+            debug!("Changed '{username}' password successfully");
+
+            Ok(())
+        })
     }
+}
 
-    pub async fn add_user_to_group(&self, username: &str, group: i32) -> Result<()> {
-        let operation = AddUserToGroup::build(AddUserToGroupVariables { username, group });
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::MemoryUser;
+    use crate::config::LldapSettings;
+    use crate::testing::lldap::{ADMIN_PASSWORD, ADMIN_USERNAME, MockLldap};
+    use crate::testing::{CERTIFICATE, PRIVATE_KEY};
+
+    #[test]
+    fn reload_credentials_from_files() {
+        let dir = std::env::temp_dir().join(format!("lldap-controller-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let password_file = dir.join("password");
+        std::fs::write(&password_file, "first\n").unwrap();
+
+        let config = LldapConfig {
+            credentials: Arc::new(RwLock::new(Credentials {
+                username: "admin".into(),
+                password: read_credential(&password_file).unwrap(),
+            })),
+            client: Default::default(),
+            username_file: None,
+            password_file: Some(password_file.clone()),
+            url: "http://lldap:17170".into(),
+            timeout: Duration::from_secs(1),
+            connect_timeout: None,
+            ca_certificates: Vec::new(),
+            identity: None,
+            insecure_skip_verify: false,
+            proxy: None,
+        };
+        assert_eq!(config.credentials().password, "first");
+        assert!(!config.reload_credentials().unwrap());
 
-        let response = self
-            .client
-            .post(format!("{}/api/graphql", self.url))
-            .run_graphql(operation)
-            .await?;
+        std::fs::write(&password_file, "second").unwrap();
+        assert!(config.reload_credentials().unwrap());
+        assert_eq!(config.credentials().username, "admin");
+        assert_eq!(config.credentials().password, "second");
 
-        check_graphql_errors(response)?;
+        std::fs::remove_file(&password_file).unwrap();
+        assert!(config.reload_credentials().is_err());
+        assert_eq!(config.credentials().password, "second");
 
-        Ok(())
+        std::fs::remove_dir_all(&dir).unwrap();
     }
 
-    pub async fn remove_user_from_group(&self, username: &str, group: i32) -> Result<()> {
-        let operation =
-            RemoveUserFromGroup::build(RemoveUserFromGroupVariables { username, group });
+    #[tokio::test]
+    async fn update_user_groups() {
+        let lldap = MockLldap::start().await;
+        {
+            let mut state = lldap.state();
+            let old = state.add_group("old");
+            state.add_group("media");
+            state.users.insert(
+                "jellyfin.media".into(),
+                MemoryUser {
+                    groups: [old].into(),
+                    ..Default::default()
+                },
+            );
+        }
 
-        let response = self
-            .client
-            .post(format!("{}/api/graphql", self.url))
-            .run_graphql(operation)
-            .await?;
+        let client = lldap.config().build_client().await.unwrap();
+        let user = client.get_user("jellyfin.media").await.unwrap().unwrap();
+        client
+            .update_user_groups(
+                &user,
+                &[
+                    "media".into(),
+                    "lldap_strict_readonly".into(),
+                    "missing".into(),
+                ],
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(
+            lldap.state().user_groups("jellyfin.media"),
+            ["lldap_strict_readonly".into(), "media".into()].into()
+        );
+    }
+
+    #[tokio::test]
+    async fn reuse_client_until_credentials_change() {
+        let lldap = MockLldap::start().await;
+        let config = lldap.config();
+
+        config.client().await.unwrap();
+        config.clone().client().await.unwrap();
+        assert_eq!(lldap.state().logins, 1);
 
-        check_graphql_errors(response)?;
+        // Changed credentials log in again, the failed login does not replace the working client
+        let credentials = config.credentials().clone();
+        config.credentials.write().unwrap().password = "wrong".into();
+        assert!(config.client().await.is_err());
 
-        Ok(())
+        *config.credentials.write().unwrap() = credentials;
+        config.client().await.unwrap();
+        assert_eq!(lldap.state().logins, 1);
     }
 
-    pub async fn update_user_groups(&self, user: &User, needed_groups: &[String]) -> Result<()> {
-        let all_groups = self.get_groups().await?;
+    #[tokio::test]
+    async fn log_in_again_when_token_is_rejected() {
+        let lldap = MockLldap::start().await;
+        let config = lldap.config();
 
-        // TODO: Error when invalid name
-        let needed_groups: Vec<_> = needed_groups
-            .iter()
-            .filter_map(|needed_group| {
-                all_groups
-                    .iter()
-                    .find(|group| &group.display_name == needed_group)
-                    .map(|group| group.id)
-            })
-            .collect();
+        let client = config.client().await.unwrap();
+        lldap.state().tokens.clear();
+        assert!(client.get_groups().await.is_err());
 
-        let current_groups: Vec<_> = user.groups.iter().map(|group| group.id).collect();
+        config.client().await.unwrap().get_groups().await.unwrap();
+        assert_eq!(lldap.state().logins, 2);
+    }
 
-        let remove = current_groups
-            .iter()
-            .filter(|group| !needed_groups.contains(group));
-        for &group in remove {
-            trace!(username = user.id, group, "Removing user from group");
+    #[tokio::test]
+    async fn update_password() {
+        let lldap = MockLldap::start().await;
+        let client = lldap.config().build_client().await.unwrap();
 
-            self.remove_user_from_group(&user.id, group).await?;
-        }
+        client.create_user("grafana.monitoring").await.unwrap();
+        client
+            .update_password("grafana.monitoring", "secret")
+            .await
+            .unwrap();
 
-        let add = needed_groups
-            .iter()
-            .filter(|group| !current_groups.contains(group));
-        for &group in add {
-            trace!(username = user.id, group, "Adding user to group");
+        assert_eq!(lldap.state().password_changes["grafana.monitoring"], 1);
+    }
 
-            self.add_user_to_group(&user.id, group).await?;
-        }
+    #[tokio::test]
+    async fn user_not_found() {
+        let lldap = MockLldap::start().await;
+        let client = lldap.config().build_client().await.unwrap();
 
-        Ok(())
+        // Missing users are recognized by the exact messages of LLDAP
+        assert!(client.get_user("missing.default").await.unwrap().is_none());
+        assert!(!client.delete_user("missing.default").await.unwrap());
     }
 
-    pub async fn update_password(&self, username: &str, password: &str) -> Result<()> {
-        let mut rng = rand::rngs::OsRng;
-        let registration_start_request =
-            opaque::client::registration::start_registration(password.as_bytes(), &mut rng)?;
+    #[tokio::test]
+    async fn wrong_credentials() {
+        let lldap = MockLldap::start().await;
+        let config = LldapConfig::from_connection(ConnectionSettings {
+            password: "wrong".into(),
+            ..lldap.connection_settings()
+        })
+        .unwrap();
 
-        let start_request = registration::ClientRegistrationStartRequest {
-            username: username.into(),
-            registration_start_request: registration_start_request.message,
+        let Err(err) = config.build_client().await else {
+            panic!("Expected login to fail");
         };
+        assert!(matches!(
+            err,
+            Error::LoginRejected(StatusCode::UNAUTHORIZED)
+        ));
+        assert!(!err.is_unavailable());
+    }
 
-        let response: ServerRegistrationStartResponse = self
-            .client
-            .post(format!("{}/auth/opaque/register/start", self.url))
-            .json(&start_request)
-            .send()
-            .await?
-            .json()
-            .await?;
+    #[tokio::test]
+    async fn unavailable_errors() {
+        // Nothing listens on the port once the listener is dropped
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        drop(listener);
+        let config = LldapConfig::from_connection(ConnectionSettings {
+            url: format!("http://{address}"),
+            ..MockLldap::start().await.connection_settings()
+        })
+        .unwrap();
+        let Err(err) = config.build_client().await else {
+            panic!("Expected connection error");
+        };
+        assert!(err.is_unavailable());
+
+        let lldap = MockLldap::start().await;
+        let unauthenticated = LldapClient {
+            client: reqwest::Client::new(),
+            url: lldap.url().into(),
+            logged_in_at: Instant::now(),
+            cache: None,
+        };
+        let err = unauthenticated.get_api_version().await.unwrap_err();
+        assert!(!err.is_unavailable());
+
+        let err = Error::Cynic(CynicReqwestError::ErrorResponse(
+            StatusCode::BAD_GATEWAY,
+            "Bad Gateway".into(),
+        ));
+        assert!(err.is_unavailable());
+        assert!(!Error::NotFound("missing.default".into()).is_unavailable());
+    }
 
-        let registration_finish = opaque::client::registration::finish_registration(
-            registration_start_request.state,
-            response.registration_response,
-            &mut rng,
-        )?;
+    /// Write the files the settings refer to, returning the directory they are in
+    fn settings_files(test: &str, files: &[(&str, &str)]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("lldap-{test}-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        for (name, contents) in files {
+            std::fs::write(dir.join(name), contents).unwrap();
+        }
 
-        let request = registration::ClientRegistrationFinishRequest {
-            server_data: response.server_data,
-            registration_upload: registration_finish.message,
+        dir
+    }
+
+    #[test]
+    fn connection_settings_from_config() {
+        let dir = settings_files(
+            "settings",
+            &[
+                ("ca.pem", CERTIFICATE),
+                ("tls.crt", CERTIFICATE),
+                ("tls.key", PRIVATE_KEY),
+            ],
+        );
+        let settings = LldapSettings {
+            url: Some("https://lldap.test:17170".into()),
+            username: Some(ADMIN_USERNAME.into()),
+            password: Some(ADMIN_PASSWORD.into()),
+            timeout_secs: 5,
+            connect_timeout_secs: Some(2),
+            ca_file: Some(dir.join("ca.pem")),
+            client_cert_file: Some(dir.join("tls.crt")),
+            client_key_file: Some(dir.join("tls.key")),
+            insecure_skip_verify: true,
+            proxy: Some("http://proxy.test:3128".into()),
+            ..Default::default()
         };
 
-        let _response = self
-            .client
-            .post(format!("{}/auth/opaque/register/finish", self.url))
-            .json(&request)
-            .send()
-            .await?;
+        let config = LldapConfig::new(&settings).unwrap();
+        assert_eq!(config.url(), "https://lldap.test:17170");
+        assert_eq!(config.timeout, Duration::from_secs(5));
+        assert_eq!(config.connect_timeout, Some(Duration::from_secs(2)));
+        assert_eq!(config.ca_certificates.len(), 1);
+        assert!(config.identity.is_some());
+        assert!(config.insecure_skip_verify);
+        assert!(config.proxy.is_some());
+
+        let invalid = [
+            (
+                LldapSettings {
+                    url: Some("lldap.test".into()),
+                    ..settings.clone()
+                },
+                "Url is not valid",
+            ),
+            (
+                LldapSettings {
+                    ca_file: Some(dir.join("tls.key")),
+                    ..settings.clone()
+                },
+                "CA bundle does not contain any certificates",
+            ),
+            (
+                LldapSettings {
+                    ca_file: Some(dir.join("missing.pem")),
+                    ..settings.clone()
+                },
+                "Failed to read file",
+            ),
+            (
+                LldapSettings {
+                    client_key_file: None,
+                    ..settings.clone()
+                },
+                "need to be set together",
+            ),
+            (
+                LldapSettings {
+                    client_key_file: Some(dir.join("ca.pem")),
+                    ..settings.clone()
+                },
+                "Client certificate and key are not valid",
+            ),
+            (
+                LldapSettings {
+                    proxy: Some("http://[proxy".into()),
+                    ..settings.clone()
+                },
+                "Proxy is not valid",
+            ),
+        ];
+        for (settings, expected) in invalid {
+            let err = LldapConfig::new(&settings).err().expect(expected);
+            assert!(format!("{err:#}").contains(expected), "{err:#}");
+        }
 
-        debug!("Changed '{username}' password successfully");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 
-        Ok(())
+    #[tokio::test]
+    async fn build_client_with_settings() {
+        let lldap = MockLldap::start().await;
+
+        // The CA is only used for https, the mock is reached over plain http
+        let config = LldapConfig::from_connection(ConnectionSettings {
+            ca_bundle: Some(CERTIFICATE.as_bytes()),
+            timeout: Duration::from_millis(500),
+            connect_timeout: Some(Duration::from_millis(200)),
+            ..lldap.connection_settings()
+        })
+        .unwrap();
+        let client = config.build_client().await.unwrap();
+        client.get_api_version().await.unwrap();
+        assert_eq!(lldap.state().logins, 1);
+
+        let err = LldapConfig::from_connection(ConnectionSettings {
+            ca_bundle: Some(PRIVATE_KEY.as_bytes()),
+            ..lldap.connection_settings()
+        })
+        .err()
+        .unwrap();
+        assert_eq!(
+            format!("{err:#}"),
+            "CA bundle does not contain any certificates"
+        );
+
+        // The mock does not speak TLS, so the handshake fails like an unreachable server
+        let config = LldapConfig::from_connection(ConnectionSettings {
+            url: lldap.url().replace("http://", "https://"),
+            ca_bundle: Some(CERTIFICATE.as_bytes()),
+            ..lldap.connection_settings()
+        })
+        .unwrap();
+        let Err(err) = config.build_client().await else {
+            panic!("Expected TLS handshake to fail");
+        };
+        assert!(err.is_unavailable());
+        assert_eq!(lldap.state().logins, 1);
     }
 }
@@ -1,23 +1,52 @@
+use std::fmt;
+use std::path::PathBuf;
 use std::sync::Arc;
 use std::time::Duration;
 
-use futures::StreamExt;
-use k8s_openapi::api::core::v1::Secret;
+use clap::{Parser, Subcommand};
+use futures::future::{join_all, ready};
+use futures::{StreamExt, stream};
+use k8s_openapi::api::core::v1::{Namespace, Secret};
 use kube::runtime::controller::{self, Action};
-use kube::runtime::reflector::ObjectRef;
-use kube::runtime::{Controller, watcher};
+use kube::runtime::reflector::{self, ObjectRef};
+use kube::runtime::{Controller, WatchStreamExt, watcher};
 use kube::{Api, Client as KubeClient, Resource};
+use lldap_controller::backend::DirectoryBackend;
+use lldap_controller::config::Config;
 use lldap_controller::context::Context;
+use lldap_controller::documents::Documents;
+use lldap_controller::export::{self, Filter};
 use lldap_controller::lldap::LldapConfig;
-use lldap_controller::resources::{self, Error, Group, ServiceUser, reconcile};
+use lldap_controller::plan::{self, Current, Desired};
+use lldap_controller::poller::Poller;
+use lldap_controller::resources::{
+    self, Error, GrantStores, Group, GroupBinding, ServiceUser, reconcile,
+};
+use lldap_controller::standalone::{self, CredentialsOutput};
+use lldap_controller::{breaker, doctor, webhook};
+use serde::de::DeserializeOwned;
+use tokio::sync::watch;
 use tracing::{debug, info, warn};
 use tracing_subscriber::layer::SubscriberExt;
 use tracing_subscriber::util::SubscriberInitExt;
 use tracing_subscriber::{EnvFilter, Registry};
 
-fn error_policy<T>(_obj: Arc<T>, err: &resources::Error, _ctx: Arc<Context>) -> Action {
-    warn!("error: {}", err);
-    Action::requeue(Duration::from_secs(5))
+fn error_policy<T>(obj: Arc<T>, err: &resources::Error, ctx: Arc<Context>) -> Action
+where
+    T: Resource,
+    <T as Resource>::DynamicType: Default,
+{
+    if err.is_permanent() {
+        warn!("error: {}, waiting for change", err);
+        ctx.backoff.reset(obj.as_ref());
+
+        return Action::await_change();
+    }
+
+    let delay = ctx.backoff.next_delay(obj.as_ref());
+    warn!("error: {}, retrying in {}s", err, delay.as_secs());
+
+    Action::requeue(delay)
 }
 
 async fn log_status<T>(
@@ -31,13 +60,289 @@ async fn log_status<T>(
     }
 }
 
+/// Keeps the Namespaces and the GroupBindings in the owner namespaces in stores, the receiver
+/// is notified every time a GroupBinding changes
+fn watch_grants(
+    client: &KubeClient,
+    owner_namespaces: &[String],
+) -> (GrantStores, watch::Receiver<()>) {
+    let (tx, rx) = watch::channel(());
+    let tx = Arc::new(tx);
+
+    let (namespaces, writer) = reflector::store();
+    let api = Api::<Namespace>::all(client.clone());
+    tokio::spawn(async move {
+        let mut events = watcher(api, watcher::Config::default())
+            .default_backoff()
+            .reflect(writer)
+            .boxed();
+
+        while let Some(event) = events.next().await {
+            if let Err(err) = event {
+                warn!("Namespace watch failed: {err}");
+            }
+        }
+    });
+
+    let mut bindings = Vec::new();
+    for owner_namespace in owner_namespaces {
+        let (store, writer) = reflector::store();
+        bindings.push(store);
+
+        let api = Api::<GroupBinding>::namespaced(client.clone(), owner_namespace);
+        let tx = tx.clone();
+        tokio::spawn(async move {
+            let mut changes = watcher(api, watcher::Config::default())
+                .default_backoff()
+                .reflect(writer)
+                .touched_objects()
+                .boxed();
+
+            while let Some(change) = changes.next().await {
+                match change {
+                    // Changes that are not seen yet are merged into one reconcile
+                    Ok(_) => {
+                        tx.send_replace(());
+                    }
+                    Err(err) => warn!("GroupBinding watch failed: {err}"),
+                }
+            }
+        });
+    }
+
+    (
+        GrantStores {
+            namespaces,
+            bindings,
+        },
+        rx,
+    )
+}
+
+/// Forget the dry run changes reported for objects once they are deleted, without our finalizer
+/// they are gone before they can be reconciled again
+fn forget_deleted<K>(api: Api<K>, config: watcher::Config, ctx: Arc<Context>)
+where
+    K: Resource<DynamicType = ()> + Clone + DeserializeOwned + fmt::Debug + Send + 'static,
+{
+    tokio::spawn(async move {
+        let mut events = watcher(api, config).default_backoff().boxed();
+
+        while let Some(event) = events.next().await {
+            match event {
+                Ok(watcher::Event::Delete(obj)) => {
+                    ctx.dry_run_reporter.forget(&obj.object_ref(&()));
+                }
+                Ok(_) => {}
+                Err(err) => warn!("Watch for deleted objects failed: {err}"),
+            }
+        }
+    });
+}
+
+#[derive(Parser)]
+#[command(version, about)]
+struct Args {
+    /// Path to the YAML configuration file
+    #[arg(long, env = "LLDAP_CONTROLLER_CONFIG")]
+    config: Option<PathBuf>,
+    /// Print the default configuration and exit
+    #[arg(long)]
+    print_default_config: bool,
+    /// Runs the controller when no command is given
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Write the groups and users in LLDAP as Group and ServiceUser manifests.
+    ///
+    /// Only users named `<name>.<namespace>` can be represented as a ServiceUser. Without
+    /// `--include-group` or `--include-user` only members of the password manager or strict
+    /// readonly group are exported. Applying the exported ServiceUsers gives the users a newly
+    /// generated password.
+    Export(ExportArgs),
+    /// Show what reconciling the resources would change in LLDAP, without changing anything.
+    ///
+    /// Only resources without a serverRef are planned, against the LLDAP server from the
+    /// configuration.
+    Plan(PlanArgs),
+    /// Reconcile the resources in YAML files into LLDAP without Kubernetes.
+    ///
+    /// Only resources without a serverRef are applied, to the LLDAP server from the
+    /// configuration. Resources that are removed from the files are left alone in LLDAP.
+    Sync(SyncArgs),
+    /// Check access to Kubernetes and LLDAP and print a report with hints for every failure
+    Doctor,
+}
+
+#[derive(clap::Args)]
+struct SyncArgs {
+    /// YAML files or directories containing the resources
+    #[arg(required = true)]
+    paths: Vec<PathBuf>,
+    /// Namespace of ServiceUsers in the files that do not set one
+    #[arg(long, default_value = "default")]
+    namespace: String,
+    /// Directory to keep the credentials in, without it the credentials of new users are printed
+    /// and existing users keep their password
+    #[arg(long)]
+    credentials_dir: Option<PathBuf>,
+    /// Keep running and sync again after this many seconds
+    #[arg(long)]
+    interval_secs: Option<u64>,
+}
+
+#[derive(clap::Args)]
+struct PlanArgs {
+    /// YAML files or directories containing the resources
+    #[arg(required_unless_present = "cluster")]
+    paths: Vec<PathBuf>,
+    /// Read the resources the controller watches from the cluster instead
+    #[arg(long, conflicts_with = "paths")]
+    cluster: bool,
+    /// Namespace of ServiceUsers in the files that do not set one
+    #[arg(long, default_value = "default")]
+    namespace: String,
+}
+
+#[derive(clap::Args)]
+struct ExportArgs {
+    /// Only export members of this group, can be repeated
+    #[arg(long)]
+    include_group: Vec<String>,
+    /// Skip members of this group, can be repeated
+    #[arg(long)]
+    exclude_group: Vec<String>,
+    /// Only export users with an id matching this pattern, `*` matches anything, can be repeated
+    #[arg(long)]
+    include_user: Vec<String>,
+    /// Skip users with an id matching this pattern, `*` matches anything, can be repeated
+    #[arg(long)]
+    exclude_user: Vec<String>,
+    /// LldapServer to set as serverRef on the exported resources
+    #[arg(long)]
+    server_ref: Option<String>,
+    /// File to write the manifests to instead of stdout
+    #[arg(long, short)]
+    output: Option<PathBuf>,
+}
+
+async fn export(config: &Config, args: ExportArgs) -> anyhow::Result<()> {
+    let client = LldapConfig::new(&config.lldap)?.build_client().await?;
+
+    let users = client.list_users().await?;
+    let groups = client.list_groups().await?;
+    info!(
+        users = users.len(),
+        groups = groups.len(),
+        "Fetched LLDAP state"
+    );
+
+    let filter = Filter {
+        include_groups: args.include_group,
+        exclude_groups: args.exclude_group,
+        include_users: args.include_user,
+        exclude_users: args.exclude_user,
+    };
+    let export = export::export(config, users, groups, &filter, args.server_ref.as_deref());
+
+    let mut documents = Vec::new();
+    for group in &export.groups {
+        documents.push(serde_yaml::to_string(group)?);
+    }
+    for service_user in &export.service_users {
+        documents.push(serde_yaml::to_string(service_user)?);
+    }
+    let manifests = documents.join("---\n");
+
+    match args.output {
+        Some(path) => std::fs::write(&path, manifests)?,
+        None => print!("{manifests}"),
+    }
+
+    info!(
+        groups = export.groups.len(),
+        service_users = export.service_users.len(),
+        "Exported resources"
+    );
+
+    Ok(())
+}
+
+async fn plan(config: &Config, args: PlanArgs) -> anyhow::Result<()> {
+    let desired = if args.cluster {
+        let client = KubeClient::try_default().await?;
+        Desired::from_cluster(&client, config).await?
+    } else {
+        Desired::from_documents(config, Documents::read(&args.paths, &args.namespace)?)
+    };
+
+    let client = LldapConfig::new(&config.lldap)?.build_client().await?;
+    let current = Current::fetch(&client).await?;
+
+    let plan = plan::plan(config, &desired, &current).await?;
+
+    for warning in &plan.warnings {
+        println!("! {warning}");
+    }
+    if plan.changes.is_empty() {
+        println!("No changes, LLDAP matches the resources");
+    }
+    for change in &plan.changes {
+        println!("{change}");
+    }
+
+    Ok(())
+}
+
+async fn sync(config: &Config, args: SyncArgs) -> anyhow::Result<()> {
+    let credentials = match args.credentials_dir {
+        Some(directory) => CredentialsOutput::Directory(directory),
+        None => CredentialsOutput::Stdout,
+    };
+
+    let Some(interval_secs) = args.interval_secs else {
+        return standalone::sync(config, &args.paths, &args.namespace, &credentials).await;
+    };
+
+    let mut interval = tokio::time::interval(Duration::from_secs(interval_secs));
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            () = webhook::shutdown_signal() => return Ok(()),
+        }
+
+        // Keep going, the next sync might succeed
+        if let Err(err) = standalone::sync(config, &args.paths, &args.namespace, &credentials).await
+        {
+            warn!("Sync failed: {err:#}");
+        }
+    }
+}
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
+    let args = Args::parse();
+
+    if args.print_default_config {
+        print!("{}", serde_yaml::to_string(&Config::default())?);
+
+        return Ok(());
+    }
+
     let env_filter = EnvFilter::try_from_default_env()
         .or_else(|_| EnvFilter::try_new("info"))
         .expect("Fallback should be valid");
 
-    if std::env::var("CARGO").is_ok() {
+    if args.command.is_some() {
+        // Commands write their output to stdout
+        let logger = tracing_subscriber::fmt::layer()
+            .compact()
+            .with_writer(std::io::stderr);
+        Registry::default().with(logger).with(env_filter).init();
+    } else if std::env::var("CARGO").is_ok() {
         let logger = tracing_subscriber::fmt::layer().compact();
         Registry::default().with(logger).with(env_filter).init();
     } else {
@@ -45,33 +350,159 @@ async fn main() -> anyhow::Result<()> {
         Registry::default().with(logger).with(env_filter).init();
     }
 
+    let config = Config::load(args.config.as_deref())?;
+
+    match args.command {
+        Some(Command::Export(args)) => return export(&config, args).await,
+        Some(Command::Plan(args)) => return plan(&config, args).await,
+        Some(Command::Sync(args)) => return sync(&config, args).await,
+        Some(Command::Doctor) => {
+            let report = doctor::run(&config).await;
+            print!("{report}");
+
+            if !report.passed() {
+                anyhow::bail!("Some checks failed");
+            }
+            return Ok(());
+        }
+        None => {}
+    }
+
     info!("Starting controller");
 
     let client = KubeClient::try_default().await?;
 
-    let data = Context::new(
-        "lldap.huizinga.dev",
-        client.clone(),
-        LldapConfig::try_from_env()?,
-    );
+    let mut ctx = Context::new(client.clone(), config)?;
+    let mut grant_changes = None;
+    if !ctx.config.policy.group_binding_namespaces.is_empty() {
+        let (grants, changes) = watch_grants(&client, &ctx.config.policy.group_binding_namespaces);
+        ctx = ctx.with_grants(grants);
+        grant_changes = Some(changes);
+    }
+    let ctx = Arc::new(ctx);
+    if ctx.config.dry_run {
+        warn!("Dry run mode, changes are only logged and published as events");
+    }
+
+    tokio::spawn(breaker::probe(ctx.clone()));
+    if let Some(instance) = ctx.instances.default_instance() {
+        tokio::spawn(instance.config.clone().watch_credentials());
+    }
+
+    let webhook = async {
+        if ctx.config.webhook.enabled {
+            webhook::serve(ctx.config.clone()).await
+        } else {
+            Ok(())
+        }
+    };
+
+    let watch = &ctx.config.watch;
+    let mut resource_config = watcher::Config::default();
+    if let Some(selector) = &watch.label_selector {
+        resource_config = resource_config.labels(selector);
+    }
+    // Only watch the secrets we created ourselves
+    let secret_config = watcher::Config::default().labels(&ctx.managed_by_selector());
+
+    let namespaces: Vec<_> = if watch.namespaces.is_empty() {
+        vec![None]
+    } else {
+        watch.namespaces.iter().map(Some).collect()
+    };
+
+    let mut poller = Poller::default();
+
+    let service_user_controllers = join_all(namespaces.into_iter().map(|namespace| {
+        let (service_users, secrets) = match namespace {
+            Some(namespace) => {
+                info!(namespace, "Watching namespace");
+                (
+                    Api::<ServiceUser>::namespaced(client.clone(), namespace),
+                    Api::<Secret>::namespaced(client.clone(), namespace),
+                )
+            }
+            None => (
+                Api::<ServiceUser>::all(client.clone()),
+                Api::<Secret>::all(client.clone()),
+            ),
+        };
+
+        if ctx.config.dry_run {
+            forget_deleted(service_users.clone(), resource_config.clone(), ctx.clone());
+        }
+
+        let mut controller = Controller::new(service_users, resource_config.clone())
+            .owns(secrets, secret_config.clone());
+
+        // Every controller gets all changes, but only knows the objects in its own namespace. Only
+        // objects of the server that changed are reconciled, others can have the same name
+        let store = controller.store();
+        let lldap_changes = poller.service_users().filter_map(move |polled| {
+            let uses_server = store
+                .get(&polled.obj_ref)
+                .is_some_and(|obj| obj.spec.server_ref == polled.server);
+            ready(uses_server.then_some(polled.obj_ref))
+        });
+        controller = controller.reconcile_on(lldap_changes);
 
-    let service_users = Api::<ServiceUser>::all(client.clone());
-    let secrets = Api::<Secret>::all(client.clone());
+        // Changed grants can affect every service user
+        if let Some(grant_changes) = &grant_changes {
+            let changes = stream::unfold(grant_changes.clone(), |mut changes| async move {
+                changes.changed().await.ok()?;
+                Some(((), changes))
+            });
+            controller = controller.reconcile_all_on(changes);
+        }
 
-    let service_user_controller = Controller::new(service_users, Default::default())
-        .owns(secrets, Default::default())
-        .shutdown_on_signal()
-        .run(reconcile, error_policy, Arc::new(data.clone()))
-        .for_each(log_status);
+        controller
+            .shutdown_on_signal()
+            .run(reconcile, error_policy, ctx.clone())
+            .for_each(log_status)
+    }));
 
-    let groups = Api::<Group>::all(client.clone());
+    let group_changes = poller.groups();
+    let group_controller = async {
+        if !watch.groups {
+            info!("Not watching groups");
+            return;
+        }
+
+        let groups = Api::<Group>::all(client.clone());
+        if ctx.config.dry_run {
+            forget_deleted(groups.clone(), resource_config.clone(), ctx.clone());
+        }
+
+        let controller = Controller::new(groups, resource_config.clone());
+        let store = controller.store();
+        let lldap_changes = group_changes.filter_map(move |polled| {
+            let uses_server = store
+                .get(&polled.obj_ref)
+                .is_some_and(|obj| obj.spec.server_ref == polled.server);
+            ready(uses_server.then_some(polled.obj_ref))
+        });
+
+        controller
+            .reconcile_on(lldap_changes)
+            .shutdown_on_signal()
+            .run(reconcile, error_policy, ctx.clone())
+            .for_each(log_status)
+            .await
+    };
+
+    match ctx.config.watch.lldap_poll_interval_secs {
+        Some(secs) => {
+            tokio::spawn(poller.run(ctx.clone(), Duration::from_secs(secs)));
+        }
+        None => info!("Not polling LLDAP for changes"),
+    }
 
-    let group_controller = Controller::new(groups, Default::default())
-        .shutdown_on_signal()
-        .run(reconcile, error_policy, Arc::new(data))
-        .for_each(log_status);
+    let controllers = async {
+        tokio::join!(service_user_controllers, group_controller);
+        Ok(())
+    };
 
-    tokio::join!(service_user_controller, group_controller);
+    tokio::try_join!(controllers, webhook)?;
 
     Ok(())
 }
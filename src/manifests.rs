@@ -0,0 +1,312 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use k8s_openapi::api::admissionregistration::v1::{
+    RuleWithOperations, ServiceReference, ValidatingWebhook, ValidatingWebhookConfiguration,
+    WebhookClientConfig,
+};
+use k8s_openapi::api::apps::v1::{Deployment, DeploymentSpec};
+use k8s_openapi::api::core::v1::{
+    ConfigMapVolumeSource, Container, ContainerPort, EnvVar, PodSpec, PodTemplateSpec,
+    ResourceRequirements, SecretVolumeSource, SecurityContext, Service, ServiceAccount,
+    ServicePort, ServiceSpec, Volume, VolumeMount,
+};
+use k8s_openapi::apiextensions_apiserver::pkg::apis::apiextensions::v1::CustomResourceDefinition;
+use k8s_openapi::apimachinery::pkg::api::resource::Quantity;
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::LabelSelector;
+use k8s_openapi::apimachinery::pkg::util::intstr::IntOrString;
+use kube::api::ObjectMeta;
+use kube::{CustomResourceExt, Resource};
+use serde_json::{Value, json};
+
+use crate::config::Config;
+use crate::resources::{Group, GroupBinding, LldapServer, ServiceUser, service_user};
+
+const CONFIG_DIR: &str = "/etc/lldap-controller/config";
+
+/// Where and how the controller is deployed
+pub struct ManifestOptions {
+    /// Name of the deployment, service account and roles
+    pub name: String,
+    /// Namespace the controller is deployed in
+    pub namespace: String,
+    pub image: String,
+}
+
+impl ManifestOptions {
+    fn webhook_service(&self) -> String {
+        format!("{}-webhook", self.name)
+    }
+
+    fn webhook_secret(&self) -> String {
+        format!("{}-webhook-tls", self.name)
+    }
+
+    fn metadata(&self, name: String) -> ObjectMeta {
+        ObjectMeta {
+            name: Some(name),
+            namespace: Some(self.namespace.clone()),
+            labels: Some(self.labels()),
+            ..Default::default()
+        }
+    }
+
+    fn labels(&self) -> BTreeMap<String, String> {
+        BTreeMap::from([
+            ("app".into(), self.name.clone()),
+            ("app.kubernetes.io/name".into(), self.name.clone()),
+        ])
+    }
+}
+
+pub fn crds(
+    config: &Config,
+    options: &ManifestOptions,
+) -> anyhow::Result<Vec<CustomResourceDefinition>> {
+    Ok(vec![
+        service_user::crd(config, &options.namespace, &options.webhook_service())?,
+        Group::crd(),
+        GroupBinding::crd(),
+        LldapServer::crd(),
+    ])
+}
+
+pub fn service_account(options: &ManifestOptions) -> ServiceAccount {
+    ServiceAccount {
+        metadata: options.metadata(options.name.clone()),
+        automount_service_account_token: Some(true),
+        ..Default::default()
+    }
+}
+
+fn rule<K: Resource<DynamicType = ()>>(versions: &[&str]) -> RuleWithOperations {
+    RuleWithOperations {
+        api_groups: Some(vec![K::group(&()).into_owned()]),
+        api_versions: Some(versions.iter().map(|version| (*version).into()).collect()),
+        operations: Some(vec!["CREATE".into(), "UPDATE".into()]),
+        resources: Some(vec![K::plural(&()).into_owned()]),
+        scope: None,
+    }
+}
+
+/// Service for the admission and conversion webhooks, a cert-manager certificate for it and the
+/// validating webhook configuration
+pub fn webhooks(config: &Config, options: &ManifestOptions) -> Vec<Value> {
+    let service_name = options.webhook_service();
+    let issuer_name = format!("{}-selfsigned", options.name);
+
+    let service = Service {
+        metadata: options.metadata(service_name.clone()),
+        spec: Some(ServiceSpec {
+            selector: Some(BTreeMap::from([("app".into(), options.name.clone())])),
+            ports: Some(vec![ServicePort {
+                name: Some("https".into()),
+                port: 443,
+                target_port: Some(IntOrString::Int(config.webhook.listen.port().into())),
+                ..Default::default()
+            }]),
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+
+    let issuer = json!({
+        "apiVersion": "cert-manager.io/v1",
+        "kind": "Issuer",
+        "metadata": options.metadata(issuer_name.clone()),
+        "spec": { "selfSigned": {} },
+    });
+
+    let certificate = json!({
+        "apiVersion": "cert-manager.io/v1",
+        "kind": "Certificate",
+        "metadata": options.metadata(service_name.clone()),
+        "spec": {
+            "secretName": options.webhook_secret(),
+            "dnsNames": [
+                format!("{service_name}.{}.svc", options.namespace),
+                format!("{service_name}.{}.svc.cluster.local", options.namespace),
+            ],
+            "issuerRef": { "kind": "Issuer", "name": issuer_name },
+        },
+    });
+
+    let mut metadata = options.metadata(options.name.clone());
+    metadata.namespace = None;
+    metadata.annotations = Some(BTreeMap::from([(
+        "cert-manager.io/inject-ca-from".into(),
+        format!("{}/{service_name}", options.namespace),
+    )]));
+
+    let webhook_configuration = ValidatingWebhookConfiguration {
+        metadata,
+        webhooks: Some(vec![ValidatingWebhook {
+            name: format!("validate.{}", ServiceUser::group(&())),
+            admission_review_versions: vec!["v1".into()],
+            side_effects: "None".into(),
+            failure_policy: Some("Fail".into()),
+            client_config: WebhookClientConfig {
+                service: Some(ServiceReference {
+                    name: service_name,
+                    namespace: options.namespace.clone(),
+                    path: Some("/validate".into()),
+                    port: None,
+                }),
+                ..Default::default()
+            },
+            rules: Some(vec![
+                rule::<ServiceUser>(&["v1", "v2"]),
+                rule::<Group>(&["v1"]),
+            ]),
+            ..Default::default()
+        }]),
+    };
+
+    vec![
+        serde_json::to_value(service).expect("Service should serialize"),
+        issuer,
+        certificate,
+        serde_json::to_value(webhook_configuration)
+            .expect("Webhook configuration should serialize"),
+    ]
+}
+
+/// Sample deployment, reads the configuration from the `<name>-config` ConfigMap
+pub fn deployment(config: &Config, options: &ManifestOptions) -> Deployment {
+    let mut volumes = vec![Volume {
+        name: "config".into(),
+        config_map: Some(ConfigMapVolumeSource {
+            name: format!("{}-config", options.name),
+            ..Default::default()
+        }),
+        ..Default::default()
+    }];
+    let mut volume_mounts = vec![VolumeMount {
+        name: "config".into(),
+        mount_path: CONFIG_DIR.into(),
+        read_only: Some(true),
+        ..Default::default()
+    }];
+    let mut ports = Vec::new();
+
+    let webhook = &config.webhook;
+    if webhook.enabled {
+        volumes.push(Volume {
+            name: "webhook-tls".into(),
+            secret: Some(SecretVolumeSource {
+                secret_name: Some(options.webhook_secret()),
+                ..Default::default()
+            }),
+            ..Default::default()
+        });
+
+        let mount = |key: &str, path: &Path| VolumeMount {
+            name: "webhook-tls".into(),
+            mount_path: path.display().to_string(),
+            sub_path: Some(key.into()),
+            read_only: Some(true),
+            ..Default::default()
+        };
+        volume_mounts.push(mount("tls.crt", &webhook.cert_file));
+        volume_mounts.push(mount("tls.key", &webhook.key_file));
+
+        ports.push(ContainerPort {
+            name: Some("webhook".into()),
+            container_port: webhook.listen.port().into(),
+            ..Default::default()
+        });
+    }
+
+    let quantities = |cpu: &str, memory: &str| {
+        BTreeMap::from([
+            ("cpu".into(), Quantity(cpu.into())),
+            ("memory".into(), Quantity(memory.into())),
+        ])
+    };
+
+    let container = Container {
+        name: options.name.clone(),
+        image: Some(options.image.clone()),
+        image_pull_policy: Some("IfNotPresent".into()),
+        env: Some(vec![
+            EnvVar {
+                name: "RUST_LOG".into(),
+                value: Some("info,lldap_controller=debug".into()),
+                ..Default::default()
+            },
+            EnvVar {
+                name: "LLDAP_CONTROLLER_CONFIG".into(),
+                value: Some(format!("{CONFIG_DIR}/config.yaml")),
+                ..Default::default()
+            },
+        ]),
+        ports: Some(ports),
+        volume_mounts: Some(volume_mounts),
+        resources: Some(ResourceRequirements {
+            limits: Some(quantities("200m", "256Mi")),
+            requests: Some(quantities("50m", "100Mi")),
+            ..Default::default()
+        }),
+        security_context: Some(SecurityContext {
+            allow_privilege_escalation: Some(false),
+            read_only_root_filesystem: Some(true),
+            run_as_non_root: Some(true),
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+
+    Deployment {
+        metadata: options.metadata(options.name.clone()),
+        spec: Some(DeploymentSpec {
+            replicas: Some(1),
+            selector: LabelSelector {
+                match_labels: Some(BTreeMap::from([("app".into(), options.name.clone())])),
+                ..Default::default()
+            },
+            template: PodTemplateSpec {
+                metadata: Some(ObjectMeta {
+                    labels: Some(options.labels()),
+                    annotations: Some(BTreeMap::from([(
+                        "kubectl.kubernetes.io/default-container".into(),
+                        options.name.clone(),
+                    )])),
+                    ..Default::default()
+                }),
+                spec: Some(PodSpec {
+                    service_account_name: Some(options.name.clone()),
+                    containers: vec![container],
+                    volumes: Some(volumes),
+                    ..Default::default()
+                }),
+            },
+            ..Default::default()
+        }),
+        ..Default::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The kustomization mounts the sample configuration and patches the generated deployment, the
+    /// names have to line up with what crdgen generates
+    #[test]
+    fn sample_config_matches_kustomization() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("manifests/config.yaml");
+        let config = Config::load(Some(&path)).unwrap();
+        assert!(config.webhook.enabled);
+
+        let options = ManifestOptions {
+            name: "lldap-controller".into(),
+            namespace: "lldap".into(),
+            image: "lldap-controller".into(),
+        };
+        let deployment = deployment(&config, &options);
+        let pod = deployment.spec.unwrap().template.spec.unwrap();
+        assert_eq!(pod.containers[0].name, "lldap-controller");
+        let config_map = pod.volumes.unwrap()[0].config_map.clone().unwrap();
+        assert_eq!(config_map.name, "lldap-controller-config");
+    }
+}
@@ -0,0 +1,387 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::{Arc, Mutex};
+
+use futures::future::BoxFuture;
+use kube::api::ListParams;
+use kube::{Api, ResourceExt};
+use queries::{GroupDetails, UserDetails};
+
+use crate::backend::{Change, ChangeSink, DirectoryBackend, MemoryBackend, RecordingBackend};
+use crate::config::Config;
+use crate::documents::Documents;
+use crate::lldap::{Result, custom_attributes};
+use crate::resources::service_user::format_username;
+use crate::resources::{Group, ServiceUser, granted_groups};
+
+/// The changes together with everything that would stop the resources from being applied as
+/// written
+#[derive(Debug, Default)]
+pub struct Plan {
+    pub changes: Vec<Change>,
+    pub warnings: Vec<String>,
+}
+
+/// The resources LLDAP should match
+#[derive(Debug, Default)]
+pub struct Desired {
+    pub groups: Vec<Group>,
+    pub service_users: Vec<ServiceUser>,
+    /// Groups the GroupBindings grant each namespace access to, `None` when GroupBindings are not
+    /// used
+    pub granted: Option<BTreeMap<String, BTreeSet<String>>>,
+}
+
+impl Desired {
+    pub fn from_documents(config: &Config, documents: Documents) -> Self {
+        let granted = (!config.policy.group_binding_namespaces.is_empty()).then(|| {
+            documents
+                .service_users
+                .iter()
+                .filter_map(|service_user| {
+                    let namespace = service_user.namespace()?;
+                    let groups = documents.granted_groups(config, &namespace)?;
+
+                    Some((namespace, groups))
+                })
+                .collect()
+        });
+
+        Self {
+            groups: documents.groups,
+            service_users: documents.service_users,
+            granted,
+        }
+    }
+
+    /// Read the resources the controller would reconcile from the cluster
+    pub async fn from_cluster(client: &kube::Client, config: &Config) -> kube::Result<Self> {
+        let watch = &config.watch;
+        let mut params = ListParams::default();
+        if let Some(selector) = &watch.label_selector {
+            params = params.labels(selector);
+        }
+
+        let mut service_users = Vec::new();
+        if watch.namespaces.is_empty() {
+            service_users = Api::<ServiceUser>::all(client.clone())
+                .list(&params)
+                .await?
+                .items;
+        } else {
+            for namespace in &watch.namespaces {
+                service_users.extend(
+                    Api::<ServiceUser>::namespaced(client.clone(), namespace)
+                        .list(&params)
+                        .await?,
+                );
+            }
+        }
+
+        let groups = if watch.groups {
+            Api::<Group>::all(client.clone()).list(&params).await?.items
+        } else {
+            Vec::new()
+        };
+
+        let mut granted = BTreeMap::new();
+        let namespaces: BTreeSet<_> = service_users
+            .iter()
+            .filter_map(|service_user| service_user.namespace())
+            .collect();
+        for namespace in namespaces {
+            if let Some(groups) = granted_groups(client, config, &namespace).await? {
+                granted.insert(namespace, groups);
+            }
+        }
+
+        Ok(Self {
+            groups,
+            service_users,
+            granted: (!config.policy.group_binding_namespaces.is_empty()).then_some(granted),
+        })
+    }
+}
+
+/// The users and groups currently in LLDAP
+#[derive(Debug, Default)]
+pub struct Current {
+    pub users: Vec<UserDetails>,
+    pub groups: Vec<GroupDetails>,
+}
+
+impl Current {
+    pub async fn fetch(backend: &dyn DirectoryBackend) -> crate::lldap::Result<Self> {
+        Ok(Self {
+            users: backend.list_users().await?,
+            groups: backend.list_groups().await?,
+        })
+    }
+}
+
+/// Collects the changes that are made to the copy of LLDAP, attributes are set on every apply so
+/// the ones that already have the value are left out
+#[derive(Default)]
+struct PlannedChanges(Mutex<Vec<Change>>);
+
+impl ChangeSink for PlannedChanges {
+    fn record<'a>(
+        &'a self,
+        backend: &'a dyn DirectoryBackend,
+        change: Change,
+    ) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            let skip = match &change {
+                Change::SetUserAttribute {
+                    username,
+                    name,
+                    value,
+                } => backend
+                    .list_users()
+                    .await?
+                    .iter()
+                    .find(|user| user.id == *username)
+                    .is_some_and(|user| {
+                        custom_attributes(&user.attributes).get(name) == Some(value)
+                    }),
+                Change::SetGroupAttribute { group, name, value } => backend
+                    .list_groups()
+                    .await?
+                    .iter()
+                    .find(|details| details.display_name == *group)
+                    .is_some_and(|group| {
+                        custom_attributes(&group.attributes).get(name) == Some(value)
+                    }),
+                // The password can not be compared with the one in LLDAP
+                Change::SetPassword { .. } => true,
+                _ => false,
+            };
+
+            if !skip {
+                self.0
+                    .lock()
+                    .expect("Lock should not be poisoned")
+                    .push(change);
+            }
+
+            Ok(())
+        })
+    }
+}
+
+/// Compute what reconciling the resources against the default server would change, by applying
+/// them the same way the reconcilers do to a copy of LLDAP in memory
+pub async fn plan(config: &Config, desired: &Desired, current: &Current) -> Result<Plan> {
+    let mut plan = Plan::default();
+    let backend = RecordingBackend::new(
+        Arc::new(MemoryBackend::from_details(&current.users, &current.groups)),
+        PlannedChanges::default(),
+    );
+
+    for group in &desired.groups {
+        let name = group.name_any();
+
+        if let Some(server_ref) = &group.spec.server_ref {
+            plan.warnings.push(format!(
+                "group '{name}': skipped, only the default server is planned and it uses serverRef '{server_ref}'"
+            ));
+            continue;
+        }
+
+        if let Err(violations) = group.validate(config) {
+            plan.warnings
+                .push(format!("group '{name}': invalid: {violations}"));
+            continue;
+        }
+
+        if group.metadata.deletion_timestamp.is_some() {
+            let groups = backend.get_groups().await?;
+            if let Some(lldap_group) = groups.iter().find(|group| group.display_name == name) {
+                backend.delete_group(lldap_group.id).await?;
+            }
+            continue;
+        }
+
+        group.apply_lldap(&backend).await?;
+    }
+
+    for service_user in &desired.service_users {
+        let name = service_user.name_any();
+        let namespace = service_user.namespace().unwrap_or_default();
+        let username = format_username(&name, &namespace);
+
+        if let Some(server_ref) = &service_user.spec.server_ref {
+            plan.warnings.push(format!(
+                "user '{username}': skipped, only the default server is planned and it uses serverRef '{server_ref}'"
+            ));
+            continue;
+        }
+
+        if let Err(violations) = service_user.validate(config) {
+            plan.warnings
+                .push(format!("user '{username}': invalid: {violations}"));
+            continue;
+        }
+
+        if service_user.metadata.deletion_timestamp.is_some() {
+            backend.delete_user(&username).await?;
+            continue;
+        }
+
+        let granted = desired
+            .granted
+            .as_ref()
+            .map(|granted| granted.get(&namespace).cloned().unwrap_or_default());
+        if let Err(violations) = service_user.check_policy(config, granted.as_ref()) {
+            plan.warnings.push(format!(
+                "user '{username}': groups not allowed by the policy are ignored: {violations}"
+            ));
+        }
+
+        // Groups that do not exist are skipped when applying, but that is worth pointing out
+        let existing_groups: BTreeSet<_> = backend
+            .get_groups()
+            .await?
+            .into_iter()
+            .map(|group| group.display_name)
+            .collect();
+        for group in service_user.desired_groups(config, granted.as_ref()) {
+            if !existing_groups.contains(&group) {
+                plan.warnings.push(format!(
+                    "user '{username}': group '{group}' does not exist and is ignored"
+                ));
+            }
+        }
+
+        // The password is left alone, it can not be compared with the one in LLDAP
+        service_user
+            .apply_lldap(&backend, config, granted.as_ref(), None)
+            .await?;
+    }
+
+    plan.changes = backend
+        .sink()
+        .0
+        .lock()
+        .expect("Lock should not be poisoned")
+        .clone();
+
+    Ok(plan)
+}
+
+#[cfg(test)]
+mod tests {
+    use queries::{AttributeSchema, AttributeValue};
+
+    use super::*;
+
+    fn group(id: i32, name: &str) -> queries::Group {
+        queries::Group {
+            id,
+            display_name: name.into(),
+        }
+    }
+
+    #[tokio::test]
+    async fn plan_output() {
+        let mut documents = Documents::default();
+        documents
+            .parse(
+                r#"
+apiVersion: lldap.huizinga.dev/v1
+kind: Group
+metadata:
+  name: media
+spec:
+  attributes:
+    gid: ["2000"]
+---
+apiVersion: lldap.huizinga.dev/v1
+kind: Group
+metadata:
+  name: monitoring
+spec: {}
+---
+apiVersion: lldap.huizinga.dev/v2
+kind: ServiceUser
+metadata:
+  name: grafana
+  namespace: monitoring
+spec:
+  groups:
+    - name: monitoring
+    - name: lldap_admin
+  attributes:
+    department: [monitoring]
+---
+apiVersion: lldap.huizinga.dev/v2
+kind: ServiceUser
+metadata:
+  name: jellyfin
+  namespace: media
+spec:
+  passwordManager: true
+  groups:
+    - name: media
+    - name: missing
+---
+apiVersion: lldap.huizinga.dev/v2
+kind: ServiceUser
+metadata:
+  name: sonarr
+  namespace: media
+spec:
+  serverRef: other
+"#,
+                "default",
+            )
+            .unwrap();
+        let config = Config::default();
+        let desired = Desired::from_documents(&config, documents);
+
+        let current = Current {
+            users: vec![UserDetails {
+                id: "jellyfin.media".into(),
+                groups: vec![group(3, "lldap_strict_readonly"), group(4, "old")],
+                attributes: Vec::new(),
+            }],
+            groups: vec![
+                GroupDetails {
+                    id: 2,
+                    display_name: "lldap_password_manager".into(),
+                    attributes: Vec::new(),
+                },
+                GroupDetails {
+                    id: 3,
+                    display_name: "lldap_strict_readonly".into(),
+                    attributes: Vec::new(),
+                },
+                GroupDetails {
+                    id: 4,
+                    display_name: "old".into(),
+                    attributes: Vec::new(),
+                },
+                GroupDetails {
+                    id: 5,
+                    display_name: "media".into(),
+                    attributes: vec![AttributeValue {
+                        name: "gid".into(),
+                        value: vec!["1000".into()],
+                        schema: AttributeSchema {
+                            is_hardcoded: false,
+                        },
+                    }],
+                },
+            ],
+        };
+
+        let plan = plan(&config, &desired, &current).await.unwrap();
+
+        let output: Vec<_> = plan
+            .changes
+            .iter()
+            .map(ToString::to_string)
+            .chain(plan.warnings.iter().map(|warning| format!("! {warning}")))
+            .collect();
+        insta::assert_snapshot!(output.join("\n"));
+    }
+}
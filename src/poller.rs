@@ -0,0 +1,421 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use futures::channel::mpsc;
+use futures::future::BoxFuture;
+use kube::runtime::reflector::ObjectRef;
+use queries::{GroupDetails, UserDetails};
+use tracing::{debug, trace, warn};
+
+use crate::backend::{Change, ChangeSink, DirectoryBackend, RecordingBackend};
+use crate::context::Context;
+use crate::instances::LldapInstance;
+use crate::lldap::{self, custom_attributes};
+use crate::resources::service_user::split_username;
+use crate::resources::{Group, ServiceUser};
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+struct UserState {
+    groups: BTreeSet<String>,
+    attributes: BTreeMap<String, Vec<String>>,
+}
+
+/// The parts of the users and groups in LLDAP that the controller manages
+#[derive(Debug, Default, Clone)]
+pub struct Snapshot {
+    users: BTreeMap<String, UserState>,
+    groups: BTreeMap<String, BTreeMap<String, Vec<String>>>,
+}
+
+impl Snapshot {
+    pub fn new(users: &[UserDetails], groups: &[GroupDetails]) -> Self {
+        let users = users
+            .iter()
+            .map(|user| {
+                let state = UserState {
+                    groups: user
+                        .groups
+                        .iter()
+                        .map(|group| group.display_name.clone())
+                        .collect(),
+                    attributes: custom_attributes(&user.attributes),
+                };
+
+                (user.id.clone(), state)
+            })
+            .collect();
+        let groups = groups
+            .iter()
+            .map(|group| {
+                (
+                    group.display_name.clone(),
+                    custom_attributes(&group.attributes),
+                )
+            })
+            .collect();
+
+        Self { users, groups }
+    }
+
+    /// Fetch all users and all groups, one query each
+    pub async fn fetch(backend: &dyn DirectoryBackend) -> lldap::Result<Self> {
+        let users = backend.list_users().await?;
+        let groups = backend.list_groups().await?;
+
+        Ok(Self::new(&users, &groups))
+    }
+
+    /// Users and groups that were created, deleted or changed since the previous snapshot
+    pub fn changes(&self, previous: &Self) -> Changes {
+        Changes {
+            users: changed_keys(&self.users, &previous.users),
+            groups: changed_keys(&self.groups, &previous.groups),
+        }
+    }
+}
+
+fn changed_keys<V: PartialEq>(
+    current: &BTreeMap<String, V>,
+    previous: &BTreeMap<String, V>,
+) -> BTreeSet<String> {
+    let changed = current
+        .iter()
+        .filter(|(key, value)| previous.get(*key) != Some(*value))
+        .map(|(key, _)| key.clone());
+    let deleted = previous
+        .keys()
+        .filter(|key| !current.contains_key(*key))
+        .cloned();
+
+    changed.chain(deleted).collect()
+}
+
+/// Names of the users and groups that changed
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Changes {
+    pub users: BTreeSet<String>,
+    pub groups: BTreeSet<String>,
+}
+
+impl Changes {
+    pub fn is_empty(&self) -> bool {
+        self.users.is_empty() && self.groups.is_empty()
+    }
+
+    /// Leave out the users and groups that are in the other changes
+    pub fn remove(&mut self, other: &Changes) {
+        self.users
+            .retain(|username| !other.users.contains(username));
+        self.groups.retain(|group| !other.groups.contains(group));
+    }
+
+    /// ServiceUsers the changed users would belong to, users that do not follow the
+    /// `<name>.<namespace>` format can not belong to one
+    pub fn service_users(&self) -> impl Iterator<Item = ObjectRef<ServiceUser>> + '_ {
+        self.users
+            .iter()
+            .filter_map(|username| split_username(username))
+            .map(|(name, namespace)| ObjectRef::new(name).within(namespace))
+    }
+
+    pub fn groups(&self) -> impl Iterator<Item = ObjectRef<Group>> + '_ {
+        self.groups.iter().map(|name| ObjectRef::new(name))
+    }
+}
+
+/// Users and groups the controller changed itself since the previous poll, by the name of the
+/// LLDAP instance. The objects that made the changes were just reconciled, so the poller skips
+/// them.
+#[derive(Default)]
+pub struct OwnChanges {
+    changes: Mutex<HashMap<Option<String>, Changes>>,
+}
+
+impl OwnChanges {
+    /// Record the users and groups the backend changes in the instance
+    pub fn wrap(
+        self: &Arc<Self>,
+        instance: &LldapInstance,
+        inner: Arc<dyn DirectoryBackend>,
+    ) -> Arc<dyn DirectoryBackend> {
+        let sink = InstanceChanges {
+            own_changes: self.clone(),
+            server: instance.name.clone(),
+        };
+
+        Arc::new(RecordingBackend::new(inner, sink))
+    }
+
+    fn record(&self, server: &Option<String>, record: impl FnOnce(&mut Changes)) {
+        let mut changes = self.changes.lock().expect("Lock should not be poisoned");
+        record(changes.entry(server.clone()).or_default());
+    }
+
+    /// The changes made to the instance since the last call
+    pub fn take(&self, instance: &LldapInstance) -> Changes {
+        self.changes
+            .lock()
+            .expect("Lock should not be poisoned")
+            .remove(&instance.name)
+            .unwrap_or_default()
+    }
+}
+
+/// Records which users and groups of the instance are changed
+struct InstanceChanges {
+    own_changes: Arc<OwnChanges>,
+    server: Option<String>,
+}
+
+impl ChangeSink for InstanceChanges {
+    fn record<'a>(
+        &'a self,
+        _backend: &'a dyn DirectoryBackend,
+        change: Change,
+    ) -> BoxFuture<'a, lldap::Result<()>> {
+        self.own_changes
+            .record(&self.server, |changes| match change {
+                Change::CreateUser { username }
+                | Change::DeleteUser { username }
+                | Change::AddUserToGroup { username, .. }
+                | Change::RemoveUserFromGroup { username, .. }
+                | Change::SetUserAttribute { username, .. } => {
+                    changes.users.insert(username);
+                }
+                Change::CreateGroup { group }
+                | Change::DeleteGroup { group }
+                | Change::SetGroupAttribute { group, .. } => {
+                    changes.groups.insert(group);
+                }
+                // The password is not part of the snapshot
+                Change::SetPassword { .. } => {}
+            });
+
+        Box::pin(async { Ok(()) })
+    }
+}
+
+/// An object to reconcile because its user or group changed in the LLDAP instance with the name,
+/// `None` for the default server
+#[derive(Debug, Clone)]
+pub struct Polled<K: kube::Resource<DynamicType = ()>> {
+    pub server: Option<String>,
+    pub obj_ref: ObjectRef<K>,
+}
+
+/// Polls LLDAP for changes made outside the controller and sends the objects that need to be
+/// reconciled to every subscribed controller
+#[derive(Default)]
+pub struct Poller {
+    service_users: Vec<mpsc::UnboundedSender<Polled<ServiceUser>>>,
+    groups: Vec<mpsc::UnboundedSender<Polled<Group>>>,
+}
+
+impl Poller {
+    /// ServiceUsers whose user changed, for `Controller::reconcile_on` after checking that the
+    /// object uses the server
+    pub fn service_users(&mut self) -> mpsc::UnboundedReceiver<Polled<ServiceUser>> {
+        let (tx, rx) = mpsc::unbounded();
+        self.service_users.push(tx);
+
+        rx
+    }
+
+    /// Groups that changed, for `Controller::reconcile_on` after checking that the object uses
+    /// the server
+    pub fn groups(&mut self) -> mpsc::UnboundedReceiver<Polled<Group>> {
+        let (tx, rx) = mpsc::unbounded();
+        self.groups.push(tx);
+
+        rx
+    }
+
+    fn send(&self, server: &Option<String>, changes: &Changes) {
+        // Receivers are only gone when their controller stopped
+        for tx in &self.service_users {
+            for obj_ref in changes.service_users() {
+                let server = server.clone();
+                let _ = tx.unbounded_send(Polled { server, obj_ref });
+            }
+        }
+        for tx in &self.groups {
+            for obj_ref in changes.groups() {
+                let server = server.clone();
+                let _ = tx.unbounded_send(Polled { server, obj_ref });
+            }
+        }
+    }
+
+    /// Poll every known LLDAP instance. The first poll of an instance only takes the snapshot,
+    /// the objects are reconciled at startup anyway
+    pub async fn run(self, ctx: Arc<Context>, interval: Duration) {
+        let mut snapshots = HashMap::<String, Snapshot>::new();
+
+        loop {
+            tokio::time::sleep(interval).await;
+
+            if let Err(err) = ctx.instances.prune(&ctx.client).await {
+                warn!("Failed to remove LLDAP instances of deleted LldapServers: {err}");
+            }
+            let instances = ctx.instances.all();
+            snapshots.retain(|server, _| {
+                instances
+                    .iter()
+                    .any(|instance| instance.display_name() == server)
+            });
+
+            for instance in instances {
+                // Reconciles are paused anyway until the probe succeeds
+                if instance.breaker.is_open() {
+                    continue;
+                }
+
+                let server = instance.display_name().to_owned();
+                trace!(server, "Polling LLDAP");
+                // Taken before listing, a change made while listing is skipped in the next poll
+                let own_changes = ctx.own_changes.take(&instance);
+                let snapshot = match ctx.read_backend(&instance).await {
+                    Ok(backend) => Snapshot::fetch(backend.as_ref()).await,
+                    Err(err) => Err(err),
+                };
+                let snapshot = match snapshot {
+                    Ok(snapshot) => snapshot,
+                    Err(err) => {
+                        warn!(server, "Polling LLDAP failed: {err}");
+                        continue;
+                    }
+                };
+
+                if let Some(previous) = snapshots.get(&server) {
+                    let mut changes = snapshot.changes(previous);
+                    changes.remove(&own_changes);
+                    if !changes.is_empty() {
+                        debug!(
+                            server,
+                            users = changes.users.len(),
+                            groups = changes.groups.len(),
+                            "LLDAP changed"
+                        );
+                        self.send(&instance.name, &changes);
+                    }
+                }
+                snapshots.insert(server, snapshot);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::StreamExt;
+
+    use super::*;
+    use crate::backend::MemoryBackend;
+    use crate::config::Config;
+    use crate::testing::kube::TestEnv;
+
+    #[tokio::test]
+    async fn changes_since_previous_snapshot() {
+        let backend = MemoryBackend::default();
+        let media = backend.create_group("media").await.unwrap();
+        backend.create_group("monitoring").await.unwrap();
+        backend.create_user("grafana.monitoring").await.unwrap();
+        backend.create_user("jellyfin.media").await.unwrap();
+        backend
+            .add_user_to_group("jellyfin.media", media.id)
+            .await
+            .unwrap();
+        let previous = Snapshot::fetch(&backend).await.unwrap();
+
+        backend
+            .remove_user_from_group("jellyfin.media", media.id)
+            .await
+            .unwrap();
+        backend.delete_user("grafana.monitoring").await.unwrap();
+        backend.create_user("admin").await.unwrap();
+        let attributes = BTreeMap::from([("gid".into(), vec!["2000".into()])]);
+        backend
+            .update_group_attributes(media.id, &attributes)
+            .await
+            .unwrap();
+        let changes = Snapshot::fetch(&backend).await.unwrap().changes(&previous);
+
+        assert_eq!(
+            changes,
+            Changes {
+                users: [
+                    "admin".into(),
+                    "grafana.monitoring".into(),
+                    "jellyfin.media".into()
+                ]
+                .into(),
+                groups: ["media".into()].into(),
+            }
+        );
+
+        let mut poller = Poller::default();
+        let service_users = poller.service_users();
+        let mut groups = poller.groups();
+        poller.send(&Some("other".into()), &changes);
+        drop(poller);
+
+        let server = Some("other".to_owned());
+        let service_users: Vec<_> = service_users
+            .map(|polled| (polled.server, polled.obj_ref))
+            .collect()
+            .await;
+        assert_eq!(
+            service_users,
+            [
+                (
+                    server.clone(),
+                    ObjectRef::new("grafana").within("monitoring")
+                ),
+                (server.clone(), ObjectRef::new("jellyfin").within("media"))
+            ]
+        );
+        let group = groups.next().await.unwrap();
+        assert_eq!(
+            (group.server, group.obj_ref),
+            (server, ObjectRef::new("media"))
+        );
+    }
+
+    #[tokio::test]
+    async fn own_changes_skipped() {
+        let env = TestEnv::start(Config::default()).await;
+        let instance = env.ctx.instances.get(&env.ctx.client, None).await.unwrap();
+        let backend = Arc::new(MemoryBackend::default());
+        let media = backend.create_group("media").await.unwrap();
+        backend.create_user("jellyfin.media").await.unwrap();
+        let previous = Snapshot::fetch(backend.as_ref()).await.unwrap();
+
+        let reconciler = env.ctx.own_changes.wrap(&instance, backend.clone());
+        reconciler
+            .add_user_to_group("jellyfin.media", media.id)
+            .await
+            .unwrap();
+        let attributes = BTreeMap::from([("gid".into(), vec!["2000".into()])]);
+        reconciler
+            .update_group_attributes(media.id, &attributes)
+            .await
+            .unwrap();
+        // Made outside the controller
+        backend.create_user("admin").await.unwrap();
+
+        let mut changes = Snapshot::fetch(backend.as_ref())
+            .await
+            .unwrap()
+            .changes(&previous);
+        changes.remove(&env.ctx.own_changes.take(&instance));
+        assert_eq!(
+            changes,
+            Changes {
+                users: ["admin".into()].into(),
+                groups: BTreeSet::new(),
+            }
+        );
+        assert!(env.ctx.own_changes.take(&instance).is_empty());
+    }
+}
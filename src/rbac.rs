@@ -0,0 +1,352 @@
+use std::any::TypeId;
+use std::collections::BTreeMap;
+
+use k8s_openapi::NamespaceResourceScope;
+use k8s_openapi::api::core::v1::{Namespace, Secret};
+use k8s_openapi::api::events::v1::Event;
+use k8s_openapi::api::rbac::v1::{
+    ClusterRole, ClusterRoleBinding, PolicyRule, Role, RoleBinding, RoleRef, Subject,
+};
+use kube::Resource;
+use kube::api::ObjectMeta;
+
+use crate::config::Config;
+use crate::resources::{Group, GroupBinding, LldapServer, ServiceUser};
+
+/// Where the controller needs a permission
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Scope {
+    /// Cluster scoped resources
+    Cluster,
+    /// The namespaces the controller watches
+    Watched,
+    Namespaces(Vec<String>),
+}
+
+struct Permission {
+    scope: Scope,
+    rule: PolicyRule,
+}
+
+fn permission<K>(subresources: &[&str], verbs: &[&str], scope: Scope) -> Permission
+where
+    K: Resource<DynamicType = ()>,
+    K::Scope: 'static,
+{
+    let plural = K::plural(&());
+    let resources = if subresources.is_empty() {
+        vec![plural.into_owned()]
+    } else {
+        subresources
+            .iter()
+            .map(|subresource| format!("{plural}/{subresource}"))
+            .collect()
+    };
+
+    let scope = if TypeId::of::<K::Scope>() == TypeId::of::<NamespaceResourceScope>() {
+        scope
+    } else {
+        Scope::Cluster
+    };
+
+    Permission {
+        scope,
+        rule: PolicyRule {
+            api_groups: Some(vec![K::group(&()).into_owned()]),
+            resources: Some(resources),
+            verbs: verbs.iter().map(|verb| (*verb).to_owned()).collect(),
+            ..Default::default()
+        },
+    }
+}
+
+/// Everything the controller does with the api server given its configuration
+fn permissions(config: &Config, namespace: &str) -> Vec<Permission> {
+    let mut permissions = vec![
+        permission::<ServiceUser>(&[], &["get", "list", "watch", "patch"], Scope::Watched),
+        permission::<ServiceUser>(&["status"], &["get", "patch"], Scope::Watched),
+        // Needed to set the ServiceUser as owner of the secret
+        permission::<ServiceUser>(&["finalizers"], &["update"], Scope::Watched),
+        permission::<Secret>(
+            &[],
+            &["get", "list", "watch", "create", "update", "patch"],
+            Scope::Watched,
+        ),
+        permission::<Event>(&[], &["create", "patch"], Scope::Watched),
+        // Listed to forget the instances of deleted servers
+        permission::<LldapServer>(&[], &["get", "list"], Scope::Cluster),
+        // Secrets of LldapServers are always read from the namespace of the controller, the CRD
+        // has no way to refer to other namespaces
+        permission::<Secret>(&[], &["get"], Scope::Namespaces(vec![namespace.into()])),
+    ];
+
+    if config.watch.groups {
+        permissions.extend([
+            permission::<Group>(&[], &["get", "list", "watch", "patch"], Scope::Cluster),
+            permission::<Group>(&["status"], &["get", "patch"], Scope::Cluster),
+            // Events for cluster scoped objects end up in the default namespace
+            permission::<Event>(
+                &[],
+                &["create", "patch"],
+                Scope::Namespaces(vec!["default".into()]),
+            ),
+        ]);
+    }
+
+    let owner_namespaces = &config.policy.group_binding_namespaces;
+    if !owner_namespaces.is_empty() {
+        permissions.extend([
+            permission::<GroupBinding>(
+                &[],
+                &["get", "list", "watch"],
+                Scope::Namespaces(owner_namespaces.clone()),
+            ),
+            // Watched for the labels the namespace selectors match
+            permission::<Namespace>(&[], &["get", "list", "watch"], Scope::Cluster),
+        ]);
+    }
+
+    permissions
+}
+
+/// Add the rule unless an existing rule already grants it
+fn add_rule(rules: &mut Vec<PolicyRule>, rule: PolicyRule) {
+    let covered = rules.iter().any(|existing| {
+        existing.api_groups == rule.api_groups
+            && existing.resources == rule.resources
+            && rule.verbs.iter().all(|verb| existing.verbs.contains(verb))
+    });
+
+    if !covered {
+        rules.push(rule);
+    }
+}
+
+/// A permission the controller needs in a single namespace
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Access {
+    pub group: String,
+    pub resource: String,
+    pub subresource: Option<String>,
+    pub verbs: Vec<String>,
+    /// `None` for cluster scoped resources, or all namespaces
+    pub namespace: Option<String>,
+}
+
+/// Everything the controller needs access to, split up by namespace so it can be checked with
+/// access reviews
+pub fn required_access(config: &Config, namespace: &str) -> Vec<Access> {
+    let mut required = Vec::new();
+
+    for Permission { scope, rule } in permissions(config, namespace) {
+        let namespaces = match scope {
+            Scope::Cluster => vec![None],
+            Scope::Watched if config.watch.namespaces.is_empty() => vec![None],
+            Scope::Watched => config.watch.namespaces.iter().cloned().map(Some).collect(),
+            Scope::Namespaces(namespaces) => namespaces.into_iter().map(Some).collect(),
+        };
+
+        let group = rule
+            .api_groups
+            .iter()
+            .flatten()
+            .next()
+            .cloned()
+            .unwrap_or_default();
+        for resource in rule.resources.iter().flatten() {
+            let (resource, subresource) = match resource.split_once('/') {
+                Some((resource, subresource)) => (resource, Some(subresource.to_owned())),
+                None => (resource.as_str(), None),
+            };
+
+            for namespace in &namespaces {
+                required.push(Access {
+                    group: group.clone(),
+                    resource: resource.to_owned(),
+                    subresource: subresource.clone(),
+                    verbs: rule.verbs.clone(),
+                    namespace: namespace.clone(),
+                });
+            }
+        }
+    }
+
+    required
+}
+
+/// Roles and bindings granting the service account of the controller exactly what it needs
+#[derive(Debug, Default)]
+pub struct Rbac {
+    pub cluster_role: Option<ClusterRole>,
+    pub cluster_role_binding: Option<ClusterRoleBinding>,
+    pub roles: Vec<Role>,
+    pub role_bindings: Vec<RoleBinding>,
+}
+
+impl Rbac {
+    /// Cluster wide permissions when the controller watches all namespaces, otherwise a Role in
+    /// every namespace the controller needs access to and a ClusterRole for the cluster scoped
+    /// resources
+    pub fn new(config: &Config, name: &str, namespace: &str) -> Self {
+        let cluster_wide = config.watch.namespaces.is_empty();
+
+        let mut cluster_rules = Vec::new();
+        let mut namespaced_rules: BTreeMap<String, Vec<PolicyRule>> = BTreeMap::new();
+        for Permission { scope, rule } in permissions(config, namespace) {
+            match scope {
+                Scope::Cluster => add_rule(&mut cluster_rules, rule),
+                _ if cluster_wide => add_rule(&mut cluster_rules, rule),
+                Scope::Watched => {
+                    for watched in &config.watch.namespaces {
+                        add_rule(
+                            namespaced_rules.entry(watched.clone()).or_default(),
+                            rule.clone(),
+                        );
+                    }
+                }
+                Scope::Namespaces(namespaces) => {
+                    for namespace in namespaces {
+                        add_rule(namespaced_rules.entry(namespace).or_default(), rule.clone());
+                    }
+                }
+            }
+        }
+
+        let subject = Subject {
+            kind: "ServiceAccount".into(),
+            name: name.into(),
+            namespace: Some(namespace.into()),
+            ..Default::default()
+        };
+        let role_ref = |kind: &str| RoleRef {
+            api_group: "rbac.authorization.k8s.io".into(),
+            kind: kind.into(),
+            name: name.into(),
+        };
+
+        let mut rbac = Self::default();
+
+        if !cluster_rules.is_empty() {
+            rbac.cluster_role = Some(ClusterRole {
+                metadata: ObjectMeta {
+                    name: Some(name.into()),
+                    ..Default::default()
+                },
+                rules: Some(cluster_rules),
+                ..Default::default()
+            });
+            rbac.cluster_role_binding = Some(ClusterRoleBinding {
+                metadata: ObjectMeta {
+                    name: Some(name.into()),
+                    ..Default::default()
+                },
+                subjects: Some(vec![subject.clone()]),
+                role_ref: role_ref("ClusterRole"),
+            });
+        }
+
+        for (namespace, rules) in namespaced_rules {
+            let metadata = ObjectMeta {
+                name: Some(name.into()),
+                namespace: Some(namespace),
+                ..Default::default()
+            };
+
+            rbac.roles.push(Role {
+                metadata: metadata.clone(),
+                rules: Some(rules),
+            });
+            rbac.role_bindings.push(RoleBinding {
+                metadata,
+                subjects: Some(vec![subject.clone()]),
+                role_ref: role_ref("Role"),
+            });
+        }
+
+        rbac
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use kube::CustomResourceExt;
+
+    use super::*;
+
+    #[test]
+    fn cluster_role_output() {
+        let rbac = Rbac::new(&Config::default(), "lldap-controller", "lldap");
+
+        assert!(rbac.roles.is_empty());
+        insta::assert_yaml_snapshot!(rbac.cluster_role);
+    }
+
+    /// The hand-written role this replaced misspelled the status subresource, which went unnoticed
+    #[test]
+    fn group_status() {
+        let rbac = Rbac::new(&Config::default(), "lldap-controller", "lldap");
+
+        let resources: Vec<_> = rbac
+            .cluster_role
+            .unwrap()
+            .rules
+            .unwrap()
+            .into_iter()
+            .flat_map(|rule| rule.resources.unwrap_or_default())
+            .collect();
+        assert!(resources.contains(&"groups/status".to_owned()));
+    }
+
+    #[test]
+    fn namespaced_rbac() {
+        let mut config = Config::default();
+        config.watch.namespaces = vec!["apps".into()];
+        config.watch.groups = false;
+
+        let rbac = Rbac::new(&config, "lldap-controller", "lldap");
+
+        let cluster_rules = rbac.cluster_role.unwrap().rules.unwrap();
+        assert_eq!(cluster_rules.len(), 1);
+        assert_eq!(
+            cluster_rules[0].resources,
+            Some(vec!["lldapservers".to_owned()])
+        );
+
+        let namespaces: Vec<_> = rbac
+            .roles
+            .iter()
+            .map(|role| role.metadata.namespace.as_deref().unwrap())
+            .collect();
+        assert_eq!(namespaces, ["apps", "lldap"]);
+
+        let required = required_access(&config, "lldap");
+        let status = required
+            .iter()
+            .find(|access| access.subresource.as_deref() == Some("status"))
+            .unwrap();
+        assert_eq!(status.resource, "serviceusers");
+        assert_eq!(status.namespace.as_deref(), Some("apps"));
+
+        // The secrets of LldapServers are only readable in the namespace of the controller
+        let secret_namespaces: Vec<_> = required
+            .iter()
+            .filter(|access| access.resource == "secrets" && access.verbs.contains(&"get".into()))
+            .map(|access| access.namespace.as_deref())
+            .collect();
+        assert_eq!(secret_namespaces, [Some("apps"), Some("lldap")]);
+        // Which only works when an LldapServer can not point at secrets in other namespaces
+        let crd = serde_json::to_value(LldapServer::crd()).unwrap();
+        let spec = &crd["spec"]["versions"][0]["schema"]["openAPIV3Schema"]["properties"]["spec"];
+        for secret_ref in [
+            &spec["properties"]["credentials"],
+            &spec["properties"]["tls"]["properties"]["caSecretRef"],
+            &spec["properties"]["tls"]["properties"]["clientCertSecretRef"],
+        ] {
+            assert!(secret_ref["properties"]["name"].is_object(), "{secret_ref}");
+            assert!(
+                secret_ref["properties"]["namespace"].is_null(),
+                "{secret_ref}"
+            );
+        }
+    }
+}
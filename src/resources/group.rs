@@ -1,26 +1,125 @@
+use std::collections::BTreeMap;
 use std::sync::Arc;
 use std::time::Duration;
 
-use kube::CustomResource;
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::Condition;
 use kube::runtime::controller::Action;
+use kube::{Api, CustomResource, ResourceExt};
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
-use tracing::{debug, trace};
+use tracing::{debug, trace, warn};
 
-use super::{Error, Reconcile, Result};
+use super::{Error, Reconcile, Result, resync};
+use crate::backend::DirectoryBackend;
+use crate::config::Config;
 use crate::context::{Context, ControllerEvents};
+use crate::instances::LldapInstance;
+use crate::lldap;
+use crate::validation::{self, Violations};
 
 #[derive(CustomResource, Deserialize, Serialize, Clone, Debug, JsonSchema)]
-#[kube(kind = "Group", group = "lldap.huizinga.dev", version = "v1")]
+#[kube(
+    kind = "Group",
+    group = "lldap.huizinga.dev",
+    version = "v1",
+    status = "GroupStatus"
+)]
 #[kube(
     shortname = "lg",
     doc = "Custom resource for managing Groups inside of LLDAP"
 )]
 #[serde(rename_all = "camelCase")]
-pub struct GroupSpec {}
+pub struct GroupSpec {
+    /// Custom attributes of the group, attributes that are not listed are left untouched
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub attributes: BTreeMap<String, Vec<String>>,
+    /// Name of the LldapServer to create the group in, uses the default server if not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub server_ref: Option<String>,
+    /// Seconds between reconciles when nothing changes, overrides the controller configuration
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub resync_interval_secs: Option<u64>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Default, Debug, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct GroupStatus {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub conditions: Vec<Condition>,
+    /// Value of the reconcile-at annotation that was handled last
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_handled_reconcile_at: Option<String>,
+}
+
+impl Group {
+    /// Check the spec for anything LLDAP or the policy would reject
+    pub fn validate(&self, config: &Config) -> Result<(), Violations> {
+        let mut violations = Violations::default();
+
+        if let Some(name) = &self.metadata.name {
+            violations.check("metadata.name", validation::group_name(name));
+            violations.check("metadata.name", Self::managed(name, config));
+        }
+
+        if self.spec.resync_interval_secs == Some(0) {
+            violations.check(
+                "spec.resyncIntervalSecs",
+                Err("should be at least 1".into()),
+            );
+        }
+
+        violations.into_result()
+    }
+
+    /// Check if the controller is allowed to manage, and thus delete, the group with this name
+    fn managed(name: &str, config: &Config) -> Result<(), String> {
+        validation::group_allowed(name, None, config)?;
+
+        // Deleting the Group would delete the builtin group as well
+        if name == config.groups.password_manager || name == config.groups.strict_readonly {
+            return Err(format!("group '{name}' is builtin and can not be managed"));
+        }
+
+        Ok(())
+    }
+
+    /// Make the group in LLDAP match the spec, returns true when the group had to be created
+    pub async fn apply_lldap(&self, backend: &dyn DirectoryBackend) -> lldap::Result<bool> {
+        let name = self.name_any();
+
+        trace!(name, "Get existing groups");
+        let groups = backend.get_groups().await?;
+
+        let (id, created) = match groups.iter().find(|group| group.display_name == name) {
+            Some(group) => {
+                trace!("Group already exists");
+
+                (group.id, false)
+            }
+            None => {
+                trace!("Group does not exist yet");
+
+                (backend.create_group(&name).await?.id, true)
+            }
+        };
+
+        if !self.spec.attributes.is_empty() {
+            trace!(name, "Updating attributes");
+            backend
+                .update_group_attributes(id, &self.spec.attributes)
+                .await?;
+        }
+
+        Ok(created)
+    }
+}
 
 impl Reconcile for Group {
-    async fn reconcile(self: Arc<Self>, ctx: Arc<Context>) -> Result<Action> {
+    async fn reconcile(
+        self: Arc<Self>,
+        ctx: Arc<Context>,
+        instance: Arc<LldapInstance>,
+    ) -> Result<Action> {
         let name = self
             .metadata
             .name
@@ -29,25 +128,22 @@ impl Reconcile for Group {
 
         debug!(name, "Apply");
 
-        let lldap_client = ctx.lldap_config.build_client().await?;
+        self.validate(&ctx.config)?;
 
-        trace!(name, "Get existing groups");
-        let groups = lldap_client.get_groups().await?;
-
-        if !groups.iter().any(|group| group.display_name == name) {
-            trace!("Group does not exist yet");
-
-            lldap_client.create_group(&name).await?;
+        let backend = ctx.backend(&instance, self.as_ref()).await?;
 
+        if self.apply_lldap(backend.as_ref()).await? && !ctx.config.dry_run {
             ctx.recorder.group_created(self.as_ref(), &name).await?;
-        } else {
-            trace!("Group already exists");
         }
 
-        Ok(Action::requeue(Duration::from_secs(3600)))
+        Ok(resync(self.as_ref(), &ctx.config))
     }
 
-    async fn cleanup(self: Arc<Self>, ctx: Arc<Context>) -> Result<Action> {
+    async fn cleanup(
+        self: Arc<Self>,
+        ctx: Arc<Context>,
+        instance: Arc<LldapInstance>,
+    ) -> Result<Action> {
         let name = self
             .metadata
             .name
@@ -56,21 +152,207 @@ impl Reconcile for Group {
 
         debug!(name, "Cleanup");
 
-        let lldap_client = ctx.lldap_config.build_client().await?;
+        // The Group never managed the group in LLDAP, so it should not delete it either
+        if let Err(reason) = Self::managed(&name, &ctx.config) {
+            warn!(name, "Not deleting group: {reason}");
+
+            if !ctx.config.dry_run {
+                ctx.recorder.cleanup_skipped(self.as_ref(), &reason).await?;
+            }
+
+            return Ok(Action::await_change());
+        }
+
+        let backend = ctx.backend(&instance, self.as_ref()).await?;
 
         trace!(name, "Get existing groups");
-        let groups = lldap_client.get_groups().await?;
+        let groups = backend.get_groups().await?;
 
         if let Some(group) = groups.iter().find(|group| group.display_name == name) {
             trace!(name, "Deleting group");
 
-            lldap_client.delete_group(group.id).await?;
+            backend.delete_group(group.id).await?;
 
-            ctx.recorder.group_deleted(self.as_ref(), &name).await?;
+            if !ctx.config.dry_run {
+                ctx.recorder.group_deleted(self.as_ref(), &name).await?;
+            }
         } else {
             trace!(name, "Group does not exist")
         }
 
         Ok(Action::await_change())
     }
+
+    fn api(&self, client: kube::Client) -> Api<Self> {
+        Api::all(client)
+    }
+
+    fn server_ref(&self) -> Option<&str> {
+        self.spec.server_ref.as_deref()
+    }
+
+    fn conditions(&self) -> &[Condition] {
+        self.status
+            .as_ref()
+            .map(|status| status.conditions.as_slice())
+            .unwrap_or_default()
+    }
+
+    fn last_handled_reconcile_at(&self) -> Option<&str> {
+        self.status
+            .as_ref()
+            .and_then(|status| status.last_handled_reconcile_at.as_deref())
+    }
+
+    fn resync_interval(&self, config: &Config) -> Duration {
+        self.spec
+            .resync_interval_secs
+            .map_or_else(|| config.group_resync_interval(), Duration::from_secs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use kube::api::{DeleteParams, Patch, PatchParams, PostParams};
+    use serde_json::json;
+
+    use super::*;
+    use crate::resources::{RECONCILE_AT_ANNOTATION, reconcile};
+    use crate::testing::kube::TestEnv;
+
+    async fn create(env: &TestEnv) -> Api<Group> {
+        let group = Group::new(
+            "media",
+            GroupSpec {
+                attributes: BTreeMap::from([("gid".into(), vec!["2000".into()])]),
+                server_ref: None,
+                resync_interval_secs: None,
+            },
+        );
+
+        let api = Api::<Group>::all(env.ctx.client.clone());
+        api.create(&PostParams::default(), &group).await.unwrap();
+
+        for _ in 0..2 {
+            reconcile(env.latest(&api, "media").await, env.ctx.clone())
+                .await
+                .unwrap();
+        }
+
+        api
+    }
+
+    #[tokio::test]
+    async fn reconcile_creates_group() {
+        let env = TestEnv::start(Config::default()).await;
+        create(&env).await;
+
+        let state = env.lldap.state();
+        let id = state.group_id("media").unwrap();
+        assert_eq!(
+            state.groups[&id].attributes,
+            BTreeMap::from([("gid".into(), vec!["2000".into()])])
+        );
+        drop(state);
+
+        assert_eq!(
+            env.kube.event_reasons(),
+            [("GroupCreated".into(), "media".into())]
+        );
+    }
+
+    #[tokio::test]
+    async fn reconcile_requested_by_annotation() {
+        let env = TestEnv::start(Config::default()).await;
+        let api = create(&env).await;
+        {
+            let mut state = env.lldap.state();
+            let id = state.group_id("media").unwrap();
+            state.groups.remove(&id);
+        }
+
+        let patch = json!({
+            "metadata": { "annotations": { RECONCILE_AT_ANNOTATION: "2026-01-01T00:00:00Z" } }
+        });
+        api.patch("media", &PatchParams::default(), &Patch::Merge(&patch))
+            .await
+            .unwrap();
+        reconcile(env.latest(&api, "media").await, env.ctx.clone())
+            .await
+            .unwrap();
+
+        assert!(env.lldap.state().group_id("media").is_some());
+        let group = api.get("media").await.unwrap();
+        assert_eq!(
+            group.last_handled_reconcile_at(),
+            Some("2026-01-01T00:00:00Z")
+        );
+    }
+
+    #[tokio::test]
+    async fn deletion_removes_group() {
+        let env = TestEnv::start(Config::default()).await;
+        let api = create(&env).await;
+
+        api.delete("media", &DeleteParams::default()).await.unwrap();
+        reconcile(env.latest(&api, "media").await, env.ctx.clone())
+            .await
+            .unwrap();
+
+        assert!(api.get_opt("media").await.unwrap().is_none());
+        assert!(env.lldap.state().group_id("media").is_none());
+        assert_eq!(
+            env.kube.event_reasons().last().unwrap(),
+            &("GroupDeleted".into(), "media".into())
+        );
+    }
+
+    #[tokio::test]
+    async fn deletion_when_group_is_gone() {
+        let env = TestEnv::start(Config::default()).await;
+        let api = create(&env).await;
+        {
+            let mut state = env.lldap.state();
+            let id = state.group_id("media").unwrap();
+            state.groups.remove(&id);
+        }
+
+        api.delete("media", &DeleteParams::default()).await.unwrap();
+        reconcile(env.latest(&api, "media").await, env.ctx.clone())
+            .await
+            .unwrap();
+
+        assert!(api.get_opt("media").await.unwrap().is_none());
+        assert_eq!(env.kube.event_reasons().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn deletion_keeps_privileged_group() {
+        let env = TestEnv::start(Config::default()).await;
+        let mut group = Group::new(
+            "lldap_admin",
+            GroupSpec {
+                attributes: BTreeMap::new(),
+                server_ref: None,
+                resync_interval_secs: None,
+            },
+        );
+        group.metadata.finalizers = Some(vec![env.ctx.controller_name.clone()]);
+
+        let api = Api::<Group>::all(env.ctx.client.clone());
+        api.create(&PostParams::default(), &group).await.unwrap();
+        api.delete("lldap_admin", &DeleteParams::default())
+            .await
+            .unwrap();
+        reconcile(env.latest(&api, "lldap_admin").await, env.ctx.clone())
+            .await
+            .unwrap();
+
+        assert!(api.get_opt("lldap_admin").await.unwrap().is_none());
+        assert!(env.lldap.state().group_id("lldap_admin").is_some());
+        assert_eq!(
+            env.kube.event_reasons(),
+            [("CleanupSkipped".into(), "lldap_admin".into())]
+        );
+    }
 }
@@ -0,0 +1,265 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use k8s_openapi::api::core::v1::Namespace;
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::LabelSelector;
+use kube::core::{Selector, SelectorExt};
+use kube::runtime::reflector::{ObjectRef, Store};
+use kube::{Api, CustomResource, ResourceExt};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::config::Config;
+
+#[derive(CustomResource, Deserialize, Serialize, Clone, Debug, JsonSchema)]
+#[kube(
+    kind = "GroupBinding",
+    group = "lldap.huizinga.dev",
+    version = "v1",
+    namespaced
+)]
+#[kube(
+    shortname = "lgb",
+    doc = "Custom resource granting namespaces access to a Group, only trusted when created in one of the group owner namespaces",
+    printcolumn = r#"{"name":"Group", "type":"string", "jsonPath":".spec.group"}"#,
+    printcolumn = r#"{"name":"Age", "type":"date", "jsonPath":".metadata.creationTimestamp"}"#
+)]
+#[serde(rename_all = "camelCase")]
+pub struct GroupBindingSpec {
+    /// Name of the group ServiceUsers are allowed to join
+    pub group: String,
+    /// Namespaces whose ServiceUsers may join the group
+    #[serde(default)]
+    pub namespaces: Vec<String>,
+    /// Namespaces matching this selector may join the group as well
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub namespace_selector: Option<LabelSelector>,
+}
+
+impl GroupBinding {
+    /// Check if the binding grants the namespace access to the group
+    pub fn grants(&self, namespace: &str, labels: &BTreeMap<String, String>) -> bool {
+        if self.spec.namespaces.iter().any(|n| n == namespace) {
+            return true;
+        }
+
+        let Some(selector) = self.spec.namespace_selector.clone() else {
+            return false;
+        };
+
+        match Selector::try_from(selector) {
+            Ok(selector) => selector.matches(labels),
+            Err(err) => {
+                warn!(
+                    name = self.name_any(),
+                    namespace = self.namespace(),
+                    "Invalid namespace selector: {err}"
+                );
+                false
+            }
+        }
+    }
+}
+
+/// Groups that the GroupBindings in the owner namespaces grant the namespace access to, `None`
+/// when GroupBindings are not used
+pub async fn granted_groups(
+    client: &kube::Client,
+    config: &Config,
+    namespace: &str,
+) -> Result<Option<BTreeSet<String>>, kube::Error> {
+    let owner_namespaces = &config.policy.group_binding_namespaces;
+    if owner_namespaces.is_empty() {
+        return Ok(None);
+    }
+
+    let labels = namespace_labels(client, namespace).await?;
+
+    let mut bindings = Vec::new();
+    for owner_namespace in owner_namespaces {
+        bindings.extend(
+            Api::<GroupBinding>::namespaced(client.clone(), owner_namespace)
+                .list(&Default::default())
+                .await?,
+        );
+    }
+
+    Ok(Some(granted_by(&bindings, namespace, &labels)))
+}
+
+/// Namespaces and the GroupBindings in the owner namespaces as seen by the watches of the
+/// controller, so reconciles do not have to get them from the API server
+#[derive(Clone)]
+pub struct GrantStores {
+    pub namespaces: Store<Namespace>,
+    /// One store for every owner namespace
+    pub bindings: Vec<Store<GroupBinding>>,
+}
+
+impl GrantStores {
+    /// Same as [`granted_groups`], but served from the stores
+    pub async fn granted_groups(
+        &self,
+        client: &kube::Client,
+        config: &Config,
+        namespace: &str,
+    ) -> Result<Option<BTreeSet<String>>, kube::Error> {
+        if config.policy.group_binding_namespaces.is_empty() {
+            return Ok(None);
+        }
+
+        // Before the initial list a missing binding would revoke the group. When a watch has
+        // stopped the store is as good as it gets
+        for store in &self.bindings {
+            let _ = store.wait_until_ready().await;
+        }
+
+        let labels = match self.namespaces.get(&ObjectRef::new(namespace)) {
+            Some(obj) => obj.labels().clone(),
+            // The namespace can be newer than the watch
+            None => namespace_labels(client, namespace).await?,
+        };
+
+        let bindings: Vec<_> = self.bindings.iter().flat_map(Store::state).collect();
+        let bindings = bindings.iter().map(AsRef::as_ref);
+
+        Ok(Some(granted_by(bindings, namespace, &labels)))
+    }
+}
+
+async fn namespace_labels(
+    client: &kube::Client,
+    namespace: &str,
+) -> Result<BTreeMap<String, String>, kube::Error> {
+    Ok(Api::<Namespace>::all(client.clone())
+        .get(namespace)
+        .await?
+        .labels()
+        .clone())
+}
+
+fn granted_by<'a>(
+    bindings: impl IntoIterator<Item = &'a GroupBinding>,
+    namespace: &str,
+    labels: &BTreeMap<String, String>,
+) -> BTreeSet<String> {
+    bindings
+        .into_iter()
+        .filter(|binding| binding.grants(namespace, labels))
+        .map(|binding| binding.spec.group.clone())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use kube::api::{ObjectMeta, PostParams};
+    use kube::runtime::{reflector, watcher};
+
+    use super::*;
+    use crate::testing::kube::FakeKube;
+
+    #[test]
+    fn grants_by_name_and_selector() {
+        let binding = GroupBinding::new(
+            "media",
+            GroupBindingSpec {
+                group: "media".into(),
+                namespaces: vec!["jellyfin".into()],
+                namespace_selector: Some(LabelSelector {
+                    match_labels: Some(BTreeMap::from([("team".into(), "media".into())])),
+                    ..Default::default()
+                }),
+            },
+        );
+
+        let no_labels = BTreeMap::new();
+        let media_labels = BTreeMap::from([("team".into(), "media".into())]);
+
+        assert!(binding.grants("jellyfin", &no_labels));
+        assert!(binding.grants("sonarr", &media_labels));
+        assert!(!binding.grants("default", &no_labels));
+    }
+
+    fn namespace(name: &str, labels: &[(&str, &str)]) -> Namespace {
+        Namespace {
+            metadata: ObjectMeta {
+                name: Some(name.into()),
+                labels: Some(
+                    labels
+                        .iter()
+                        .map(|(key, value)| (key.to_string(), value.to_string()))
+                        .collect(),
+                ),
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn grants_from_stores() {
+        let kube = FakeKube::default();
+        let client = kube.client();
+        let mut config = Config::default();
+        config.policy.group_binding_namespaces = vec!["lldap".into()];
+
+        // Created after the watch listed the namespaces, so only the api server knows it
+        Api::<Namespace>::all(client.clone())
+            .create(
+                &PostParams::default(),
+                &namespace("radarr", &[("team", "media")]),
+            )
+            .await
+            .unwrap();
+
+        let (namespaces, mut namespace_writer) = reflector::store();
+        namespace_writer.apply_watcher_event(&watcher::Event::Apply(namespace(
+            "sonarr",
+            &[("team", "media")],
+        )));
+        namespace_writer.apply_watcher_event(&watcher::Event::Apply(namespace("default", &[])));
+
+        let mut binding = GroupBinding::new(
+            "media",
+            GroupBindingSpec {
+                group: "media".into(),
+                namespaces: Vec::new(),
+                namespace_selector: Some(LabelSelector {
+                    match_labels: Some(BTreeMap::from([("team".into(), "media".into())])),
+                    ..Default::default()
+                }),
+            },
+        );
+        binding.metadata.namespace = Some("lldap".into());
+
+        let (bindings, mut binding_writer) = reflector::store();
+        binding_writer.apply_watcher_event(&watcher::Event::Init);
+        binding_writer.apply_watcher_event(&watcher::Event::InitApply(binding));
+        binding_writer.apply_watcher_event(&watcher::Event::InitDone);
+
+        let stores = GrantStores {
+            namespaces,
+            bindings: vec![bindings],
+        };
+
+        let media = Some(BTreeSet::from(["media".to_string()]));
+        for (namespace, expected) in [
+            ("sonarr", media.clone()),
+            ("radarr", media),
+            ("default", Some(BTreeSet::new())),
+        ] {
+            let granted = stores
+                .granted_groups(&client, &config, namespace)
+                .await
+                .unwrap();
+            assert_eq!(granted, expected, "{namespace}");
+        }
+
+        config.policy.group_binding_namespaces.clear();
+        let granted = stores
+            .granted_groups(&client, &config, "sonarr")
+            .await
+            .unwrap();
+        assert_eq!(granted, None);
+    }
+}
@@ -0,0 +1,73 @@
+use kube::CustomResource;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+#[derive(CustomResource, Deserialize, Serialize, Clone, Debug, JsonSchema)]
+#[kube(kind = "LldapServer", group = "lldap.huizinga.dev", version = "v1")]
+#[kube(
+    shortname = "lls",
+    doc = "Custom resource describing an LLDAP instance that ServiceUsers and Groups can reference",
+    printcolumn = r#"{"name":"Url", "type":"string", "jsonPath":".spec.url"}"#,
+    printcolumn = r#"{"name":"Age", "type":"date", "jsonPath":".metadata.creationTimestamp"}"#
+)]
+#[serde(rename_all = "camelCase")]
+pub struct LldapServerSpec {
+    pub url: String,
+    /// Secret containing the credentials of an LLDAP admin. Like all secrets of the LldapServer
+    /// it is read from the namespace of the controller, so the controller does not need access to
+    /// secrets in other namespaces
+    pub credentials: CredentialsSecretRef,
+    #[serde(default)]
+    pub tls: LldapServerTls,
+    /// Request timeout, defaults to the timeout of the controller configuration
+    pub timeout_secs: Option<u64>,
+    /// Connect timeout, defaults to the connect timeout of the controller configuration
+    pub connect_timeout_secs: Option<u64>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct CredentialsSecretRef {
+    pub name: String,
+    #[serde(default = "default_username_key")]
+    pub username_key: String,
+    #[serde(default = "default_password_key")]
+    pub password_key: String,
+}
+
+fn default_username_key() -> String {
+    "username".into()
+}
+
+fn default_password_key() -> String {
+    "password".into()
+}
+
+#[derive(Deserialize, Serialize, Clone, Default, Debug, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct LldapServerTls {
+    /// Secret containing additional CA certificates to trust
+    pub ca_secret_ref: Option<SecretKeyRef>,
+    /// Secret of type kubernetes.io/tls used as client certificate
+    pub client_cert_secret_ref: Option<SecretRef>,
+    #[serde(default)]
+    pub insecure_skip_verify: bool,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct SecretRef {
+    pub name: String,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct SecretKeyRef {
+    pub name: String,
+    #[serde(default = "default_ca_key")]
+    pub key: String,
+}
+
+fn default_ca_key() -> String {
+    "ca.crt".into()
+}
@@ -1,20 +1,33 @@
 mod group;
-mod service_user;
+mod group_binding;
+mod lldap_server;
+pub mod service_user;
 
 use core::fmt;
 use std::sync::Arc;
+use std::time::Duration;
 
+use chrono::Utc;
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::{Condition, Time};
+use kube::api::{Patch, PatchParams};
 use kube::runtime::controller::Action;
 use kube::runtime::finalizer;
 use kube::{Api, Resource, ResourceExt};
+use rand::Rng;
 use serde::Serialize;
 use serde::de::DeserializeOwned;
-use tracing::{debug, instrument};
+use serde_json::json;
+use tracing::{debug, instrument, trace, warn};
 
-pub use self::group::Group;
+pub use self::group::{Group, GroupSpec};
+pub use self::group_binding::{GrantStores, GroupBinding, granted_groups};
+pub use self::lldap_server::LldapServer;
 pub use self::service_user::ServiceUser;
-use crate::context::Context;
+use crate::config::Config;
+use crate::context::{Context, ControllerEvents};
+use crate::instances::{self, LldapInstance};
 use crate::lldap;
+use crate::validation::Violations;
 
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
@@ -28,6 +41,43 @@ pub enum Error {
     Finalizer(#[source] Box<finalizer::Error<Self>>),
     #[error("MissingObjectKey: {0}")]
     MissingObjectKey(&'static str),
+    #[error("LLDAP instance error: {0}")]
+    Instance(#[from] instances::Error),
+    #[error("Invalid spec: {0}")]
+    InvalidSpec(#[from] Violations),
+}
+
+impl Error {
+    /// Errors that will not go away by retrying, the object needs to change first
+    pub fn is_permanent(&self) -> bool {
+        match self {
+            Self::MissingObjectKey(_)
+            | Self::Instance(instances::Error::NoDefaultServer)
+            | Self::InvalidSpec(_) => true,
+            Self::Finalizer(error) => match error.as_ref() {
+                finalizer::Error::ApplyFailed(error) | finalizer::Error::CleanupFailed(error) => {
+                    error.is_permanent()
+                }
+                finalizer::Error::UnnamedObject | finalizer::Error::InvalidFinalizer => true,
+                finalizer::Error::AddFinalizer(_) | finalizer::Error::RemoveFinalizer(_) => false,
+            },
+            _ => false,
+        }
+    }
+
+    /// Errors that indicate that LLDAP itself is not reachable
+    pub fn is_lldap_unavailable(&self) -> bool {
+        match self {
+            Self::Lldap(error) => error.is_unavailable(),
+            Self::Finalizer(error) => match error.as_ref() {
+                finalizer::Error::ApplyFailed(error) | finalizer::Error::CleanupFailed(error) => {
+                    error.is_lldap_unavailable()
+                }
+                _ => false,
+            },
+            _ => false,
+        }
+    }
 }
 
 impl From<finalizer::Error<Self>> for Error {
@@ -38,29 +88,504 @@ impl From<finalizer::Error<Self>> for Error {
 
 type Result<T, E = Error> = std::result::Result<T, E>;
 
-trait Reconcile {
-    async fn reconcile(self: Arc<Self>, ctx: Arc<Context>) -> Result<Action>;
+trait Reconcile: Sized {
+    async fn reconcile(
+        self: Arc<Self>,
+        ctx: Arc<Context>,
+        instance: Arc<LldapInstance>,
+    ) -> Result<Action>;
+
+    async fn cleanup(
+        self: Arc<Self>,
+        ctx: Arc<Context>,
+        instance: Arc<LldapInstance>,
+    ) -> Result<Action>;
+
+    /// Name of the LldapServer the object belongs to, `None` for the default server
+    fn server_ref(&self) -> Option<&str>;
+
+    /// Api scoped to where the object lives
+    fn api(&self, client: kube::Client) -> Api<Self>;
+
+    fn conditions(&self) -> &[Condition];
+
+    /// Value of the reconcile-at annotation that was handled last
+    fn last_handled_reconcile_at(&self) -> Option<&str>;
+
+    /// Interval between reconciles when nothing changes, from the spec or the configuration
+    fn resync_interval(&self, config: &Config) -> Duration;
+}
+
+/// Requeue after the resync interval of the object, moved forward by a random part of the
+/// configured jitter
+fn resync<T: Reconcile>(obj: &T, config: &Config) -> Action {
+    let interval = obj.resync_interval(config);
+    let jitter = interval.mul_f64(f64::from(config.resync.jitter_percent) / 100.0);
+
+    Action::requeue(interval - rand::thread_rng().gen_range(Duration::ZERO..=jitter))
+}
+
+const LLDAP_REACHABLE: &str = "LldapReachable";
+const POLICY_COMPLIANT: &str = "PolicyCompliant";
+const PAUSED: &str = "Paused";
+
+/// Set to `"true"` to stop reconciling the object
+pub const PAUSED_ANNOTATION: &str = "lldap.huizinga.dev/paused";
+
+/// Set to a new value, usually the current time, to reconcile the object right away
+pub const RECONCILE_AT_ANNOTATION: &str = "lldap.huizinga.dev/reconcile-at";
+
+/// Value of the annotation if it differs from the value that was handled last
+fn requested<'a, T: Resource>(
+    obj: &'a T,
+    annotation: &str,
+    handled: Option<&str>,
+) -> Option<&'a str> {
+    obj.meta()
+        .annotations
+        .as_ref()?
+        .get(annotation)
+        .map(String::as_str)
+        .filter(|value| Some(*value) != handled)
+}
+
+/// Why the object is paused, `None` when it is not
+fn paused_reason<T: Resource>(obj: &T, config: &Config) -> Option<(&'static str, &'static str)> {
+    if config.pause.all {
+        Some((
+            "ControllerPaused",
+            "Reconciliation of all objects is paused",
+        ))
+    } else if obj
+        .meta()
+        .annotations
+        .as_ref()
+        .and_then(|annotations| annotations.get(PAUSED_ANNOTATION))
+        .is_some_and(|value| value == "true")
+    {
+        Some((
+            "AnnotationPaused",
+            "Reconciliation is paused by the paused annotation",
+        ))
+    } else {
+        None
+    }
+}
 
-    async fn cleanup(self: Arc<Self>, ctx: Arc<Context>) -> Result<Action>;
+fn new_condition<T: Resource>(
+    obj: &T,
+    type_: &str,
+    status: bool,
+    reason: &str,
+    message: String,
+) -> Condition {
+    Condition {
+        last_transition_time: Time(Utc::now()),
+        message,
+        observed_generation: obj.meta().generation,
+        reason: reason.into(),
+        status: if status { "True" } else { "False" }.into(),
+        type_: type_.into(),
+    }
+}
+
+fn lldap_reachable_condition<T: Resource>(
+    obj: &T,
+    instance: &LldapInstance,
+    reachable: bool,
+) -> Condition {
+    let server = instance.display_name();
+    if reachable {
+        new_condition(
+            obj,
+            LLDAP_REACHABLE,
+            true,
+            "Reachable",
+            format!("LLDAP server '{server}' is reachable"),
+        )
+    } else {
+        new_condition(
+            obj,
+            LLDAP_REACHABLE,
+            false,
+            "Unreachable",
+            format!("LLDAP server '{server}' is unreachable, waiting for it to recover"),
+        )
+    }
+}
+
+fn paused_condition<T: Resource>(obj: &T, reason: Option<(&str, &str)>) -> Condition {
+    match reason {
+        Some((reason, message)) => new_condition(obj, PAUSED, true, reason, message.into()),
+        None => new_condition(
+            obj,
+            PAUSED,
+            false,
+            "Resumed",
+            "Reconciliation is no longer paused".into(),
+        ),
+    }
+}
+
+fn policy_compliant_condition<T: Resource>(obj: &T, violations: Option<&Violations>) -> Condition {
+    match violations {
+        None => new_condition(
+            obj,
+            POLICY_COMPLIANT,
+            true,
+            "Compliant",
+            "All requested groups are allowed".into(),
+        ),
+        Some(violations) => new_condition(
+            obj,
+            POLICY_COMPLIANT,
+            false,
+            "PolicyViolation",
+            format!("Groups not allowed by the policy are ignored: {violations}"),
+        ),
+    }
+}
+
+/// Add or replace a condition in the list, returns if the condition changed
+fn merge_condition(conditions: &mut Vec<Condition>, condition: &Condition) -> bool {
+    match conditions.iter_mut().find(|c| c.type_ == condition.type_) {
+        Some(existing)
+            if existing.status == condition.status
+                && existing.reason == condition.reason
+                && existing.message == condition.message =>
+        {
+            false
+        }
+        Some(existing) => {
+            let last_transition_time = if existing.status == condition.status {
+                existing.last_transition_time.clone()
+            } else {
+                condition.last_transition_time.clone()
+            };
+
+            *existing = Condition {
+                last_transition_time,
+                ..condition.clone()
+            };
+
+            true
+        }
+        None => {
+            conditions.push(condition.clone());
+
+            true
+        }
+    }
+}
+
+/// Add or replace a condition in the status of the object, only patching when something changed.
+/// Returns if the condition changed.
+async fn set_condition<T>(obj: &T, ctx: &Context, condition: Condition) -> Result<bool>
+where
+    T: Resource<DynamicType = ()> + ResourceExt + Clone + DeserializeOwned + fmt::Debug + Reconcile,
+{
+    set_conditions(obj, ctx, &[condition]).await
+}
+
+/// Add or replace conditions in the status of the object with a single patch, only patching when
+/// something changed. Returns if any of the conditions changed.
+async fn set_conditions<T>(obj: &T, ctx: &Context, updates: &[Condition]) -> Result<bool>
+where
+    T: Resource<DynamicType = ()> + ResourceExt + Clone + DeserializeOwned + fmt::Debug + Reconcile,
+{
+    let mut conditions = obj.conditions().to_vec();
+    let changes: Vec<_> = updates
+        .iter()
+        .filter(|condition| merge_condition(&mut conditions, condition))
+        .map(|condition| {
+            let change = format!(
+                "set condition '{}' to '{}'",
+                condition.type_, condition.status
+            );
+            (condition, change)
+        })
+        .collect();
+    if changes.is_empty() {
+        return Ok(false);
+    }
+
+    if ctx.config.dry_run {
+        // Nothing is patched, so only the first reconcile to find a change counts it
+        let mut reported = false;
+        for (condition, change) in changes {
+            let subject = format!("condition '{}'", condition.type_);
+            reported |= report_dry_run(obj, ctx, &subject, &change).await?;
+        }
+
+        return Ok(reported);
+    }
+
+    // The list is replaced as a whole, so merge into the current conditions instead of the ones
+    // the reconcile started with, an earlier patch in the same reconcile would be lost otherwise
+    let api = obj.api(ctx.client.clone());
+    let current = api.get_status(&obj.name_any()).await?;
+    let mut conditions = current.conditions().to_vec();
+    let changed = updates.iter().fold(false, |changed, condition| {
+        merge_condition(&mut conditions, condition) || changed
+    });
+    if !changed {
+        return Ok(false);
+    }
+
+    trace!(name = obj.name_any(), "Updating conditions");
+    let status = json!({
+        "status": { "conditions": conditions }
+    });
+    api.patch_status(
+        &obj.name_any(),
+        &PatchParams::default(),
+        &Patch::Merge(&status),
+    )
+    .await?;
+
+    Ok(true)
+}
+
+/// Record in the status that the reconcile-at annotation was handled
+async fn reconcile_handled<T>(obj: &T, ctx: &Context, requested_at: &str) -> Result<()>
+where
+    T: Resource<DynamicType = ()> + ResourceExt + Clone + DeserializeOwned + fmt::Debug + Reconcile,
+{
+    if ctx.config.dry_run {
+        let change = format!("record handled reconcile-at '{requested_at}' in status");
+        report_dry_run(obj, ctx, "reconcile-at", &change).await?;
+
+        return Ok(());
+    }
+
+    let status = json!({
+        "status": { "lastHandledReconcileAt": requested_at }
+    });
+    obj.api(ctx.client.clone())
+        .patch_status(
+            &obj.name_any(),
+            &PatchParams::default(),
+            &Patch::Merge(&status),
+        )
+        .await?;
+
+    Ok(())
+}
+
+/// Log and publish an event for a change to the subject of the object that is not made because of
+/// dry run mode, unless the same change was reported last. Returns if the change was reported.
+async fn report_dry_run<T>(obj: &T, ctx: &Context, subject: &str, change: &str) -> Result<bool>
+where
+    T: Resource<DynamicType = ()>,
+{
+    let reported = ctx
+        .dry_run_reporter
+        .report(&obj.object_ref(&()), subject, change)
+        .await?;
+
+    Ok(reported)
+}
+
+/// Same as [`finalizer`], but only reporting that the finalizer would be added or removed
+async fn dry_run_finalizer<T>(
+    obj: Arc<T>,
+    ctx: Arc<Context>,
+    instance: Arc<LldapInstance>,
+) -> Result<Action>
+where
+    T: Resource<DynamicType = ()> + Reconcile,
+{
+    let has_finalizer = obj.finalizers().contains(&ctx.controller_name);
+
+    match (obj.meta().deletion_timestamp.is_some(), has_finalizer) {
+        (false, _) => {
+            if !has_finalizer {
+                let change = format!("add finalizer '{}'", ctx.controller_name);
+                report_dry_run(obj.as_ref(), &ctx, "finalizer", &change).await?;
+            }
+
+            obj.reconcile(ctx, instance).await
+        }
+        (true, true) => {
+            let action = obj.clone().cleanup(ctx.clone(), instance).await?;
+
+            let change = format!("remove finalizer '{}'", ctx.controller_name);
+            report_dry_run(obj.as_ref(), &ctx, "finalizer", &change).await?;
+
+            Ok(action)
+        }
+        // Without our finalizer there is nothing left to clean up
+        (true, false) => Ok(Action::await_change()),
+    }
+}
+
+/// Remove the finalizer of a deleted object without cleaning up LLDAP
+async fn skip_cleanup<T>(obj: &T, ctx: &Context, err: &instances::Error) -> Result<Action>
+where
+    T: Resource<DynamicType = ()>
+        + ResourceExt
+        + Clone
+        + DeserializeOwned
+        + fmt::Debug
+        + Sync
+        + Reconcile,
+{
+    if !obj.finalizers().contains(&ctx.controller_name) {
+        return Ok(Action::await_change());
+    }
+
+    warn!(
+        name = obj.name_any(),
+        "LLDAP instance is gone, removing finalizer without cleanup: {err}"
+    );
+
+    if ctx.config.dry_run {
+        let change = format!("remove finalizer '{}'", ctx.controller_name);
+        report_dry_run(obj, ctx, "finalizer", &change).await?;
+
+        return Ok(Action::await_change());
+    }
+
+    ctx.recorder.cleanup_skipped(obj, &err.to_string()).await?;
+
+    let finalizers: Vec<_> = obj
+        .finalizers()
+        .iter()
+        .filter(|finalizer| **finalizer != ctx.controller_name)
+        .collect();
+    // The resource version makes the patch fail if the finalizers changed in the meantime
+    let patch = json!({
+        "metadata": {
+            "finalizers": finalizers,
+            "resourceVersion": obj.resource_version(),
+        }
+    });
+    obj.api(ctx.client.clone())
+        .patch(
+            &obj.name_any(),
+            &PatchParams::default(),
+            &Patch::Merge(&patch),
+        )
+        .await?;
+
+    Ok(Action::await_change())
 }
 
 #[instrument(skip(obj, ctx))]
 pub async fn reconcile<T>(obj: Arc<T>, ctx: Arc<Context>) -> Result<Action>
 where
-    T: Resource + ResourceExt + Clone + Serialize + DeserializeOwned + fmt::Debug + Reconcile,
-    <T as Resource>::DynamicType: Default,
+    T: Resource<DynamicType = ()>
+        + ResourceExt
+        + Clone
+        + Serialize
+        + DeserializeOwned
+        + fmt::Debug
+        + Sync
+        + Reconcile,
 {
     debug!(name = obj.name_any(), "Reconcile");
 
-    let service_users = Api::<T>::all(ctx.client.clone());
+    let paused = paused_reason(obj.as_ref(), &ctx.config);
+    if let Some(reason) = paused {
+        let deleting = obj.meta().deletion_timestamp.is_some();
+        if !deleting || !ctx.config.pause.cleanup {
+            debug!(name = obj.name_any(), "Paused, skipping");
+
+            // Deleted objects can not be patched once the finalizer is gone
+            if !deleting {
+                set_condition(
+                    obj.as_ref(),
+                    &ctx,
+                    paused_condition(obj.as_ref(), Some(reason)),
+                )
+                .await?;
+            }
+
+            // Removing the annotation or restarting the controller triggers a new reconcile
+            return Ok(Action::await_change());
+        }
+    }
+
+    let reconcile_requested = requested(
+        obj.as_ref(),
+        RECONCILE_AT_ANNOTATION,
+        obj.last_handled_reconcile_at(),
+    )
+    .map(ToOwned::to_owned);
+    if let Some(requested_at) = &reconcile_requested {
+        debug!(name = obj.name_any(), requested_at, "Reconcile requested");
+
+        // Earlier failures should not delay the retry if this attempt fails as well
+        ctx.backoff.reset(obj.as_ref());
+    }
+
+    let instance = match ctx.instances.get(&ctx.client, obj.server_ref()).await {
+        Ok(instance) => instance,
+        // Nothing can be cleaned up without the instance, waiting for it would keep the object
+        // from being deleted forever
+        Err(err) if obj.meta().deletion_timestamp.is_some() && err.is_gone() => {
+            return skip_cleanup(obj.as_ref(), &ctx, &err).await;
+        }
+        Err(err) => return Err(err.into()),
+    };
+
+    // Deleting is destructive and a requested reconcile should see changes made directly in
+    // LLDAP, so neither works from cached users and groups
+    if obj.meta().deletion_timestamp.is_some() || reconcile_requested.is_some() {
+        ctx.cache.invalidate(&instance);
+    }
 
-    Ok(
-        finalizer(&service_users, &ctx.controller_name, obj, |event| async {
+    if instance.breaker.is_open() {
+        debug!(name = obj.name_any(), "LLDAP is unreachable, skipping");
+
+        let condition = lldap_reachable_condition(obj.as_ref(), &instance, false);
+        set_condition(obj.as_ref(), &ctx, condition).await?;
+
+        return Ok(Action::requeue(instance.breaker.probe_interval()));
+    }
+
+    let result = if ctx.config.dry_run {
+        dry_run_finalizer(obj.clone(), ctx.clone(), instance.clone()).await
+    } else {
+        let api = obj.api(ctx.client.clone());
+
+        finalizer(&api, &ctx.controller_name, obj.clone(), |event| async {
             match event {
-                finalizer::Event::Apply(obj) => obj.reconcile(ctx.clone()).await,
-                finalizer::Event::Cleanup(obj) => obj.cleanup(ctx.clone()).await,
+                finalizer::Event::Apply(obj) => obj.reconcile(ctx.clone(), instance.clone()).await,
+                finalizer::Event::Cleanup(obj) => obj.cleanup(ctx.clone(), instance.clone()).await,
             }
         })
-        .await?,
-    )
+        .await
+        .map_err(Error::from)
+    };
+
+    match &result {
+        Ok(_) => instance.breaker.record_success(),
+        Err(err) if err.is_lldap_unavailable() => instance.breaker.record_failure(),
+        Err(_) => {}
+    }
+    let action = result?;
+
+    ctx.backoff.reset(obj.as_ref());
+
+    if obj.meta().deletion_timestamp.is_none() {
+        let mut conditions = vec![lldap_reachable_condition(obj.as_ref(), &instance, true)];
+
+        // Objects that were never paused do not get the condition. If the object is older than
+        // the patch that paused it, that patch triggers another reconcile that resumes it
+        if obj
+            .conditions()
+            .iter()
+            .any(|condition| condition.type_ == PAUSED)
+        {
+            conditions.push(paused_condition(obj.as_ref(), None));
+        }
+        set_conditions(obj.as_ref(), &ctx, &conditions).await?;
+
+        if let Some(requested_at) = reconcile_requested {
+            reconcile_handled(obj.as_ref(), &ctx, &requested_at).await?;
+        }
+    }
+
+    Ok(action)
 }
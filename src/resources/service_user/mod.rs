@@ -0,0 +1,1375 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::str::from_utf8;
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use k8s_openapi::ByteString;
+use k8s_openapi::api::core::v1::Secret;
+use k8s_openapi::apiextensions_apiserver::pkg::apis::apiextensions::v1::{
+    CustomResourceConversion, CustomResourceDefinition, ServiceReference, WebhookClientConfig,
+    WebhookConversion,
+};
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::{Condition, OwnerReference};
+use kube::api::{ObjectMeta, Patch, PatchParams, PostParams};
+use kube::core::crd::merge_crds;
+use kube::runtime::controller::Action;
+use kube::{Api, CustomResource, CustomResourceExt, Resource, ResourceExt};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use tracing::{debug, trace, warn};
+
+use super::{
+    Error, Reconcile, Result, group_binding, policy_compliant_condition, report_dry_run, requested,
+    resync, set_condition,
+};
+use crate::backend::DirectoryBackend;
+use crate::config::Config;
+use crate::context::{Context, ControllerEvents, MANAGED_BY_LABEL};
+use crate::instances::LldapInstance;
+use crate::lldap;
+use crate::validation::{self, Violations};
+
+pub mod v1;
+
+#[derive(CustomResource, Deserialize, Serialize, Clone, Debug, JsonSchema)]
+#[kube(
+    kind = "ServiceUser",
+    group = "lldap.huizinga.dev",
+    version = "v2",
+    namespaced,
+    status = "ServiceUserStatus"
+)]
+#[kube(
+    shortname = "lsu",
+    doc = "Custom resource for managing Service Users inside of LLDAP",
+    printcolumn = r#"{"name":"Manager", "type":"boolean", "description":"Can the service user manage passwords", "jsonPath":".spec.passwordManager"}"#,
+    printcolumn = r#"{"name":"Password", "type":"date", "description":"Secret creation timestamp", "jsonPath":".status.secretCreated"}"#,
+    printcolumn = r#"{"name":"Age", "type":"date", "jsonPath":".metadata.creationTimestamp"}"#
+)]
+#[serde(rename_all = "camelCase")]
+pub struct ServiceUserSpec {
+    #[serde(default)]
+    pub password_manager: bool,
+    /// Groups the service user is a member of, next to the builtin group selected by
+    /// `passwordManager`
+    #[serde(default)]
+    pub groups: Vec<GroupRef>,
+    #[serde(default)]
+    pub secret_template: SecretTemplate,
+    /// Custom attributes of the user, attributes that are not listed are left untouched
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub attributes: BTreeMap<String, Vec<String>>,
+    /// Name of the LldapServer to create the user in, uses the default server if not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub server_ref: Option<String>,
+    /// Seconds between reconciles when nothing changes, overrides the controller configuration
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub resync_interval_secs: Option<u64>,
+}
+
+#[derive(Deserialize, Serialize, Clone, PartialEq, Eq, Debug, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct GroupRef {
+    /// Name of the group in LLDAP
+    pub name: String,
+}
+
+/// Shape of the secret that holds the credentials of the service user
+#[derive(Deserialize, Serialize, Clone, Default, PartialEq, Eq, Debug, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct SecretTemplate {
+    /// Name of the secret, defaults to `<name>-lldap-credentials`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub labels: BTreeMap<String, String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub annotations: BTreeMap<String, String>,
+}
+
+impl SecretTemplate {
+    pub fn secret_name(&self, name: &str) -> String {
+        self.name
+            .clone()
+            .unwrap_or_else(|| format!("{name}-lldap-credentials"))
+    }
+
+    /// Apply the labels and annotations of the template to the secret
+    fn apply(&self, secret: &mut Secret) {
+        secret.labels_mut().extend(self.labels.clone());
+        secret.annotations_mut().extend(self.annotations.clone());
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Default, Debug, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ServiceUserStatus {
+    pub secret_created: Option<DateTime<Utc>>,
+    /// When the password was last replaced because of the rotate-password-at annotation
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password_rotated: Option<DateTime<Utc>>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub conditions: Vec<Condition>,
+    /// Value of the reconcile-at annotation that was handled last
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_handled_reconcile_at: Option<String>,
+    /// Value of the rotate-password-at annotation that was handled last
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_handled_rotate_password_at: Option<String>,
+}
+
+/// Set to a new value, usually the current time, to generate a new password for the service user
+pub const ROTATE_PASSWORD_AT_ANNOTATION: &str = "lldap.huizinga.dev/rotate-password-at";
+
+fn generate_password(config: &Config) -> String {
+    config
+        .password
+        .generator()
+        .generate_one()
+        .expect("Settings should be valid")
+}
+
+fn new_secret(
+    username: &str,
+    oref: OwnerReference,
+    template: &SecretTemplate,
+    config: &Config,
+) -> Secret {
+    let mut contents = BTreeMap::new();
+    contents.insert("username".into(), username.into());
+    contents.insert("password".into(), generate_password(config));
+
+    let mut secret = Secret {
+        metadata: ObjectMeta {
+            owner_references: Some(vec![oref]),
+            labels: Some(BTreeMap::from([(
+                MANAGED_BY_LABEL.into(),
+                config.controller_name.clone(),
+            )])),
+            ..Default::default()
+        },
+        string_data: Some(contents),
+        ..Default::default()
+    };
+    template.apply(&mut secret);
+
+    secret
+}
+
+/// Name of the user in LLDAP
+pub fn format_username(name: &str, namespace: &str) -> String {
+    format!("{name}.{namespace}")
+}
+
+/// Split a username in the `<name>.<namespace>` format used for ServiceUsers
+pub fn split_username(username: &str) -> Option<(&str, &str)> {
+    username
+        .rsplit_once('.')
+        .filter(|(name, namespace)| !name.is_empty() && !namespace.is_empty())
+}
+
+/// CRD serving all versions, with v2 as storage version and the conversion webhook behind the
+/// service converting between them.
+///
+/// Without the webhook nothing converts objects that are still stored as v1, reading them as v2
+/// would prune their groups and the next reconcile would remove the user from all of them.
+pub fn crd(
+    config: &Config,
+    namespace: &str,
+    service: &str,
+) -> anyhow::Result<CustomResourceDefinition> {
+    if !config.webhook.enabled {
+        anyhow::bail!(
+            "Invalid value for 'webhook.enabled': the ServiceUser CRD needs the conversion webhook to read objects stored as v1"
+        );
+    }
+
+    let mut crd = merge_crds(vec![v1::ServiceUser::crd(), ServiceUser::crd()], "v2")
+        .expect("Versions should be compatible");
+
+    crd.spec.conversion = Some(CustomResourceConversion {
+        strategy: "Webhook".into(),
+        webhook: Some(WebhookConversion {
+            client_config: Some(WebhookClientConfig {
+                service: Some(ServiceReference {
+                    namespace: namespace.into(),
+                    name: service.into(),
+                    path: Some("/convert".into()),
+                    port: None,
+                }),
+                ..Default::default()
+            }),
+            conversion_review_versions: vec!["v1".into()],
+        }),
+    });
+    crd.annotations_mut().insert(
+        "cert-manager.io/inject-ca-from".into(),
+        format!("{namespace}/{service}"),
+    );
+
+    Ok(crd)
+}
+
+/// Convert a ServiceUser between api versions, used by the conversion webhook
+pub fn convert(object: Value, desired_api_version: &str) -> Result<Value, String> {
+    let v1 = v1::ServiceUser::api_version(&());
+    let v2 = ServiceUser::api_version(&());
+
+    let api_version = object
+        .get("apiVersion")
+        .and_then(Value::as_str)
+        .unwrap_or_default()
+        .to_owned();
+
+    let parse_error =
+        |err: serde_json::Error| format!("Failed to parse {api_version} ServiceUser: {err}");
+    let service_user: ServiceUser = if api_version == v1 {
+        serde_json::from_value::<v1::ServiceUser>(object)
+            .map_err(parse_error)?
+            .into()
+    } else if api_version == v2 {
+        serde_json::from_value(object).map_err(parse_error)?
+    } else {
+        return Err(format!("Unsupported apiVersion '{api_version}'"));
+    };
+
+    let converted = if desired_api_version == v1 {
+        serde_json::to_value(v1::ServiceUser::from(service_user))
+    } else if desired_api_version == v2 {
+        serde_json::to_value(service_user)
+    } else {
+        return Err(format!("Unsupported apiVersion '{desired_api_version}'"));
+    };
+
+    converted.map_err(|err| format!("Failed to serialize ServiceUser: {err}"))
+}
+
+impl ServiceUser {
+    /// Check the spec for anything LLDAP or the policy would reject
+    pub fn validate(&self, config: &Config) -> Result<(), Violations> {
+        let mut violations = Violations::default();
+
+        // The name is not known yet when it is generated by the api server
+        if let (Some(name), Some(namespace)) = (&self.metadata.name, &self.metadata.namespace) {
+            violations.check(
+                "metadata.name",
+                validation::username(&format_username(name, namespace)),
+            );
+        }
+
+        for (i, GroupRef { name: group }) in self.spec.groups.iter().enumerate() {
+            let field = format!("spec.groups[{i}].name");
+
+            violations.check(&field, validation::group_name(group));
+
+            if *group == config.groups.password_manager || *group == config.groups.strict_readonly {
+                violations.check(
+                    &field,
+                    Err(format!(
+                        "group '{group}' is managed through 'spec.passwordManager'"
+                    )),
+                );
+            }
+
+            if self.spec.groups[..i]
+                .iter()
+                .any(|other| other.name == *group)
+            {
+                violations.check(&field, Err(format!("group '{group}' is listed twice")));
+            }
+        }
+
+        if self.spec.resync_interval_secs == Some(0) {
+            violations.check(
+                "spec.resyncIntervalSecs",
+                Err("should be at least 1".into()),
+            );
+        }
+
+        violations.into_result()
+    }
+
+    /// Check that the policy allows the namespace of the service user to request its groups.
+    ///
+    /// `granted` are the groups the GroupBindings grant access to, `None` when GroupBindings are
+    /// not used or not known yet.
+    pub fn check_policy(
+        &self,
+        config: &Config,
+        granted: Option<&BTreeSet<String>>,
+    ) -> Result<(), Violations> {
+        let mut violations = Violations::default();
+
+        for (i, GroupRef { name: group }) in self.spec.groups.iter().enumerate() {
+            violations.check(
+                &format!("spec.groups[{i}].name"),
+                self.check_group(group, config, granted),
+            );
+        }
+
+        violations.into_result()
+    }
+
+    /// Groups the user should be a member of in LLDAP, groups the policy does not allow are left
+    /// out, which also removes the user from them if it was added before the policy changed
+    pub fn desired_groups(
+        &self,
+        config: &Config,
+        granted: Option<&BTreeSet<String>>,
+    ) -> Vec<String> {
+        let mut groups: Vec<_> = self
+            .spec
+            .groups
+            .iter()
+            .map(|group| &group.name)
+            .filter(|group| self.check_group(group, config, granted).is_ok())
+            .cloned()
+            .collect();
+        groups.push(if self.spec.password_manager {
+            config.groups.password_manager.clone()
+        } else {
+            config.groups.strict_readonly.clone()
+        });
+
+        groups
+    }
+
+    /// Make the user in LLDAP match the spec, returns true when the user had to be created.
+    ///
+    /// The password is left alone when it is not given.
+    pub async fn apply_lldap(
+        &self,
+        backend: &dyn DirectoryBackend,
+        config: &Config,
+        granted: Option<&BTreeSet<String>>,
+        password: Option<&str>,
+    ) -> lldap::Result<bool> {
+        let name = self.name_any();
+        let username = format_username(&name, &self.namespace().unwrap_or_default());
+
+        trace!(name, "Creating user if needed");
+        let (user, created) = match backend.get_user(&username).await? {
+            Some(user) => {
+                debug!(name, username, "User already exists");
+
+                (user, false)
+            }
+            None => {
+                debug!(name, username, "Creating new user");
+
+                (backend.create_user(&username).await?, true)
+            }
+        };
+
+        trace!(name, "Updating groups");
+        let groups = self.desired_groups(config, granted);
+        backend.update_user_groups(&user, &groups).await?;
+
+        if !self.spec.attributes.is_empty() {
+            trace!(name, "Updating attributes");
+            backend
+                .update_user_attributes(&username, &self.spec.attributes)
+                .await?;
+        }
+
+        if let Some(password) = password {
+            trace!(name, "Updating password");
+            backend.update_password(&username, password).await?;
+        }
+
+        Ok(created)
+    }
+
+    fn check_group(
+        &self,
+        group: &str,
+        config: &Config,
+        granted: Option<&BTreeSet<String>>,
+    ) -> Result<(), String> {
+        let namespace = self.metadata.namespace.as_deref();
+        validation::group_allowed(group, namespace, config)?;
+
+        match granted {
+            Some(granted) if !granted.contains(group) => Err(format!(
+                "no GroupBinding grants namespace '{}' access to group '{group}'",
+                namespace.unwrap_or_default()
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Reconcile for ServiceUser {
+    async fn reconcile(
+        self: Arc<Self>,
+        ctx: Arc<Context>,
+        instance: Arc<LldapInstance>,
+    ) -> Result<Action> {
+        let name = self
+            .metadata
+            .name
+            .clone()
+            .ok_or(Error::MissingObjectKey(".metadata.name"))?;
+        let namespace = self
+            .metadata
+            .namespace
+            .clone()
+            .ok_or(Error::MissingObjectKey(".metadata.namespace"))?;
+        let oref = self
+            .controller_owner_ref(&())
+            .expect("Field should populated by apiserver");
+
+        debug!(name, "Apply");
+
+        self.validate(&ctx.config)?;
+
+        let granted = match &ctx.grants {
+            Some(grants) => {
+                grants
+                    .granted_groups(&ctx.client, &ctx.config, &namespace)
+                    .await?
+            }
+            None => group_binding::granted_groups(&ctx.client, &ctx.config, &namespace).await?,
+        };
+        let violations = self.check_policy(&ctx.config, granted.as_ref()).err();
+        let condition = policy_compliant_condition(self.as_ref(), violations.as_ref());
+        if set_condition(self.as_ref(), &ctx, condition).await? {
+            if let Some(violations) = &violations {
+                warn!(name, "Policy violation: {violations}");
+                ctx.recorder
+                    .policy_violation(self.as_ref(), &violations.to_string())
+                    .await?;
+            }
+        }
+
+        let secret_name = self.spec.secret_template.secret_name(&name);
+        let username = format_username(&name, &namespace);
+
+        let client = &ctx.client;
+        let secrets = Api::<Secret>::namespaced(client.clone(), &namespace);
+
+        // The handled rotation is recorded on the secret together with the new password, the status
+        // is only consulted for secrets rotated before that
+        let handled_in_status = self
+            .status
+            .as_ref()
+            .and_then(|status| status.last_handled_rotate_password_at.as_deref());
+
+        // TODO: Potentially issue: someone modifies the secret and removes the pass
+        trace!(name, "Get or create secret");
+        let mut created = false;
+        let mut modified = false;
+        let mut rotated = false;
+        let mut rotation_requested = None;
+        let mut secret = secrets
+            .entry(&secret_name)
+            .await?
+            .and_modify(|secret| {
+                debug!(name, secret_name, "Secret already exists");
+                let before = secret.metadata.clone();
+
+                // Secrets created by older versions are missing the label we filter on
+                secret
+                    .labels_mut()
+                    .insert(MANAGED_BY_LABEL.into(), ctx.controller_name.clone());
+                self.spec.secret_template.apply(secret);
+
+                modified = secret.metadata != before;
+
+                let handled = secret
+                    .annotations()
+                    .get(ROTATE_PASSWORD_AT_ANNOTATION)
+                    .map(String::as_str)
+                    .or(handled_in_status);
+                rotation_requested =
+                    requested(self.as_ref(), ROTATE_PASSWORD_AT_ANNOTATION, handled);
+                if let Some(value) = rotation_requested {
+                    debug!(name, secret_name, "Rotating password");
+                    secret.data.get_or_insert_default().insert(
+                        "password".into(),
+                        ByteString(generate_password(&ctx.config).into_bytes()),
+                    );
+                    secret
+                        .annotations_mut()
+                        .insert(ROTATE_PASSWORD_AT_ANNOTATION.into(), value.into());
+                    rotated = true;
+                }
+            })
+            .or_insert(|| {
+                created = true;
+                debug!(name, secret_name, "Generating new secret");
+
+                let mut secret =
+                    new_secret(&username, oref, &self.spec.secret_template, &ctx.config);
+                // A new secret comes with a new password, so that handles the rotation as well
+                rotation_requested = requested(self.as_ref(), ROTATE_PASSWORD_AT_ANNOTATION, None);
+                if let Some(value) = rotation_requested {
+                    secret
+                        .annotations_mut()
+                        .insert(ROTATE_PASSWORD_AT_ANNOTATION.into(), value.into());
+                }
+
+                secret
+            });
+
+        trace!(name, "Committing secret");
+        secret
+            .commit(&PostParams {
+                // Let the api server validate the secret without storing it
+                dry_run: ctx.config.dry_run,
+                field_manager: Some(ctx.controller_name.clone()),
+            })
+            .await?;
+        let secret = secret;
+
+        if ctx.config.dry_run {
+            let change = if created {
+                Some(format!("create secret '{secret_name}'"))
+            } else if rotated {
+                Some(format!("rotate password in secret '{secret_name}'"))
+            } else if modified {
+                Some(format!("update secret '{secret_name}'"))
+            } else {
+                None
+            };
+            if let Some(change) = change {
+                report_dry_run(self.as_ref(), &ctx, "secret", &change).await?;
+            }
+        } else if created {
+            trace!(name, "Sending secret creating notification");
+            // The reason this is here instead of inside the or_insert is that we
+            // want to send the event _after_ it successfully committed.
+            // Also or_insert is not async!
+            ctx.recorder
+                .secret_created(self.as_ref(), secret.get())
+                .await?;
+        } else if rotated {
+            ctx.recorder
+                .password_rotated(self.as_ref(), secret.get())
+                .await?;
+        }
+
+        let backend = ctx.backend(&instance, self.as_ref()).await?;
+
+        let password = secret.get().data.as_ref().unwrap().get("password").unwrap();
+        let password = from_utf8(&password.0).unwrap();
+        let user_created = self
+            .apply_lldap(
+                backend.as_ref(),
+                &ctx.config,
+                granted.as_ref(),
+                Some(password),
+            )
+            .await?;
+        if user_created && !ctx.config.dry_run {
+            ctx.recorder.user_created(self.as_ref(), &username).await?;
+        }
+
+        let secret_created = secret
+            .get()
+            .meta()
+            .creation_timestamp
+            .as_ref()
+            .map(|ts| ts.0);
+        if ctx.config.dry_run {
+            let current = self
+                .status
+                .as_ref()
+                .and_then(|status| status.secret_created);
+            if current != secret_created || rotated {
+                report_dry_run(self.as_ref(), &ctx, "status", "update status").await?;
+            }
+
+            return Ok(resync(self.as_ref(), &ctx.config));
+        }
+
+        trace!(name, "Updating status");
+        let service_users = Api::<ServiceUser>::namespaced(client.clone(), &namespace);
+        let status = json!({
+            "status": ServiceUserStatus {
+                secret_created,
+                password_rotated: rotated.then(Utc::now),
+                last_handled_rotate_password_at: rotation_requested.map(ToOwned::to_owned),
+                ..Default::default()
+            }
+        });
+        service_users
+            .patch_status(&name, &PatchParams::default(), &Patch::Merge(&status))
+            .await?;
+
+        Ok(resync(self.as_ref(), &ctx.config))
+    }
+
+    async fn cleanup(
+        self: Arc<Self>,
+        ctx: Arc<Context>,
+        instance: Arc<LldapInstance>,
+    ) -> Result<Action> {
+        let name = self
+            .metadata
+            .name
+            .clone()
+            .ok_or(Error::MissingObjectKey(".metadata.name"))?;
+        let namespace = self
+            .metadata
+            .namespace
+            .clone()
+            .ok_or(Error::MissingObjectKey(".metadata.namespace"))?;
+
+        debug!(name, "Cleanup");
+
+        let username = format_username(&name, &namespace);
+
+        let backend = ctx.backend(&instance, self.as_ref()).await?;
+
+        trace!(name, username, "Deleting user");
+        if backend.delete_user(&username).await? {
+            if !ctx.config.dry_run {
+                ctx.recorder.user_deleted(self.as_ref(), &username).await?;
+            }
+        } else {
+            ctx.recorder
+                .user_not_found(self.as_ref(), &username)
+                .await?;
+            warn!(name, username, "User not found");
+        }
+
+        Ok(Action::await_change())
+    }
+
+    fn api(&self, client: kube::Client) -> Api<Self> {
+        Api::namespaced(
+            client,
+            self.metadata
+                .namespace
+                .as_deref()
+                .expect("ServiceUser is namespaced"),
+        )
+    }
+
+    fn server_ref(&self) -> Option<&str> {
+        self.spec.server_ref.as_deref()
+    }
+
+    fn conditions(&self) -> &[Condition] {
+        self.status
+            .as_ref()
+            .map(|status| status.conditions.as_slice())
+            .unwrap_or_default()
+    }
+
+    fn last_handled_reconcile_at(&self) -> Option<&str> {
+        self.status
+            .as_ref()
+            .and_then(|status| status.last_handled_reconcile_at.as_deref())
+    }
+
+    fn resync_interval(&self, config: &Config) -> Duration {
+        self.spec.resync_interval_secs.map_or_else(
+            || config.service_user_resync_interval(),
+            Duration::from_secs,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use kube::api::DeleteParams;
+
+    use super::*;
+    use crate::backend::{MemoryBackend, MemoryUser};
+    use crate::resources::{PAUSED_ANNOTATION, reconcile};
+    use crate::testing::kube::TestEnv;
+
+    fn service_user(groups: &[&str]) -> ServiceUser {
+        ServiceUser::new(
+            "grafana",
+            ServiceUserSpec {
+                password_manager: false,
+                groups: groups
+                    .iter()
+                    .map(|name| GroupRef {
+                        name: (*name).into(),
+                    })
+                    .collect(),
+                secret_template: Default::default(),
+                attributes: Default::default(),
+                server_ref: None,
+                resync_interval_secs: None,
+            },
+        )
+    }
+
+    /// Create the service user and reconcile until the finalizer is added and it is applied
+    async fn create(env: &TestEnv, service_user: &ServiceUser) -> Api<ServiceUser> {
+        let api = Api::<ServiceUser>::namespaced(env.ctx.client.clone(), "monitoring");
+        api.create(&PostParams::default(), service_user)
+            .await
+            .unwrap();
+
+        for _ in 0..2 {
+            reconcile(env.latest(&api, "grafana").await, env.ctx.clone())
+                .await
+                .unwrap();
+        }
+
+        api
+    }
+
+    fn secret_password(secret: &Secret) -> String {
+        let password = &secret.data.as_ref().unwrap()["password"];
+        String::from_utf8(password.0.clone()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn reconcile_creates_user_and_secret() {
+        let env = TestEnv::start(Config::default()).await;
+        env.lldap.state().add_group("monitoring");
+
+        let api = create(&env, &service_user(&["monitoring"])).await;
+
+        let service_user = api.get("grafana").await.unwrap();
+        assert_eq!(service_user.finalizers(), [env.ctx.controller_name.clone()]);
+        assert!(service_user.status.unwrap().secret_created.is_some());
+
+        let secret = Api::<Secret>::namespaced(env.ctx.client.clone(), "monitoring")
+            .get("grafana-lldap-credentials")
+            .await
+            .unwrap();
+        assert_eq!(
+            secret.data.as_ref().unwrap()["username"],
+            ByteString("grafana.monitoring".into())
+        );
+        assert_eq!(secret.labels()[MANAGED_BY_LABEL], env.ctx.controller_name);
+
+        assert_eq!(
+            env.lldap.state().user_groups("grafana.monitoring"),
+            ["lldap_strict_readonly".into(), "monitoring".into()].into()
+        );
+        assert_eq!(env.lldap.state().password_changes["grafana.monitoring"], 1);
+        assert_eq!(
+            env.kube.event_reasons(),
+            [
+                ("SecretCreated".into(), "grafana".into()),
+                ("UserCreated".into(), "grafana".into())
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn reconcile_with_memory_backend() {
+        let backend = Arc::new(MemoryBackend::default());
+        backend.create_group("lldap_strict_readonly").await.unwrap();
+        let env = TestEnv::start(Config::default())
+            .await
+            .with_backend(backend.clone());
+
+        create(&env, &service_user(&[])).await;
+
+        let secret = Api::<Secret>::namespaced(env.ctx.client.clone(), "monitoring")
+            .get("grafana-lldap-credentials")
+            .await
+            .unwrap();
+        assert_eq!(
+            backend.password("grafana.monitoring"),
+            Some(secret_password(&secret))
+        );
+        assert!(!env.lldap.state().users.contains_key("grafana.monitoring"));
+    }
+
+    #[tokio::test]
+    async fn dry_run_changes_nothing() {
+        let config = Config {
+            dry_run: true,
+            ..Default::default()
+        };
+        let env = TestEnv::start(config).await;
+        env.lldap.state().add_group("monitoring");
+
+        let api = Api::<ServiceUser>::namespaced(env.ctx.client.clone(), "monitoring");
+        api.create(&PostParams::default(), &service_user(&["monitoring"]))
+            .await
+            .unwrap();
+        reconcile(env.latest(&api, "grafana").await, env.ctx.clone())
+            .await
+            .unwrap();
+
+        let service_user = api.get("grafana").await.unwrap();
+        assert!(service_user.finalizers().is_empty());
+        assert!(service_user.status.is_none());
+
+        let secret = Api::<Secret>::namespaced(env.ctx.client.clone(), "monitoring")
+            .get_opt("grafana-lldap-credentials")
+            .await
+            .unwrap();
+        assert!(secret.is_none());
+        assert!(!env.lldap.state().users.contains_key("grafana.monitoring"));
+
+        assert_eq!(
+            dry_run_notes(&env),
+            [
+                "Would add finalizer 'lldap.huizinga.dev'",
+                "Would set condition 'PolicyCompliant' to 'True'",
+                "Would create secret 'grafana-lldap-credentials'",
+                "Would create user 'grafana.monitoring'",
+                "Would add user 'grafana.monitoring' to group 'monitoring'",
+                "Would add user 'grafana.monitoring' to group 'lldap_strict_readonly'",
+                "Would set password of user 'grafana.monitoring'",
+                "Would update status",
+                "Would set condition 'LldapReachable' to 'True'",
+            ]
+        );
+        let notes = dry_run_notes(&env);
+
+        // Every reconcile finds the same changes, they were already reported
+        env.kube.state().events.clear();
+        reconcile(env.latest(&api, "grafana").await, env.ctx.clone())
+            .await
+            .unwrap();
+        assert_eq!(dry_run_notes(&env), Vec::<String>::new());
+
+        // Until the object is deleted
+        env.ctx
+            .dry_run_reporter
+            .forget(&service_user.object_ref(&()));
+        reconcile(env.latest(&api, "grafana").await, env.ctx.clone())
+            .await
+            .unwrap();
+        assert_eq!(dry_run_notes(&env), notes);
+    }
+
+    #[tokio::test]
+    async fn dry_run_applied_user() {
+        let env = TestEnv::start(Config::default()).await;
+        let api = create(&env, &service_user(&[])).await;
+
+        let mut ctx = Context::clone(&env.ctx);
+        ctx.config = Arc::new(Config {
+            dry_run: true,
+            ..Default::default()
+        });
+        reconcile(env.latest(&api, "grafana").await, Arc::new(ctx))
+            .await
+            .unwrap();
+
+        // The password of an existing user can not be compared, so it is not reported every time
+        assert_eq!(dry_run_notes(&env), Vec::<String>::new());
+    }
+
+    fn dry_run_notes(env: &TestEnv) -> Vec<String> {
+        env.kube
+            .state()
+            .events
+            .iter()
+            .filter(|event| event.reason.as_deref() == Some("DryRun"))
+            .map(|event| event.note.clone().unwrap())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn reconcile_updates_groups() {
+        let env = TestEnv::start(Config::default()).await;
+        env.lldap.state().add_group("monitoring");
+        env.lldap.state().add_group("media");
+
+        let api = create(&env, &service_user(&["monitoring"])).await;
+        let secret_password_before = secret_password(
+            &Api::<Secret>::namespaced(env.ctx.client.clone(), "monitoring")
+                .get("grafana-lldap-credentials")
+                .await
+                .unwrap(),
+        );
+
+        let patch = json!({
+            "spec": { "passwordManager": true, "groups": [{ "name": "media" }] }
+        });
+        api.patch("grafana", &PatchParams::default(), &Patch::Merge(&patch))
+            .await
+            .unwrap();
+        reconcile(env.latest(&api, "grafana").await, env.ctx.clone())
+            .await
+            .unwrap();
+
+        assert_eq!(
+            env.lldap.state().user_groups("grafana.monitoring"),
+            ["lldap_password_manager".into(), "media".into()].into()
+        );
+
+        // The password is set again, but it does not change
+        let secret = Api::<Secret>::namespaced(env.ctx.client.clone(), "monitoring")
+            .get("grafana-lldap-credentials")
+            .await
+            .unwrap();
+        assert_eq!(secret_password(&secret), secret_password_before);
+        assert_eq!(env.kube.event_reasons().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn reconcile_keeps_existing_secret() {
+        let env = TestEnv::start(Config::default()).await;
+
+        let secret = Secret {
+            metadata: ObjectMeta {
+                name: Some("grafana-lldap-credentials".into()),
+                ..Default::default()
+            },
+            string_data: Some(BTreeMap::from([
+                ("username".into(), "grafana.monitoring".into()),
+                ("password".into(), "existing".into()),
+            ])),
+            ..Default::default()
+        };
+        let secrets = Api::<Secret>::namespaced(env.ctx.client.clone(), "monitoring");
+        secrets
+            .create(&PostParams::default(), &secret)
+            .await
+            .unwrap();
+
+        create(&env, &service_user(&[])).await;
+
+        // Secrets created by older versions get the label, but keep their password
+        let secret = secrets.get("grafana-lldap-credentials").await.unwrap();
+        assert_eq!(secret_password(&secret), "existing");
+        assert_eq!(secret.labels()[MANAGED_BY_LABEL], env.ctx.controller_name);
+        assert_eq!(
+            env.kube.event_reasons(),
+            [("UserCreated".into(), "grafana".into())]
+        );
+    }
+
+    #[tokio::test]
+    async fn reconcile_adopts_existing_user() {
+        let env = TestEnv::start(Config::default()).await;
+        {
+            let mut state = env.lldap.state();
+            let old = state.add_group("old");
+            state.users.insert(
+                "grafana.monitoring".into(),
+                MemoryUser {
+                    groups: [old].into(),
+                    ..Default::default()
+                },
+            );
+        }
+
+        create(&env, &service_user(&[])).await;
+
+        assert_eq!(
+            env.lldap.state().user_groups("grafana.monitoring"),
+            ["lldap_strict_readonly".into()].into()
+        );
+        assert_eq!(env.lldap.state().password_changes["grafana.monitoring"], 1);
+        assert_eq!(
+            env.kube.event_reasons(),
+            [("SecretCreated".into(), "grafana".into())]
+        );
+    }
+
+    #[tokio::test]
+    async fn resync_lists_users_and_groups_once() {
+        let env = TestEnv::start(Config::default()).await;
+        env.lldap.state().add_group("monitoring");
+
+        let api = Api::<ServiceUser>::namespaced(env.ctx.client.clone(), "monitoring");
+        let names = ["grafana", "loki", "tempo"];
+        for name in names {
+            let mut service_user = service_user(&["monitoring"]);
+            service_user.metadata.name = Some(name.into());
+            api.create(&PostParams::default(), &service_user)
+                .await
+                .unwrap();
+        }
+
+        let instance = env.ctx.instances.default_instance().unwrap();
+        env.ctx.cache.invalidate(&instance);
+        env.lldap.state().operations.clear();
+        for _ in 0..2 {
+            for name in names {
+                reconcile(env.latest(&api, name).await, env.ctx.clone())
+                    .await
+                    .unwrap();
+            }
+        }
+
+        assert_eq!(
+            env.lldap.state().user_groups("tempo.monitoring"),
+            ["lldap_strict_readonly".into(), "monitoring".into()].into()
+        );
+        let count = |operation: &str| {
+            env.lldap
+                .state()
+                .operations
+                .iter()
+                .filter(|executed| *executed == operation)
+                .count()
+        };
+        assert_eq!(count("ListUsers"), 1);
+        assert_eq!(count("GetGroups"), 1);
+        assert_eq!(count("GetUser"), 0);
+    }
+
+    #[tokio::test]
+    async fn deletion_removes_user() {
+        let env = TestEnv::start(Config::default()).await;
+        let api = create(&env, &service_user(&[])).await;
+
+        api.delete("grafana", &DeleteParams::default())
+            .await
+            .unwrap();
+        reconcile(env.latest(&api, "grafana").await, env.ctx.clone())
+            .await
+            .unwrap();
+
+        assert!(api.get_opt("grafana").await.unwrap().is_none());
+        assert!(!env.lldap.state().users.contains_key("grafana.monitoring"));
+        assert_eq!(
+            env.kube.event_reasons().last().unwrap(),
+            &("UserDeleted".into(), "grafana".into())
+        );
+    }
+
+    #[tokio::test]
+    async fn deletion_when_user_is_gone() {
+        let env = TestEnv::start(Config::default()).await;
+        let api = create(&env, &service_user(&[])).await;
+        env.lldap.state().users.remove("grafana.monitoring");
+
+        api.delete("grafana", &DeleteParams::default())
+            .await
+            .unwrap();
+        reconcile(env.latest(&api, "grafana").await, env.ctx.clone())
+            .await
+            .unwrap();
+
+        // The finalizer is still removed, otherwise the object would never go away
+        assert!(api.get_opt("grafana").await.unwrap().is_none());
+        assert_eq!(
+            env.kube.event_reasons().last().unwrap(),
+            &("UserNotFound".into(), "grafana".into())
+        );
+    }
+
+    #[tokio::test]
+    async fn rotate_password_on_request() {
+        let env = TestEnv::start(Config::default()).await;
+        let api = create(&env, &service_user(&[])).await;
+        let secrets = Api::<Secret>::namespaced(env.ctx.client.clone(), "monitoring");
+        let password_before =
+            secret_password(&secrets.get("grafana-lldap-credentials").await.unwrap());
+
+        let patch = json!({
+            "metadata": { "annotations": { ROTATE_PASSWORD_AT_ANNOTATION: "1" } }
+        });
+        api.patch("grafana", &PatchParams::default(), &Patch::Merge(&patch))
+            .await
+            .unwrap();
+        for _ in 0..2 {
+            reconcile(env.latest(&api, "grafana").await, env.ctx.clone())
+                .await
+                .unwrap();
+        }
+
+        // Only rotated once, the second reconcile sees that the request was handled
+        let password = secret_password(&secrets.get("grafana-lldap-credentials").await.unwrap());
+        assert_ne!(password, password_before);
+        let status = api.get("grafana").await.unwrap().status.unwrap();
+        assert!(status.password_rotated.is_some());
+        assert_eq!(status.last_handled_rotate_password_at.as_deref(), Some("1"));
+        assert_eq!(
+            env.kube
+                .event_reasons()
+                .iter()
+                .filter(|(reason, _)| reason == "PasswordRotated")
+                .count(),
+            1
+        );
+
+        // The handled value is stored with the password, so a lost status update does not rotate again
+        let patch = json!({ "status": { "lastHandledRotatePasswordAt": null } });
+        api.patch_status("grafana", &PatchParams::default(), &Patch::Merge(&patch))
+            .await
+            .unwrap();
+        reconcile(env.latest(&api, "grafana").await, env.ctx.clone())
+            .await
+            .unwrap();
+        let password_after =
+            secret_password(&secrets.get("grafana-lldap-credentials").await.unwrap());
+        assert_eq!(password_after, password);
+    }
+
+    fn condition(service_user: &ServiceUser, type_: &str) -> Option<(String, String)> {
+        service_user
+            .conditions()
+            .iter()
+            .find(|condition| condition.type_ == type_)
+            .map(|condition| (condition.status.clone(), condition.reason.clone()))
+    }
+
+    #[tokio::test]
+    async fn paused_skips_apply() {
+        let env = TestEnv::start(Config::default()).await;
+        let api = create(&env, &service_user(&[])).await;
+
+        let patch = json!({
+            "metadata": { "annotations": { PAUSED_ANNOTATION: "true" } },
+            "spec": { "passwordManager": true }
+        });
+        api.patch("grafana", &PatchParams::default(), &Patch::Merge(&patch))
+            .await
+            .unwrap();
+        reconcile(env.latest(&api, "grafana").await, env.ctx.clone())
+            .await
+            .unwrap();
+
+        assert_eq!(
+            env.lldap.state().user_groups("grafana.monitoring"),
+            ["lldap_strict_readonly".into()].into()
+        );
+        assert_eq!(
+            condition(&api.get("grafana").await.unwrap(), "Paused"),
+            Some(("True".into(), "AnnotationPaused".into()))
+        );
+
+        let patch = json!({ "metadata": { "annotations": { PAUSED_ANNOTATION: null } } });
+        api.patch("grafana", &PatchParams::default(), &Patch::Merge(&patch))
+            .await
+            .unwrap();
+        reconcile(env.latest(&api, "grafana").await, env.ctx.clone())
+            .await
+            .unwrap();
+
+        assert_eq!(
+            env.lldap.state().user_groups("grafana.monitoring"),
+            ["lldap_password_manager".into()].into()
+        );
+        let service_user = api.get("grafana").await.unwrap();
+        assert_eq!(
+            condition(&service_user, "Paused"),
+            Some(("False".into(), "Resumed".into()))
+        );
+        assert_eq!(
+            condition(&service_user, "LldapReachable"),
+            Some(("True".into(), "Reachable".into()))
+        );
+    }
+
+    #[tokio::test]
+    async fn policy_violation_reported_once() {
+        let mut config = Config::default();
+        config
+            .policy
+            .allowed_namespaces
+            .insert("media".into(), vec!["media".into()]);
+        let env = TestEnv::start(config).await;
+        env.lldap.state().add_group("media");
+
+        let api = create(&env, &service_user(&["media"])).await;
+        reconcile(env.latest(&api, "grafana").await, env.ctx.clone())
+            .await
+            .unwrap();
+
+        let service_user = api.get("grafana").await.unwrap();
+        assert_eq!(
+            condition(&service_user, "PolicyCompliant"),
+            Some(("False".into(), "PolicyViolation".into()))
+        );
+        assert_eq!(
+            condition(&service_user, "LldapReachable"),
+            Some(("True".into(), "Reachable".into()))
+        );
+        let violations = env
+            .kube
+            .event_reasons()
+            .into_iter()
+            .filter(|(reason, _)| reason == "PolicyViolation")
+            .count();
+        assert_eq!(violations, 1);
+    }
+
+    #[tokio::test]
+    async fn delete_after_server() {
+        let env = TestEnv::start(Config::default()).await;
+        let servers = env.create_server("secondary").await;
+        let mut service_user = service_user(&[]);
+        service_user.spec.server_ref = Some("secondary".into());
+        let api = create(&env, &service_user).await;
+
+        servers
+            .delete("secondary", &DeleteParams::default())
+            .await
+            .unwrap();
+        api.delete("grafana", &DeleteParams::default())
+            .await
+            .unwrap();
+        reconcile(env.latest(&api, "grafana").await, env.ctx.clone())
+            .await
+            .unwrap();
+
+        assert!(api.get_opt("grafana").await.unwrap().is_none());
+        assert!(
+            env.kube
+                .event_reasons()
+                .contains(&("CleanupSkipped".into(), "grafana".into()))
+        );
+        // The user can not be removed without the server
+        assert!(env.lldap.state().users.contains_key("grafana.monitoring"));
+    }
+
+    #[tokio::test]
+    async fn delete_without_server_secret() {
+        let env = TestEnv::start(Config::default()).await;
+        env.create_server("secondary").await;
+        let mut service_user = service_user(&[]);
+        service_user.spec.server_ref = Some("secondary".into());
+        let api = create(&env, &service_user).await;
+
+        Api::<Secret>::default_namespaced(env.ctx.client.clone())
+            .delete("secondary", &DeleteParams::default())
+            .await
+            .unwrap();
+        api.delete("grafana", &DeleteParams::default())
+            .await
+            .unwrap();
+        assert!(
+            reconcile(env.latest(&api, "grafana").await, env.ctx.clone())
+                .await
+                .is_err()
+        );
+
+        // The server still exists, so the cleanup is retried instead of skipped
+        assert!(api.get_opt("grafana").await.unwrap().is_some());
+        assert!(env.lldap.state().users.contains_key("grafana.monitoring"));
+    }
+
+    #[tokio::test]
+    async fn paused_controller_still_cleans_up() {
+        let env = TestEnv::start(Config::default()).await;
+        let api = create(&env, &service_user(&[])).await;
+
+        let mut config = Config::clone(&env.ctx.config);
+        config.pause.all = true;
+        let env = TestEnv {
+            ctx: Arc::new(Context {
+                config: Arc::new(config),
+                ..Context::clone(&env.ctx)
+            }),
+            ..env
+        };
+
+        reconcile(env.latest(&api, "grafana").await, env.ctx.clone())
+            .await
+            .unwrap();
+        assert_eq!(
+            condition(&api.get("grafana").await.unwrap(), "Paused"),
+            Some(("True".into(), "ControllerPaused".into()))
+        );
+
+        api.delete("grafana", &DeleteParams::default())
+            .await
+            .unwrap();
+        reconcile(env.latest(&api, "grafana").await, env.ctx.clone())
+            .await
+            .unwrap();
+
+        assert!(api.get_opt("grafana").await.unwrap().is_none());
+        assert!(!env.lldap.state().users.contains_key("grafana.monitoring"));
+    }
+
+    #[test]
+    fn resync_interval_override() {
+        let mut config = Config::default();
+        config.resync.service_user_secs = Some(600);
+
+        let mut service_user = service_user(&[]);
+        assert_eq!(
+            service_user.resync_interval(&config),
+            Duration::from_secs(600)
+        );
+
+        service_user.spec.resync_interval_secs = Some(60);
+        assert_eq!(
+            service_user.resync_interval(&config),
+            Duration::from_secs(60)
+        );
+    }
+
+    #[test]
+    fn service_user_crd_output() {
+        let mut config = Config::default();
+        config.webhook.enabled = true;
+        insta::assert_yaml_snapshot!(crd(&config, "lldap", "lldap-controller-webhook").unwrap());
+    }
+
+    #[test]
+    fn service_user_crd_needs_webhook() {
+        let err = crd(&Config::default(), "lldap", "lldap-controller-webhook").unwrap_err();
+        assert!(err.to_string().contains("webhook.enabled"), "{err}");
+    }
+
+    #[test]
+    fn read_stored_v1_object() {
+        // As stored by a version before v2, including the annotations of a converted v2 object
+        let stored = json!({
+            "apiVersion": "lldap.huizinga.dev/v1",
+            "kind": "ServiceUser",
+            "metadata": {
+                "name": "app",
+                "namespace": "default",
+                "annotations": {
+                    "lldap.huizinga.dev/v2-attributes": r#"{"department":["media"]}"#,
+                    "lldap.huizinga.dev/v2-resync-interval-secs": "300",
+                }
+            },
+            "spec": {
+                "passwordManager": false,
+                "additionalGroups": ["media", "monitoring"]
+            }
+        });
+
+        let object = convert(stored, "lldap.huizinga.dev/v2").unwrap();
+        let service_user: ServiceUser = serde_json::from_value(object).unwrap();
+        let groups: Vec<_> = service_user
+            .spec
+            .groups
+            .iter()
+            .map(|group| group.name.as_str())
+            .collect();
+        assert_eq!(groups, ["media", "monitoring"]);
+        assert_eq!(
+            service_user.spec.attributes,
+            BTreeMap::from([("department".into(), vec!["media".into()])])
+        );
+        assert_eq!(service_user.spec.resync_interval_secs, Some(300));
+        assert!(service_user.annotations().is_empty());
+    }
+
+    #[test]
+    fn conversion_roundtrips() {
+        let mut service_user = ServiceUser::new(
+            "app",
+            ServiceUserSpec {
+                password_manager: true,
+                groups: vec![GroupRef {
+                    name: "media".into(),
+                }],
+                secret_template: SecretTemplate {
+                    name: Some("app-credentials".into()),
+                    ..Default::default()
+                },
+                attributes: BTreeMap::from([("department".into(), vec!["media".into()])]),
+                server_ref: None,
+                resync_interval_secs: Some(300),
+            },
+        );
+        service_user.metadata.namespace = Some("default".into());
+        let object = serde_json::to_value(&service_user).unwrap();
+
+        let old = convert(object.clone(), "lldap.huizinga.dev/v1").unwrap();
+        assert_eq!(old["spec"]["additionalGroups"], json!(["media"]));
+
+        let new = convert(old, "lldap.huizinga.dev/v2").unwrap();
+        assert_eq!(new, object);
+    }
+}
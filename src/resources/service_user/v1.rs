@@ -0,0 +1,142 @@
+use kube::{CustomResource, ResourceExt};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use super::{GroupRef, SecretTemplate, ServiceUserStatus};
+
+/// v1 has no place for the secret template, it is kept in this annotation so converting to v1 and
+/// back does not lose it
+const SECRET_TEMPLATE_ANNOTATION: &str = "lldap.huizinga.dev/v2-secret-template";
+/// Same for the attributes
+const ATTRIBUTES_ANNOTATION: &str = "lldap.huizinga.dev/v2-attributes";
+/// And the resync interval
+const RESYNC_INTERVAL_ANNOTATION: &str = "lldap.huizinga.dev/v2-resync-interval-secs";
+
+// The original version of the ServiceUser, only served so existing objects keep working
+#[derive(CustomResource, Deserialize, Serialize, Clone, Debug, JsonSchema)]
+#[kube(
+    kind = "ServiceUser",
+    group = "lldap.huizinga.dev",
+    version = "v1",
+    namespaced,
+    status = "ServiceUserStatus"
+)]
+#[kube(
+    shortname = "lsu",
+    deprecated = "lldap.huizinga.dev/v1 ServiceUser is deprecated, use lldap.huizinga.dev/v2",
+    doc = "Custom resource for managing Service Users inside of LLDAP",
+    printcolumn = r#"{"name":"Manager", "type":"boolean", "description":"Can the service user manage passwords", "jsonPath":".spec.passwordManager"}"#,
+    printcolumn = r#"{"name":"Password", "type":"date", "description":"Secret creation timestamp", "jsonPath":".status.secretCreated"}"#,
+    printcolumn = r#"{"name":"Age", "type":"date", "jsonPath":".metadata.creationTimestamp"}"#
+)]
+#[serde(rename_all = "camelCase")]
+pub struct ServiceUserSpec {
+    #[serde(default)]
+    pub password_manager: bool,
+    #[serde(default)]
+    pub additional_groups: Vec<String>,
+    /// Name of the LldapServer to create the user in, uses the default server if not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub server_ref: Option<String>,
+}
+
+/// Path of a v2 field in the v1 object it was converted from
+pub fn field_path(field: &str) -> String {
+    let annotation = |key| format!("metadata.annotations[{key}]");
+
+    if let Some(index) = field
+        .strip_prefix("spec.groups[")
+        .and_then(|rest| rest.strip_suffix("].name"))
+    {
+        format!("spec.additionalGroups[{index}]")
+    } else if field.starts_with("spec.secretTemplate") {
+        annotation(SECRET_TEMPLATE_ANNOTATION)
+    } else if field.starts_with("spec.attributes") {
+        annotation(ATTRIBUTES_ANNOTATION)
+    } else if field == "spec.resyncIntervalSecs" {
+        annotation(RESYNC_INTERVAL_ANNOTATION)
+    } else {
+        field.to_owned()
+    }
+}
+
+impl From<ServiceUser> for super::ServiceUser {
+    fn from(mut old: ServiceUser) -> Self {
+        let mut take_annotation = |key| {
+            old.metadata
+                .annotations
+                .as_mut()
+                .and_then(|annotations| annotations.remove(key))
+        };
+        let secret_template = take_annotation(SECRET_TEMPLATE_ANNOTATION)
+            .and_then(|template| serde_json::from_str(&template).ok())
+            .unwrap_or_default();
+        let attributes = take_annotation(ATTRIBUTES_ANNOTATION)
+            .and_then(|attributes| serde_json::from_str(&attributes).ok())
+            .unwrap_or_default();
+        let resync_interval_secs =
+            take_annotation(RESYNC_INTERVAL_ANNOTATION).and_then(|secs| secs.parse().ok());
+        // Converting has to give back the exact same metadata
+        if old.annotations().is_empty() {
+            old.metadata.annotations = None;
+        }
+
+        let spec = super::ServiceUserSpec {
+            password_manager: old.spec.password_manager,
+            groups: old
+                .spec
+                .additional_groups
+                .into_iter()
+                .map(|name| GroupRef { name })
+                .collect(),
+            secret_template,
+            attributes,
+            server_ref: old.spec.server_ref,
+            resync_interval_secs,
+        };
+
+        Self {
+            metadata: old.metadata,
+            spec,
+            status: old.status,
+        }
+    }
+}
+
+impl From<super::ServiceUser> for ServiceUser {
+    fn from(mut new: super::ServiceUser) -> Self {
+        if new.spec.secret_template != SecretTemplate::default() {
+            let template = serde_json::to_string(&new.spec.secret_template)
+                .expect("Secret template should serialize");
+            new.annotations_mut()
+                .insert(SECRET_TEMPLATE_ANNOTATION.into(), template);
+        }
+        if !new.spec.attributes.is_empty() {
+            let attributes =
+                serde_json::to_string(&new.spec.attributes).expect("Attributes should serialize");
+            new.annotations_mut()
+                .insert(ATTRIBUTES_ANNOTATION.into(), attributes);
+        }
+        if let Some(secs) = new.spec.resync_interval_secs {
+            new.annotations_mut()
+                .insert(RESYNC_INTERVAL_ANNOTATION.into(), secs.to_string());
+        }
+
+        let spec = ServiceUserSpec {
+            password_manager: new.spec.password_manager,
+            additional_groups: new
+                .spec
+                .groups
+                .into_iter()
+                .map(|group| group.name)
+                .collect(),
+            server_ref: new.spec.server_ref,
+        };
+
+        Self {
+            metadata: new.metadata,
+            spec,
+            status: new.status,
+        }
+    }
+}
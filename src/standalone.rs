@@ -0,0 +1,303 @@
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use kube::ResourceExt;
+use tracing::{debug, info, warn};
+
+use crate::backend::DirectoryBackend;
+use crate::config::Config;
+use crate::documents::Documents;
+use crate::lldap::LldapConfig;
+use crate::resources::ServiceUser;
+use crate::resources::service_user::format_username;
+
+/// Where the credentials of the service users are kept when there are no Kubernetes secrets
+#[derive(Debug, Clone)]
+pub enum CredentialsOutput {
+    /// Every secret becomes a `<namespace>/<secret name>` directory containing a `username` and
+    /// `password` file, laid out like a mounted secret
+    Directory(PathBuf),
+    /// Print the credentials of newly created users, existing users keep their password
+    Stdout,
+}
+
+fn write_file(path: &Path, contents: &str) -> anyhow::Result<()> {
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+    options
+        .open(path)
+        .and_then(|mut file| file.write_all(contents.as_bytes()))
+        .with_context(|| format!("Failed to write file '{}'", path.display()))
+}
+
+impl CredentialsOutput {
+    /// The password to set for the user, a stored password is reused and a new one is generated
+    /// and stored otherwise
+    fn password(
+        &self,
+        service_user: &ServiceUser,
+        username: &str,
+        config: &Config,
+    ) -> anyhow::Result<Option<String>> {
+        let Self::Directory(directory) = self else {
+            return Ok(None);
+        };
+
+        let secret_name = service_user
+            .spec
+            .secret_template
+            .secret_name(&service_user.name_any());
+        let secret_dir = directory
+            .join(service_user.namespace().unwrap_or_default())
+            .join(secret_name);
+        let password_file = secret_dir.join("password");
+
+        if password_file.exists() {
+            let password = std::fs::read_to_string(&password_file)
+                .with_context(|| format!("Failed to read file '{}'", password_file.display()))?;
+
+            // Files edited by hand usually end with a newline that is not part of the password
+            return Ok(Some(password.trim_end_matches(['\n', '\r']).to_owned()));
+        }
+
+        debug!(username, "Generating new password");
+        let password = config
+            .password
+            .generator()
+            .generate_one()
+            .expect("Settings should be valid");
+
+        std::fs::create_dir_all(&secret_dir)
+            .with_context(|| format!("Failed to create directory '{}'", secret_dir.display()))?;
+        write_file(&secret_dir.join("username"), username)?;
+        write_file(&password_file, &password)?;
+
+        Ok(Some(password))
+    }
+}
+
+/// Reconcile the resources in the files into the LLDAP server from the configuration once
+pub async fn sync(
+    config: &Config,
+    paths: &[PathBuf],
+    default_namespace: &str,
+    credentials: &CredentialsOutput,
+) -> anyhow::Result<()> {
+    let documents = Documents::read(paths, default_namespace)?;
+    let lldap_client = LldapConfig::new(&config.lldap)?.build_client().await?;
+
+    let mut failed = 0;
+
+    for group in &documents.groups {
+        let name = group.name_any();
+
+        if let Some(server_ref) = &group.spec.server_ref {
+            warn!(
+                name,
+                server_ref, "Skipping group, only the default server is used"
+            );
+            continue;
+        }
+
+        if let Err(violations) = group.validate(config) {
+            warn!(name, "Skipping invalid group: {violations}");
+            failed += 1;
+            continue;
+        }
+
+        match group.apply_lldap(&lldap_client).await {
+            Ok(true) => info!(name, "Created group"),
+            Ok(false) => debug!(name, "Group is up to date"),
+            Err(err) => {
+                warn!(name, "Failed to apply group: {err}");
+                failed += 1;
+            }
+        }
+    }
+
+    for service_user in &documents.service_users {
+        let name = service_user.name_any();
+        let namespace = service_user.namespace().unwrap_or_default();
+        let username = format_username(&name, &namespace);
+
+        if let Some(server_ref) = &service_user.spec.server_ref {
+            warn!(
+                username,
+                server_ref, "Skipping service user, only the default server is used"
+            );
+            continue;
+        }
+
+        if let Err(violations) = service_user.validate(config) {
+            warn!(username, "Skipping invalid service user: {violations}");
+            failed += 1;
+            continue;
+        }
+
+        // Groups that are not allowed are left out, like in the controller
+        let granted = documents.granted_groups(config, &namespace);
+        if let Err(violations) = service_user.check_policy(config, granted.as_ref()) {
+            warn!(username, "Policy violation: {violations}");
+        }
+
+        let stored_password = match credentials.password(service_user, &username, config) {
+            Ok(password) => password,
+            Err(err) => {
+                warn!(username, "Failed to store credentials: {err:#}");
+                failed += 1;
+                continue;
+            }
+        };
+
+        // Only new users get a password when it can not be stored. It is generated up front so it
+        // is set together with creating the user
+        let printed_password = match &stored_password {
+            Some(_) => None,
+            None => match lldap_client.get_user(&username).await {
+                Ok(Some(_)) => None,
+                Ok(None) => Some(
+                    config
+                        .password
+                        .generator()
+                        .generate_one()
+                        .expect("Settings should be valid"),
+                ),
+                Err(err) => {
+                    warn!(username, "Failed to get user: {err}");
+                    failed += 1;
+                    continue;
+                }
+            },
+        };
+        let password = stored_password.as_deref().or(printed_password.as_deref());
+
+        match service_user
+            .apply_lldap(&lldap_client, config, granted.as_ref(), password)
+            .await
+        {
+            Ok(true) => {
+                info!(username, "Created user");
+                if let Some(password) = &printed_password {
+                    println!("{username}: {password}");
+                }
+            }
+            Ok(false) => debug!(username, "User is up to date"),
+            Err(err) => {
+                warn!(username, "Failed to apply service user: {err}");
+                failed += 1;
+            }
+        }
+    }
+
+    if failed > 0 {
+        anyhow::bail!("Failed to apply {failed} resource(s)");
+    }
+
+    info!(
+        groups = documents.groups.len(),
+        service_users = documents.service_users.len(),
+        "Synced resources"
+    );
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::resources::service_user::ServiceUserSpec;
+    use crate::testing::lldap::{ADMIN_PASSWORD, ADMIN_USERNAME, MockLldap};
+
+    #[test]
+    fn passwords_are_stored_and_reused() {
+        let dir = std::env::temp_dir().join(format!("lldap-standalone-{}", std::process::id()));
+        let credentials = CredentialsOutput::Directory(dir.clone());
+        let config = Config::default();
+
+        let mut service_user = ServiceUser::new(
+            "grafana",
+            ServiceUserSpec {
+                password_manager: false,
+                groups: Vec::new(),
+                secret_template: Default::default(),
+                attributes: Default::default(),
+                server_ref: None,
+                resync_interval_secs: None,
+            },
+        );
+        service_user.metadata.namespace = Some("monitoring".into());
+
+        let password = credentials
+            .password(&service_user, "grafana.monitoring", &config)
+            .unwrap()
+            .unwrap();
+
+        let secret_dir = dir.join("monitoring/grafana-lldap-credentials");
+        assert_eq!(
+            std::fs::read_to_string(secret_dir.join("username")).unwrap(),
+            "grafana.monitoring"
+        );
+
+        std::fs::write(secret_dir.join("password"), format!("{password}\n")).unwrap();
+        let reused = credentials
+            .password(&service_user, "grafana.monitoring", &config)
+            .unwrap();
+        assert_eq!(reused, Some(password));
+
+        assert_eq!(
+            CredentialsOutput::Stdout
+                .password(&service_user, "grafana.monitoring", &config)
+                .unwrap(),
+            None
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn password_set_when_creating_user() {
+        let lldap = MockLldap::start().await;
+        let mut config = Config::default();
+        config.lldap.url = Some(lldap.url().to_owned());
+        config.lldap.username = Some(ADMIN_USERNAME.into());
+        config.lldap.password = Some(ADMIN_PASSWORD.into());
+
+        let dir = std::env::temp_dir().join(format!("lldap-sync-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("app.yaml");
+        std::fs::write(
+            &path,
+            "apiVersion: lldap.huizinga.dev/v2\nkind: ServiceUser\nmetadata:\n  name: app\nspec: {}\n",
+        )
+        .unwrap();
+        let paths = [path];
+        let existing = lldap.state().users.len();
+
+        // Credentials that can not be stored fail the user without stopping the sync
+        let blocked = CredentialsOutput::Directory(paths[0].clone());
+        let err = sync(&config, &paths, "default", &blocked)
+            .await
+            .unwrap_err();
+        assert_eq!(err.to_string(), "Failed to apply 1 resource(s)");
+        assert_eq!(lldap.state().users.len(), existing);
+
+        sync(&config, &paths, "default", &CredentialsOutput::Stdout)
+            .await
+            .unwrap();
+        sync(&config, &paths, "default", &CredentialsOutput::Stdout)
+            .await
+            .unwrap();
+
+        let state = lldap.state();
+        assert_eq!(state.users.len(), existing + 1);
+        assert_eq!(state.password_changes.values().sum::<u32>(), 1);
+        drop(state);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
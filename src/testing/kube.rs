@@ -0,0 +1,444 @@
+use std::collections::BTreeMap;
+use std::convert::Infallible;
+use std::fmt;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use chrono::{SecondsFormat, Utc};
+use http::header::CONTENT_TYPE;
+use http::{Method, Request, Response, StatusCode};
+use json_patch::PatchOperation;
+use k8s_openapi::ByteString;
+use k8s_openapi::api::core::v1::Secret;
+use k8s_openapi::api::events::v1::Event;
+use kube::Api;
+use kube::api::PostParams;
+use kube::client::Body;
+use serde::de::DeserializeOwned;
+use serde_json::{Value, json};
+
+use crate::backend::DirectoryBackend;
+use crate::config::Config;
+use crate::context::Context;
+use crate::resources::LldapServer;
+use crate::testing::lldap::{ADMIN_PASSWORD, ADMIN_USERNAME, MockLldap};
+
+/// Objects stored by the path they are served at
+#[derive(Debug, Clone, Default)]
+pub struct State {
+    pub objects: BTreeMap<String, Value>,
+    /// Every event that was published, in order
+    pub events: Vec<Event>,
+    resource_version: u64,
+}
+
+fn status(code: StatusCode, reason: &str, message: String) -> (StatusCode, Value) {
+    let status = json!({
+        "kind": "Status",
+        "apiVersion": "v1",
+        "metadata": {},
+        "status": "Failure",
+        "message": message,
+        "reason": reason,
+        "code": code.as_u16(),
+    });
+
+    (code, status)
+}
+
+fn not_found(path: &str) -> (StatusCode, Value) {
+    status(
+        StatusCode::NOT_FOUND,
+        "NotFound",
+        format!("'{path}' not found"),
+    )
+}
+
+/// The api server only ever returns `data`, `stringData` is merged into it on write
+fn normalize_secret(object: Value) -> Value {
+    let Ok(mut secret) = serde_json::from_value::<Secret>(object.clone()) else {
+        return object;
+    };
+
+    if let Some(string_data) = secret.string_data.take() {
+        secret.data.get_or_insert_default().extend(
+            string_data
+                .into_iter()
+                .map(|(key, value)| (key, ByteString(value.into_bytes()))),
+        );
+    }
+
+    serde_json::to_value(secret).expect("Secret should serialize")
+}
+
+/// Test operations on fields that do not exist match `null` on a real api server, the finalizer
+/// helper relies on this
+fn drop_null_tests(object: &Value, operations: Vec<PatchOperation>) -> Vec<PatchOperation> {
+    operations
+        .into_iter()
+        .filter(|operation| match operation {
+            PatchOperation::Test(test) => {
+                !(test.value.is_null() && object.pointer(test.path.as_str()).is_none())
+            }
+            _ => true,
+        })
+        .collect()
+}
+
+/// `/api/v1/namespaces/default/secrets` is a collection, `/api/v1/namespaces/default` and
+/// `/api/v1/namespaces/default/secrets/name` are objects
+fn is_collection(path: &str) -> bool {
+    let segments: Vec<_> = path.trim_start_matches('/').split('/').collect();
+    let mut rest = match segments.first() {
+        Some(&"api") => segments.get(2..),
+        Some(&"apis") => segments.get(3..),
+        _ => None,
+    }
+    .unwrap_or_default();
+
+    if rest.len() >= 3 && rest[0] == "namespaces" {
+        rest = &rest[2..];
+    }
+
+    rest.len() == 1
+}
+
+impl State {
+    fn next_resource_version(&mut self) -> String {
+        self.resource_version += 1;
+        self.resource_version.to_string()
+    }
+
+    /// Objects stored directly below the collection path
+    fn list(&self, path: &str) -> Vec<Value> {
+        self.objects
+            .iter()
+            .filter(|(key, _)| {
+                key.rsplit_once('/')
+                    .is_some_and(|(parent, _)| parent == path)
+            })
+            .map(|(_, object)| object.clone())
+            .collect()
+    }
+
+    fn create(&mut self, path: &str, mut object: Value) -> (StatusCode, Value) {
+        let Some(name) = object["metadata"]["name"].as_str().map(ToOwned::to_owned) else {
+            return status(
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "Invalid",
+                "metadata.name is required".into(),
+            );
+        };
+
+        let key = format!("{path}/{name}");
+        if self.objects.contains_key(&key) {
+            return status(
+                StatusCode::CONFLICT,
+                "AlreadyExists",
+                format!("'{name}' already exists"),
+            );
+        }
+
+        let resource_version = self.next_resource_version();
+        let metadata = &mut object["metadata"];
+        if let Some((namespace, _)) = path
+            .split_once("/namespaces/")
+            .and_then(|(_, rest)| rest.split_once('/'))
+        {
+            metadata["namespace"] = namespace.into();
+        }
+        metadata["uid"] = format!("uid-{resource_version}").into();
+        metadata["resourceVersion"] = resource_version.into();
+        metadata["generation"] = 1.into();
+        metadata["creationTimestamp"] =
+            Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true).into();
+
+        let object = normalize_secret(object);
+        if path.starts_with("/apis/events.k8s.io/") {
+            self.events
+                .push(serde_json::from_value(object.clone()).expect("Event should be valid"));
+        }
+        self.objects.insert(key, object.clone());
+
+        (StatusCode::CREATED, object)
+    }
+
+    fn replace(&mut self, path: &str, mut object: Value) -> (StatusCode, Value) {
+        let Some(existing) = self.objects.get(path) else {
+            return not_found(path);
+        };
+
+        let current_version = &existing["metadata"]["resourceVersion"];
+        let version = &object["metadata"]["resourceVersion"];
+        if !version.is_null() && version != current_version {
+            return status(
+                StatusCode::CONFLICT,
+                "Conflict",
+                format!("'{path}' has been modified"),
+            );
+        }
+
+        for field in ["uid", "creationTimestamp", "namespace", "generation"] {
+            object["metadata"][field] = existing["metadata"][field].clone();
+        }
+
+        self.store(path, normalize_secret(object))
+    }
+
+    fn patch(&mut self, path: &str, content_type: &str, patch: Value) -> (StatusCode, Value) {
+        // Status is patched through the subresource, but stored in the object
+        let path = path.strip_suffix("/status").unwrap_or(path);
+        let Some(mut object) = self.objects.get(path).cloned() else {
+            return not_found(path);
+        };
+
+        match content_type {
+            "application/merge-patch+json" => json_patch::merge(&mut object, &patch),
+            "application/json-patch+json" => {
+                let operations = match serde_json::from_value(patch) {
+                    Ok(operations) => drop_null_tests(&object, operations),
+                    Err(err) => {
+                        return status(StatusCode::BAD_REQUEST, "BadRequest", err.to_string());
+                    }
+                };
+                if let Err(err) = json_patch::patch(&mut object, &operations) {
+                    return status(StatusCode::UNPROCESSABLE_ENTITY, "Invalid", err.to_string());
+                }
+            }
+            _ => {
+                return status(
+                    StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                    "UnsupportedMediaType",
+                    format!("Patch type '{content_type}' is not supported"),
+                );
+            }
+        }
+
+        self.store(path, object)
+    }
+
+    fn delete(&mut self, path: &str) -> (StatusCode, Value) {
+        let Some(object) = self.objects.get(path).cloned() else {
+            return not_found(path);
+        };
+
+        if !object["metadata"]["deletionTimestamp"].is_null() {
+            return (StatusCode::OK, object);
+        }
+
+        let mut object = object;
+        object["metadata"]["deletionTimestamp"] =
+            Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true).into();
+
+        self.store(path, object)
+    }
+
+    /// Store the object with a new resource version, objects that are deleted are removed as
+    /// soon as all finalizers are gone
+    fn store(&mut self, path: &str, mut object: Value) -> (StatusCode, Value) {
+        object["metadata"]["resourceVersion"] = self.next_resource_version().into();
+
+        let finalizers = object["metadata"]["finalizers"]
+            .as_array()
+            .map(Vec::len)
+            .unwrap_or_default();
+        if !object["metadata"]["deletionTimestamp"].is_null() && finalizers == 0 {
+            self.objects.remove(path);
+        } else {
+            self.objects.insert(path.to_owned(), object.clone());
+        }
+
+        (StatusCode::OK, object)
+    }
+
+    fn handle(
+        &mut self,
+        method: &Method,
+        path: &str,
+        content_type: &str,
+        body: &[u8],
+    ) -> (StatusCode, Value) {
+        let body = if body.is_empty() {
+            Value::Null
+        } else {
+            match serde_json::from_slice(body) {
+                Ok(body) => body,
+                Err(err) => return status(StatusCode::BAD_REQUEST, "BadRequest", err.to_string()),
+            }
+        };
+
+        // Status is read through the subresource, but stored in the object
+        let object_path = path.strip_suffix("/status").unwrap_or(path);
+        match *method {
+            Method::GET => match self.objects.get(object_path) {
+                Some(object) => (StatusCode::OK, object.clone()),
+                None if !is_collection(path) => not_found(path),
+                None => {
+                    let list = json!({
+                        "apiVersion": "v1",
+                        "kind": "List",
+                        "metadata": { "resourceVersion": self.resource_version.to_string() },
+                        "items": self.list(path),
+                    });
+
+                    (StatusCode::OK, list)
+                }
+            },
+            Method::POST => self.create(path, body),
+            Method::PUT => self.replace(path, body),
+            Method::PATCH => self.patch(path, content_type, body),
+            Method::DELETE => self.delete(path),
+            _ => status(
+                StatusCode::METHOD_NOT_ALLOWED,
+                "MethodNotAllowed",
+                format!("Method '{method}' is not supported"),
+            ),
+        }
+    }
+}
+
+/// In-memory Kubernetes api server, good enough for the requests the controller makes
+#[derive(Clone, Default)]
+pub struct FakeKube {
+    state: Arc<Mutex<State>>,
+}
+
+impl FakeKube {
+    pub fn client(&self) -> kube::Client {
+        let state = self.state.clone();
+        let service = tower::service_fn(move |request: Request<Body>| {
+            let state = state.clone();
+
+            async move {
+                let (parts, body) = request.into_parts();
+                let body = body.collect_bytes().await.unwrap_or_default();
+                let content_type = parts
+                    .headers
+                    .get(CONTENT_TYPE)
+                    .and_then(|value| value.to_str().ok())
+                    .unwrap_or_default();
+
+                let dry_run = parts
+                    .uri
+                    .query()
+                    .is_some_and(|query| query.split('&').any(|param| param == "dryRun=All"));
+
+                let mut state = state.lock().expect("Lock should not be poisoned");
+                let (status, body) = if dry_run {
+                    // Handled like any other request, but the result is thrown away
+                    state
+                        .clone()
+                        .handle(&parts.method, parts.uri.path(), content_type, &body)
+                } else {
+                    state.handle(&parts.method, parts.uri.path(), content_type, &body)
+                };
+                drop(state);
+
+                let response = Response::builder()
+                    .status(status)
+                    .header(CONTENT_TYPE, "application/json")
+                    .body(Body::from(
+                        serde_json::to_vec(&body).expect("Value should serialize"),
+                    ))
+                    .expect("Response should be valid");
+
+                Ok::<_, Infallible>(response)
+            }
+        });
+
+        kube::Client::new(service, "default")
+    }
+
+    pub fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().expect("Lock should not be poisoned")
+    }
+
+    /// Reason of every published event together with the object it is about
+    pub fn event_reasons(&self) -> Vec<(String, String)> {
+        self.state()
+            .events
+            .iter()
+            .map(|event| {
+                let regarding = event
+                    .regarding
+                    .as_ref()
+                    .and_then(|regarding| regarding.name.clone())
+                    .unwrap_or_default();
+
+                (event.reason.clone().unwrap_or_default(), regarding)
+            })
+            .collect()
+    }
+}
+
+/// A fake api server and a mock LLDAP, with a context connecting the controller to both
+pub struct TestEnv {
+    pub kube: FakeKube,
+    pub lldap: MockLldap,
+    pub ctx: Arc<Context>,
+}
+
+impl TestEnv {
+    pub async fn start(mut config: Config) -> Self {
+        let kube = FakeKube::default();
+        let lldap = MockLldap::start().await;
+
+        config.lldap.url = Some(lldap.url().to_owned());
+        config.lldap.username = Some(ADMIN_USERNAME.into());
+        config.lldap.password = Some(ADMIN_PASSWORD.into());
+        let ctx = Context::new(kube.client(), config).expect("Config should be valid");
+
+        Self {
+            kube,
+            lldap,
+            ctx: Arc::new(ctx),
+        }
+    }
+
+    /// Use the backend instead of the mock LLDAP
+    pub fn with_backend(self, backend: Arc<dyn DirectoryBackend>) -> Self {
+        Self {
+            ctx: Arc::new(Context::clone(&self.ctx).with_backend(backend)),
+            ..self
+        }
+    }
+
+    /// Create an LldapServer pointing at the mock LLDAP, together with its credentials secret
+    pub async fn create_server(&self, name: &str) -> Api<LldapServer> {
+        let secrets = Api::<Secret>::default_namespaced(self.ctx.client.clone());
+        let secret = serde_json::from_value(json!({
+            "metadata": { "name": name },
+            "stringData": { "username": ADMIN_USERNAME, "password": ADMIN_PASSWORD },
+        }))
+        .expect("Secret should be valid");
+        secrets
+            .create(&PostParams::default(), &secret)
+            .await
+            .expect("Secret should be created");
+
+        let servers = Api::<LldapServer>::all(self.ctx.client.clone());
+        let server = serde_json::from_value(json!({
+            "apiVersion": "lldap.huizinga.dev/v1",
+            "kind": "LldapServer",
+            "metadata": { "name": name },
+            "spec": {
+                "url": self.lldap.url(),
+                "credentials": { "name": name },
+            },
+        }))
+        .expect("LldapServer should be valid");
+        servers
+            .create(&PostParams::default(), &server)
+            .await
+            .expect("LldapServer should be created");
+
+        servers
+    }
+
+    /// Get the object like the controller would see it after a watch event
+    pub async fn latest<K>(&self, api: &Api<K>, name: &str) -> Arc<K>
+    where
+        K: Clone + DeserializeOwned + fmt::Debug,
+    {
+        Arc::new(api.get(name).await.expect("Object should exist"))
+    }
+}
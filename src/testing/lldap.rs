@@ -0,0 +1,440 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+use lldap_auth::login::{ClientSimpleLoginRequest, ServerLoginResponse};
+use lldap_auth::opaque;
+use lldap_auth::opaque::server::ServerSetup;
+use lldap_auth::registration::{
+    ClientRegistrationFinishRequest, ClientRegistrationStartRequest,
+    ServerRegistrationStartResponse,
+};
+use serde::Deserialize;
+use serde_json::{Value, json};
+use warp::Filter;
+use warp::http::StatusCode;
+use warp::reply::{self, Reply, Response};
+
+use crate::backend::{MemoryState, MemoryUser};
+use crate::lldap::{ConnectionSettings, LldapConfig};
+
+pub const ADMIN_USERNAME: &str = "admin";
+pub const ADMIN_PASSWORD: &str = "password";
+const API_VERSION: &str = "1.0";
+
+/// Users and groups in the mock, can be changed directly to set up a test
+#[derive(Debug, Default)]
+pub struct State {
+    /// The same directory the in-memory backend keeps, the mock serves it over GraphQL
+    pub directory: MemoryState,
+    /// Name of every GraphQL operation that was executed, in order
+    pub operations: Vec<String>,
+    /// Number of successful logins
+    pub logins: usize,
+    /// Tokens handed out by the logins, remove them to make LLDAP reject a logged in client
+    pub tokens: BTreeSet<String>,
+    /// Number of times a password was registered, by user
+    pub password_changes: BTreeMap<String, u32>,
+}
+
+impl Deref for State {
+    type Target = MemoryState;
+
+    fn deref(&self) -> &Self::Target {
+        &self.directory
+    }
+}
+
+impl DerefMut for State {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.directory
+    }
+}
+
+impl State {
+    fn group_json(&self, id: i32) -> Value {
+        json!({ "id": id, "displayName": self.groups[&id].name })
+    }
+
+    fn user_json(&self, username: &str) -> Value {
+        let user = &self.users[username];
+        let groups: Vec<_> = user.groups.iter().map(|id| self.group_json(*id)).collect();
+
+        json!({ "id": username, "groups": groups })
+    }
+
+    /// The errors of the directory in the words of LLDAP
+    fn check_user(&self, username: &str) -> Result<(), String> {
+        if !self.users.contains_key(username) {
+            return Err(format!("Entity not found: `No such user: '{username}'`"));
+        }
+
+        Ok(())
+    }
+
+    fn check_group(&self, id: i32) -> Result<(), String> {
+        if !self.groups.contains_key(&id) {
+            return Err(format!("Entity not found: `No such group: '{id}'`"));
+        }
+
+        Ok(())
+    }
+
+    /// Run one of the operations of the `queries` crate, they are recognized by name
+    fn execute(&mut self, operation: &str, variables: &Value) -> Result<Value, String> {
+        self.operations.push(operation.into());
+
+        let string = |name: &str| {
+            variables[name]
+                .as_str()
+                .map(ToOwned::to_owned)
+                .ok_or_else(|| format!("Missing variable '{name}'"))
+        };
+        let int = |name: &str| {
+            variables[name]
+                .as_i64()
+                .map(|value| value as i32)
+                .ok_or_else(|| format!("Missing variable '{name}'"))
+        };
+        let attributes = || {
+            serde_json::from_value::<Vec<AttributeInput>>(variables["attributes"].clone())
+                .map_err(|err| format!("Invalid attributes: {err}"))
+        };
+        let attributes_json = |attributes: &BTreeMap<String, Vec<String>>| {
+            attributes
+                .iter()
+                .map(|(name, value)| {
+                    json!({ "name": name, "value": value, "schema": { "isHardcoded": false } })
+                })
+                .collect::<Vec<_>>()
+        };
+        let ok = json!({ "ok": true });
+
+        match operation {
+            "GetApiVersion" => Ok(json!({ "apiVersion": API_VERSION })),
+            "GetUser" => {
+                let username = string("username")?;
+                if !self.users.contains_key(&username) {
+                    return Err(format!("Entity not found: `{username}`"));
+                }
+
+                Ok(json!({ "user": self.user_json(&username) }))
+            }
+            "CreateUser" => {
+                let username = string("username")?;
+                if self.users.contains_key(&username) {
+                    return Err(format!("Entity already exists: `{username}`"));
+                }
+                self.users.insert(username.clone(), MemoryUser::default());
+
+                Ok(json!({ "createUser": self.user_json(&username) }))
+            }
+            "DeleteUser" => {
+                let username = string("username")?;
+                self.check_user(&username)?;
+                self.users.remove(&username);
+
+                Ok(json!({ "deleteUser": ok }))
+            }
+            "GetGroups" => {
+                let groups: Vec<_> = self.groups.keys().map(|id| self.group_json(*id)).collect();
+
+                Ok(json!({ "groups": groups }))
+            }
+            "CreateGroup" => {
+                let name = string("name")?;
+                if self.group_id(&name).is_some() {
+                    return Err(format!("Entity already exists: `{name}`"));
+                }
+                let id = self.add_group(&name);
+
+                Ok(json!({ "createGroup": self.group_json(id) }))
+            }
+            "DeleteGroup" => {
+                let id = int("id")?;
+                self.check_group(id)?;
+                self.delete_group(id).expect("Group should exist");
+
+                Ok(json!({ "deleteGroup": ok }))
+            }
+            "AddUserToGroup" | "RemoveUserFromGroup" => {
+                let (username, id) = (string("username")?, int("group")?);
+                self.check_group(id)?;
+                self.check_user(&username)?;
+
+                if operation == "AddUserToGroup" {
+                    self.add_user_to_group(&username, id)
+                        .expect("User and group should exist");
+                    Ok(json!({ "addUserToGroup": ok }))
+                } else {
+                    self.remove_user_from_group(&username, id)
+                        .expect("User should exist");
+                    Ok(json!({ "removeUserFromGroup": ok }))
+                }
+            }
+            "ListUsers" => {
+                let users: Vec<_> = self
+                    .users
+                    .iter()
+                    .map(|(username, user)| {
+                        let mut json = self.user_json(username);
+                        json["attributes"] = attributes_json(&user.attributes).into();
+                        json
+                    })
+                    .collect();
+
+                Ok(json!({ "users": users }))
+            }
+            "ListGroups" => {
+                let groups: Vec<_> = self
+                    .groups
+                    .iter()
+                    .map(|(id, group)| {
+                        let mut json = self.group_json(*id);
+                        json["attributes"] = attributes_json(&group.attributes).into();
+                        json
+                    })
+                    .collect();
+
+                Ok(json!({ "groups": groups }))
+            }
+            "UpdateUserAttributes" => {
+                let (username, attributes) = (string("username")?, attributes()?);
+                self.check_user(&username)?;
+                let user = self.user_mut(&username).expect("User should exist");
+                for AttributeInput { name, value } in attributes {
+                    user.attributes.insert(name, value);
+                }
+
+                Ok(json!({ "updateUser": ok }))
+            }
+            "UpdateGroupAttributes" => {
+                let (id, attributes) = (int("id")?, attributes()?);
+                self.check_group(id)?;
+                let group = self.group_mut(id).expect("Group should exist");
+                for AttributeInput { name, value } in attributes {
+                    group.attributes.insert(name, value);
+                }
+
+                Ok(json!({ "updateGroup": ok }))
+            }
+            _ => Err(format!("Unknown operation '{operation}'")),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct AttributeInput {
+    name: String,
+    value: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct GraphQlRequest {
+    query: String,
+    #[serde(default)]
+    variables: Value,
+}
+
+impl GraphQlRequest {
+    /// `query GetUser($username: String!) {` has the operation name `GetUser`
+    fn operation(&self) -> &str {
+        self.query
+            .split_whitespace()
+            .nth(1)
+            .and_then(|name| name.split(['(', '{']).next())
+            .unwrap_or_default()
+    }
+}
+
+fn unauthorized() -> Response {
+    reply::with_status("Unauthorized", StatusCode::UNAUTHORIZED).into_response()
+}
+
+/// LLDAP running on a random local port, with an admin user and the builtin groups
+pub struct MockLldap {
+    url: String,
+    state: Arc<Mutex<State>>,
+}
+
+impl MockLldap {
+    pub async fn start() -> Self {
+        let mut state = State::default();
+        let admin_group = state.add_group("lldap_admin");
+        state.add_group("lldap_password_manager");
+        state.add_group("lldap_strict_readonly");
+        state.users.insert(
+            ADMIN_USERNAME.into(),
+            MemoryUser {
+                groups: BTreeSet::from([admin_group]),
+                ..Default::default()
+            },
+        );
+        let state = Arc::new(Mutex::new(state));
+
+        let with_state = {
+            let state = state.clone();
+            warp::any().map(move || state.clone())
+        };
+        let authorized = warp::header::optional::<String>("authorization")
+            .and(with_state.clone())
+            .map(|header: Option<String>, state: Arc<Mutex<State>>| {
+                let state = state.lock().expect("Lock should not be poisoned");
+                header
+                    .as_deref()
+                    .and_then(|header| header.strip_prefix("Bearer "))
+                    .is_some_and(|token| state.tokens.contains(token))
+            });
+
+        let login = warp::path!("auth" / "simple" / "login")
+            .and(warp::post())
+            .and(with_state.clone())
+            .and(warp::body::json())
+            .map(
+                |state: Arc<Mutex<State>>, request: ClientSimpleLoginRequest| {
+                    if request.username.as_str() != ADMIN_USERNAME
+                        || request.password != ADMIN_PASSWORD
+                    {
+                        return unauthorized();
+                    }
+                    let mut state = state.lock().expect("Lock should not be poisoned");
+                    state.logins += 1;
+                    let token = format!("mock-token-{}", state.logins);
+                    state.tokens.insert(token.clone());
+
+                    reply::json(&ServerLoginResponse {
+                        token,
+                        refresh_token: None,
+                    })
+                    .into_response()
+                },
+            );
+
+        let graphql = warp::path!("api" / "graphql")
+            .and(warp::post())
+            .and(authorized.clone())
+            .and(with_state.clone())
+            .and(warp::body::json())
+            .map(
+                |authorized: bool, state: Arc<Mutex<State>>, request: GraphQlRequest| {
+                    if !authorized {
+                        return unauthorized();
+                    }
+
+                    let mut state = state.lock().expect("Lock should not be poisoned");
+                    let body = match state.execute(request.operation(), &request.variables) {
+                        Ok(data) => json!({ "data": data }),
+                        Err(message) => json!({ "data": null, "errors": [{ "message": message }] }),
+                    };
+
+                    reply::json(&body).into_response()
+                },
+            );
+
+        let server_setup = Arc::new(ServerSetup::new(&mut rand::rngs::OsRng));
+        let register_start = warp::path!("auth" / "opaque" / "register" / "start")
+            .and(warp::post())
+            .and(authorized.clone())
+            .and(with_state.clone())
+            .and(warp::body::json())
+            .map(
+                move |authorized: bool,
+                      state: Arc<Mutex<State>>,
+                      request: ClientRegistrationStartRequest| {
+                    if !authorized {
+                        return unauthorized();
+                    }
+
+                    let username = request.username.as_str().to_owned();
+                    if !state
+                        .lock()
+                        .expect("Lock should not be poisoned")
+                        .users
+                        .contains_key(&username)
+                    {
+                        return reply::with_status("User not found", StatusCode::NOT_FOUND)
+                            .into_response();
+                    }
+
+                    let start = opaque::server::registration::start_registration(
+                        &server_setup,
+                        request.registration_start_request,
+                        &request.username,
+                    )
+                    .expect("Registration request should be valid");
+
+                    reply::json(&ServerRegistrationStartResponse {
+                        // Real LLDAP encrypts the state, the username is all the mock needs
+                        server_data: username,
+                        registration_response: start.message,
+                    })
+                    .into_response()
+                },
+            );
+
+        let register_finish = warp::path!("auth" / "opaque" / "register" / "finish")
+            .and(warp::post())
+            .and(authorized)
+            .and(with_state)
+            .and(warp::body::json())
+            .map(
+                |authorized: bool,
+                 state: Arc<Mutex<State>>,
+                 request: ClientRegistrationFinishRequest| {
+                    if !authorized {
+                        return unauthorized();
+                    }
+
+                    opaque::server::registration::get_password_file(request.registration_upload);
+                    let mut state = state.lock().expect("Lock should not be poisoned");
+                    if !state.users.contains_key(&request.server_data) {
+                        return reply::with_status("User not found", StatusCode::NOT_FOUND)
+                            .into_response();
+                    }
+                    *state
+                        .password_changes
+                        .entry(request.server_data)
+                        .or_default() += 1;
+
+                    reply::json(&json!({})).into_response()
+                },
+            );
+
+        let routes = login.or(graphql).or(register_start).or(register_finish);
+        let (address, server) = warp::serve(routes).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+
+        Self {
+            url: format!("http://{address}"),
+            state,
+        }
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    pub fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().expect("Lock should not be poisoned")
+    }
+
+    /// Settings connecting to the mock as the admin user
+    pub fn connection_settings(&self) -> ConnectionSettings<'static> {
+        ConnectionSettings {
+            url: self.url.clone(),
+            username: ADMIN_USERNAME.into(),
+            password: ADMIN_PASSWORD.into(),
+            ca_bundle: None,
+            identity: None,
+            insecure_skip_verify: false,
+            timeout: Duration::from_secs(5),
+            connect_timeout: None,
+            proxy: None,
+        }
+    }
+
+    pub fn config(&self) -> LldapConfig {
+        LldapConfig::from_connection(self.connection_settings()).expect("Settings should be valid")
+    }
+}
@@ -0,0 +1,7 @@
+pub mod kube;
+pub mod lldap;
+
+/// Self-signed certificate for `lldap.test`, usable as CA bundle and as client certificate
+pub const CERTIFICATE: &str = include_str!("cert.pem");
+/// Key of [`CERTIFICATE`]
+pub const PRIVATE_KEY: &str = include_str!("key.pem");
@@ -0,0 +1,178 @@
+use std::fmt;
+
+use crate::config::Config;
+
+/// Longest username we create in LLDAP
+pub const MAX_USERNAME_LENGTH: usize = 64;
+
+/// Everything that is wrong with a spec, reported all at once so it can be fixed in one go
+#[derive(Debug, Default)]
+pub struct Violations(Vec<(String, String)>);
+
+impl fmt::Display for Violations {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, (field, reason)) in self.0.iter().enumerate() {
+            if i > 0 {
+                f.write_str("; ")?;
+            }
+            write!(f, "{field}: {reason}")?;
+        }
+
+        Ok(())
+    }
+}
+
+impl std::error::Error for Violations {}
+
+impl Violations {
+    /// Record the problem with the field, if there is one
+    pub fn check(&mut self, field: &str, result: Result<(), String>) {
+        if let Err(reason) = result {
+            self.0.push((field.into(), reason));
+        }
+    }
+
+    /// Report the fields under different paths, for objects that were converted before they
+    /// were checked
+    pub fn rename_fields(self, rename: impl Fn(&str) -> String) -> Self {
+        Self(
+            self.0
+                .into_iter()
+                .map(|(field, reason)| (rename(&field), reason))
+                .collect(),
+        )
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn into_result(self) -> Result<(), Self> {
+        if self.is_empty() { Ok(()) } else { Err(self) }
+    }
+}
+
+pub fn username(username: &str) -> Result<(), String> {
+    if username.len() > MAX_USERNAME_LENGTH {
+        return Err(format!(
+            "username '{username}' is {} characters long, LLDAP allows at most {MAX_USERNAME_LENGTH}",
+            username.len()
+        ));
+    }
+
+    if let Some(c) = username
+        .chars()
+        .find(|c| !(c.is_ascii_lowercase() || c.is_ascii_digit() || matches!(c, '-' | '_' | '.')))
+    {
+        return Err(format!(
+            "username '{username}' contains '{c}', only lowercase letters, digits, '-', '_' and '.' are allowed"
+        ));
+    }
+
+    Ok(())
+}
+
+pub fn group_name(name: &str) -> Result<(), String> {
+    if name.trim().is_empty() {
+        return Err("group name can not be empty".into());
+    }
+
+    if name.trim() != name {
+        return Err(format!(
+            "group name '{name}' can not start or end with whitespace"
+        ));
+    }
+
+    if name.chars().any(char::is_control) {
+        return Err(format!(
+            "group name '{name}' can not contain control characters"
+        ));
+    }
+
+    Ok(())
+}
+
+/// Check that the group is not on the deny list of privileged groups and, when a namespace is
+/// given, that the namespace is allowed to request the group
+pub fn group_allowed(name: &str, namespace: Option<&str>, config: &Config) -> Result<(), String> {
+    let policy = &config.policy;
+
+    if policy
+        .denied_groups
+        .iter()
+        .any(|denied| denied.eq_ignore_ascii_case(name))
+    {
+        return Err(format!(
+            "group '{name}' is privileged and can not be managed by the controller"
+        ));
+    }
+
+    if let (Some(namespace), Some(allowed)) = (namespace, policy.allowed_namespaces.get(name)) {
+        if !allowed.iter().any(|allowed| allowed == namespace) {
+            return Err(format!(
+                "namespace '{namespace}' is not allowed to request group '{name}'"
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn usernames() {
+        assert!(username("app.default").is_ok());
+        assert!(username("my_app-2.team-a").is_ok());
+
+        let err = username(&format!("{}.default", "a".repeat(60))).unwrap_err();
+        assert!(err.contains("at most 64"), "{err}");
+
+        let err = username("App.default").unwrap_err();
+        assert!(err.contains("contains 'A'"), "{err}");
+    }
+
+    #[test]
+    fn group_names() {
+        assert!(group_name("media users").is_ok());
+        assert!(group_name("").is_err());
+        assert!(group_name(" media").is_err());
+        assert!(group_name("media\n").is_err());
+    }
+
+    #[test]
+    fn denied_groups() {
+        let config = Config::default();
+
+        assert!(group_allowed("media", Some("default"), &config).is_ok());
+
+        let err = group_allowed("LLDAP_admin", None, &config).unwrap_err();
+        assert!(err.contains("privileged"), "{err}");
+    }
+
+    #[test]
+    fn allowed_namespaces() {
+        let mut config = Config::default();
+        config
+            .policy
+            .allowed_namespaces
+            .insert("media".into(), vec!["jellyfin".into()]);
+
+        assert!(group_allowed("media", Some("jellyfin"), &config).is_ok());
+        assert!(group_allowed("media", Some("default"), &config).is_err());
+        assert!(group_allowed("other", Some("default"), &config).is_ok());
+    }
+
+    #[test]
+    fn violations_are_combined() {
+        let mut violations = Violations::default();
+        violations.check("spec.a", Ok(()));
+        violations.check("spec.b", Err("first".into()));
+        violations.check("spec.c", Err("second".into()));
+
+        let err = violations.into_result().unwrap_err();
+        assert_eq!(err.to_string(), "spec.b: first; spec.c: second");
+    }
+}
@@ -0,0 +1,340 @@
+use std::sync::Arc;
+
+use anyhow::Context;
+use kube::Resource;
+use kube::core::admission::{AdmissionRequest, AdmissionResponse, AdmissionReview, Operation};
+use kube::core::conversion::{ConversionRequest, ConversionResponse, ConversionReview};
+use kube::core::{DynamicObject, Status};
+use serde_json::Value;
+use tokio::signal::unix::SignalKind;
+use tracing::{debug, info, warn};
+use warp::Filter;
+
+use crate::config::Config;
+use crate::resources::{Group, ServiceUser, service_user};
+
+/// Check an object that is being created or updated, returns the reason it should be rejected
+fn validate(config: &Config, kind: &str, obj: DynamicObject) -> Result<(), String> {
+    match kind {
+        "ServiceUser" => {
+            // Validate every version as the latest version, so there is only one set of rules
+            let object = serde_json::to_value(obj)
+                .map_err(|err| format!("Failed to serialize ServiceUser: {err}"))?;
+            let is_v1 = object.get("apiVersion").and_then(Value::as_str)
+                == Some(&service_user::v1::ServiceUser::api_version(&()));
+            let object = service_user::convert(object, &ServiceUser::api_version(&()))?;
+            let service_user: ServiceUser = serde_json::from_value(object)
+                .map_err(|err| format!("Failed to parse ServiceUser: {err}"))?;
+
+            service_user
+                .validate(config)
+                .and_then(|()| service_user.check_policy(config, None))
+                .map_err(|violations| {
+                    // Point at the fields the way the client sent them
+                    if is_v1 {
+                        violations.rename_fields(service_user::v1::field_path)
+                    } else {
+                        violations
+                    }
+                    .to_string()
+                })
+        }
+        "Group" => obj
+            .try_parse::<Group>()
+            .map_err(|err| format!("Failed to parse Group: {err}"))?
+            .validate(config)
+            .map_err(|violations| violations.to_string()),
+        // Only reject what we know, the webhook configuration decides what is sent to us
+        _ => Ok(()),
+    }
+}
+
+fn review(
+    config: &Config,
+    review: AdmissionReview<DynamicObject>,
+) -> AdmissionReview<DynamicObject> {
+    let request: AdmissionRequest<DynamicObject> = match review.try_into() {
+        Ok(request) => request,
+        Err(err) => {
+            warn!("Invalid admission review: {err}");
+            return AdmissionResponse::invalid(err.to_string()).into_review();
+        }
+    };
+
+    let response = AdmissionResponse::from(&request);
+
+    // There is no object to validate when deleting
+    let Some(obj) = request.object else {
+        return response.into_review();
+    };
+
+    // Objects that are being deleted must be able to drop their finalizers, and an object that
+    // was admitted before must not be rejected for rules that changed since
+    if request.operation == Operation::Update {
+        let deleting = obj.metadata.deletion_timestamp.is_some();
+        let spec_unchanged = request
+            .old_object
+            .as_ref()
+            .is_some_and(|old| old.data.get("spec") == obj.data.get("spec"));
+        if deleting || spec_unchanged {
+            debug!(
+                kind = request.kind.kind,
+                name = request.name,
+                "Admitted without validation"
+            );
+            return response.into_review();
+        }
+    }
+
+    match validate(config, &request.kind.kind, obj) {
+        Ok(()) => {
+            debug!(kind = request.kind.kind, name = request.name, "Admitted");
+            response.into_review()
+        }
+        Err(reason) => {
+            info!(
+                kind = request.kind.kind,
+                name = request.name,
+                reason,
+                "Rejected"
+            );
+            response.deny(reason).into_review()
+        }
+    }
+}
+
+fn convert(review: ConversionReview) -> ConversionReview {
+    let mut request = match ConversionRequest::from_review(review) {
+        Ok(request) => request,
+        Err(err) => {
+            warn!("Invalid conversion review: {err}");
+            return ConversionResponse::invalid(Status::failure(
+                &err.to_string(),
+                "InvalidRequest",
+            ))
+            .into_review();
+        }
+    };
+
+    let desired_api_version = request.desired_api_version.clone();
+    let objects = std::mem::take(&mut request.objects);
+    let response = ConversionResponse::for_request(request);
+
+    let converted: Result<Vec<_>, _> = objects
+        .into_iter()
+        .map(|object| match object.get("kind").and_then(Value::as_str) {
+            Some("ServiceUser") => service_user::convert(object, &desired_api_version),
+            kind => Err(format!("Can not convert objects of kind {kind:?}")),
+        })
+        .collect();
+
+    match converted {
+        Ok(objects) => {
+            debug!(desired_api_version, count = objects.len(), "Converted");
+            response.success(objects)
+        }
+        Err(reason) => {
+            warn!(desired_api_version, reason, "Conversion failed");
+            response.failure(Status::failure(&reason, "ConversionFailed"))
+        }
+    }
+    .into_review()
+}
+
+/// Resolves on SIGINT or SIGTERM, the same signals the controllers stop on
+pub async fn shutdown_signal() {
+    let terminate = async {
+        match tokio::signal::unix::signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            }
+            Err(err) => {
+                warn!("Failed to listen for SIGTERM: {err}");
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        () = terminate => {}
+    }
+}
+
+/// Serve the admission and conversion webhooks until the process is asked to stop
+pub async fn serve(config: Arc<Config>) -> anyhow::Result<()> {
+    let webhook = &config.webhook;
+
+    let cert = std::fs::read(&webhook.cert_file).with_context(|| {
+        format!(
+            "Failed to read webhook certificate '{}'",
+            webhook.cert_file.display()
+        )
+    })?;
+    let key = std::fs::read(&webhook.key_file).with_context(|| {
+        format!(
+            "Failed to read webhook key '{}'",
+            webhook.key_file.display()
+        )
+    })?;
+
+    let validate = warp::path("validate")
+        .and(warp::post())
+        .and(warp::body::json())
+        .map({
+            let config = config.clone();
+            move |body| warp::reply::json(&review(&config, body))
+        });
+    let convert = warp::path("convert")
+        .and(warp::post())
+        .and(warp::body::json())
+        .map(|body| warp::reply::json(&convert(body)));
+    let routes = validate.or(convert).with(warp::trace::request());
+
+    let (addr, server) = warp::serve(routes)
+        .tls()
+        .cert(cert)
+        .key(key)
+        .try_bind_with_graceful_shutdown(webhook.listen, shutdown_signal())
+        .context("Failed to start webhook server")?;
+
+    info!(%addr, "Serving webhooks");
+    server.await;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn admission_review(kind: &str, object: serde_json::Value) -> AdmissionReview<DynamicObject> {
+        serde_json::from_value(json!({
+            "apiVersion": "admission.k8s.io/v1",
+            "kind": "AdmissionReview",
+            "request": {
+                "uid": "705ab4f5-6393-11e8-b7cc-42010a800002",
+                "kind": { "group": "lldap.huizinga.dev", "version": "v1", "kind": kind },
+                "resource": { "group": "lldap.huizinga.dev", "version": "v1", "resource": "serviceusers" },
+                "name": "app",
+                "namespace": "default",
+                "operation": "CREATE",
+                "userInfo": {},
+                "object": object,
+            }
+        }))
+        .unwrap()
+    }
+
+    fn update_review(
+        old_object: serde_json::Value,
+        object: serde_json::Value,
+    ) -> AdmissionReview<DynamicObject> {
+        serde_json::from_value(json!({
+            "apiVersion": "admission.k8s.io/v1",
+            "kind": "AdmissionReview",
+            "request": {
+                "uid": "705ab4f5-6393-11e8-b7cc-42010a800002",
+                "kind": { "group": "lldap.huizinga.dev", "version": "v1", "kind": "ServiceUser" },
+                "resource": { "group": "lldap.huizinga.dev", "version": "v1", "resource": "serviceusers" },
+                "name": "app",
+                "namespace": "default",
+                "operation": "UPDATE",
+                "userInfo": {},
+                "object": object,
+                "oldObject": old_object,
+            }
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn rejects_denied_groups() {
+        let config = Config::default();
+        let request = admission_review(
+            "ServiceUser",
+            json!({
+                "apiVersion": "lldap.huizinga.dev/v1",
+                "kind": "ServiceUser",
+                "metadata": { "name": "app", "namespace": "default" },
+                "spec": { "additionalGroups": ["media", "lldap_admin"] },
+            }),
+        );
+
+        let response = review(&config, request).response.unwrap();
+        assert!(!response.allowed);
+        assert_eq!(
+            response.result.message,
+            "spec.additionalGroups[1]: group 'lldap_admin' is privileged and can not be managed by the controller"
+        );
+
+        let request = admission_review(
+            "ServiceUser",
+            json!({
+                "apiVersion": "lldap.huizinga.dev/v2",
+                "kind": "ServiceUser",
+                "metadata": { "name": "app", "namespace": "default" },
+                "spec": { "groups": [{ "name": "lldap_admin" }] },
+            }),
+        );
+
+        let response = review(&config, request).response.unwrap();
+        assert_eq!(
+            response.result.message,
+            "spec.groups[0].name: group 'lldap_admin' is privileged and can not be managed by the controller"
+        );
+    }
+
+    #[test]
+    fn allows_valid_groups() {
+        let config = Config::default();
+        let request = admission_review(
+            "Group",
+            json!({
+                "apiVersion": "lldap.huizinga.dev/v1",
+                "kind": "Group",
+                "metadata": { "name": "media" },
+                "spec": {},
+            }),
+        );
+
+        let response = review(&config, request).response.unwrap();
+        assert!(response.allowed);
+    }
+
+    #[test]
+    fn update_only_validates_changed_spec() {
+        let config = Config::default();
+        let denied = json!({
+            "apiVersion": "lldap.huizinga.dev/v1",
+            "kind": "ServiceUser",
+            "metadata": { "name": "app", "namespace": "default" },
+            "spec": { "additionalGroups": ["lldap_admin"] },
+        });
+
+        // Only metadata changed, e.g. the controller removing its finalizer
+        let mut object = denied.clone();
+        object["metadata"]["finalizers"] = json!([]);
+        let response = review(&config, update_review(denied.clone(), object))
+            .response
+            .unwrap();
+        assert!(response.allowed);
+
+        // Objects that are being deleted are always admitted
+        let mut old_object = denied.clone();
+        old_object["spec"] = json!({});
+        let mut object = denied.clone();
+        object["metadata"]["deletionTimestamp"] = json!("2025-01-01T00:00:00Z");
+        let response = review(&config, update_review(old_object.clone(), object))
+            .response
+            .unwrap();
+        assert!(response.allowed);
+
+        let response = review(&config, update_review(old_object, denied))
+            .response
+            .unwrap();
+        assert!(!response.allowed);
+    }
+}